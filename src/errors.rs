@@ -14,6 +14,25 @@ pub enum ErrorKind {
     Custom { code: i32, msg: String },
 }
 
+/// 执行失败的SQL语句，作为ChimesError::sql的source
+#[derive(Debug)]
+pub struct SqlStatementError {
+    pub sql: String,
+    pub source: Box<dyn StdError>,
+}
+
+impl StdError for SqlStatementError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+impl fmt::Display for SqlStatementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, statement: {}", self.source, self.sql)
+    }
+}
+
 /// The WechatError type
 #[derive(Debug)]
 pub struct ChimesError {
@@ -83,6 +102,31 @@ impl ChimesError {
         }
     }
 
+    /// SQL执行错误，执行失败的语句记录在source中
+    pub fn sql(
+        code: i32,
+        msg: impl ToString,
+        sql: impl ToString,
+        source: impl Into<Box<dyn StdError>>,
+    ) -> Self {
+        Self::custom_err(
+            code,
+            msg,
+            SqlStatementError {
+                sql: sql.to_string(),
+                source: source.into(),
+            },
+        )
+    }
+
+    /// 执行失败的SQL语句
+    pub fn statement(&self) -> Option<&str> {
+        self.source
+            .as_ref()?
+            .downcast_ref::<SqlStatementError>()
+            .map(|f| f.sql.as_str())
+    }
+
     /// 写入文件到日志
     // #[allow(dead_code)]
     pub fn write_to_file(content: String) {
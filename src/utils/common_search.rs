@@ -1,6 +1,19 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use rbatis::core::convert::StmtConvert;
+use rbatis::core::db::DriverType;
+use rbatis::rbatis::Rbatis;
 use rbatis::wrapper::Wrapper;
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{
+    decode_query_component, is_valid_identifier, split_query_pairs, ChimesError, ChimesResult,
+    DynamicWrapper, ErrorKind,
+};
+
+/// 查询条件，children不为空时为一组条件，组内的条件按logic（and/or）连接
+/// for example:
+///     {"logic": "or", "children": [{"field": "status", "operator": "eq", "val": "1"}, ...]}
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct SearchField {
     pub field: Option<String>,
@@ -8,6 +21,14 @@ pub struct SearchField {
     pub render: Option<String>,
     pub field_type: Option<String>,
     pub val: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>, // in/range等列表的值，不为空时不再按逗号拆分val，值可以包含逗号
+    #[serde(default)]
+    pub timezone: Option<String>, // between_dates的时区，如 +08:00，默认为服务器的时区
+    #[serde(default)]
+    pub logic: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SearchField>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -16,149 +37,943 @@ pub struct CommonSearch {
     pub page: Option<u64>,
     pub order: Option<String>,
     pub search: Vec<SearchField>,
+    #[serde(default)]
+    pub logic: Option<String>, // search中的条件的连接方式，默认为and
+    #[serde(default)]
+    pub cursor: Option<String>, // 键集分页的游标，为上一页返回的next_cursor
+}
+
+/// 分页查询每页的最大数量，limit超过时使用这个值
+pub const MAX_PAGE_SIZE: u64 = 1000;
+
+/// 分页查询的结果，page从1开始
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PageResult<T> {
+    pub total: u64,
+    pub page: u64,
+    pub size: u64,
+    pub records: Vec<T>,
+}
+
+/// CommonSearch生成条件使用的Wrapper，rbatis的Wrapper和DynamicWrapper都实现了
+#[allow(clippy::wrong_self_convention)]
+pub trait SearchWrapper: Sized {
+    fn sql_len(&self) -> usize;
+    fn truncate_sql(self, len: usize) -> Self;
+    fn push_sql(self, sql: &str) -> Self;
+    fn and(self) -> Self;
+    fn or(self) -> Self;
+    fn eq<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn ne<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn gt<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn ge<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn lt<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn le<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    /// 包含匹配 %v%
+    fn contains<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    /// 前缀匹配 v%
+    fn starts_with<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    /// 后缀匹配 %v
+    fn ends_with<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn not_like<T: serde::Serialize>(self, column: &str, obj: T) -> Self;
+    fn between<T: serde::Serialize>(self, column: &str, min: T, max: T) -> Self;
+    fn not_between<T: serde::Serialize>(self, column: &str, min: T, max: T) -> Self;
+    fn in_array<T: serde::Serialize>(self, column: &str, obj: &[T]) -> Self;
+    fn not_in<T: serde::Serialize>(self, column: &str, obj: &[T]) -> Self;
+    fn is_null(self, column: &str) -> Self;
+    fn is_not_null(self, column: &str) -> Self;
+    fn order_bys(self, column_asc: &[(&str, bool)]) -> Self;
+    fn driver_type(&self) -> DriverType;
+    /// 检查列名，不能使用时返回None
+    fn column(&mut self, column: &str) -> Option<String>;
+    /// 添加 before ? after，?为obj的参数
+    fn push_arg(self, before: &str, obj: rbatis::Value, after: &str) -> Self;
+
+    /// JSON列包含json中的值，json为数组时需要包含所有的元素
+    /// for example:
+    ///     json_contains("tags", "[\"a\"]") MySQL " json_contains(tags, ?) " / PostgreSQL " tags::jsonb @> ?::jsonb "
+    fn json_contains(mut self, column: &str, json: &str) -> Self {
+        let column = match self.column(column) {
+            Some(name) => name,
+            None => return self,
+        };
+        // SQLite和MSSQL使用集合的差来判断，需要把单个值转换为数组
+        let array = match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(serde_json::Value::Array(_)) | Err(_) => json.to_string(),
+            Ok(value) => format!("[{}]", value),
+        };
+        match self.driver_type() {
+            DriverType::Postgres => self.push_arg(
+                &format!("{}::jsonb @> ", column),
+                rbatis::Value::String(json.to_string()),
+                "::jsonb",
+            ),
+            DriverType::Sqlite => self.push_arg(
+                "not exists (select value from json_each(",
+                rbatis::Value::String(array),
+                &format!(") except select value from json_each({}))", column),
+            ),
+            DriverType::Mssql => self.push_arg(
+                "not exists (select [value] from openjson(",
+                rbatis::Value::String(array),
+                &format!(") except select [value] from openjson({}))", column),
+            ),
+            _ => self.push_arg(
+                &format!("json_contains({}, ", column),
+                rbatis::Value::String(json.to_string()),
+                ")",
+            ),
+        }
+    }
+}
+
+/// $contains/$starts_with/$ends_with为类型中对应的like方法
+macro_rules! impl_search_wrapper {
+    ($t:ty, $column:expr, $contains:ident, $starts_with:ident, $ends_with:ident) => {
+        impl SearchWrapper for $t {
+            fn sql_len(&self) -> usize {
+                self.sql.len()
+            }
+            fn truncate_sql(mut self, len: usize) -> Self {
+                self.sql.truncate(len);
+                self
+            }
+            fn push_sql(self, sql: &str) -> Self {
+                <$t>::push_sql(self, sql)
+            }
+            fn and(self) -> Self {
+                <$t>::and(self)
+            }
+            fn or(self) -> Self {
+                <$t>::or(self)
+            }
+            fn eq<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::eq(self, column, obj)
+            }
+            fn ne<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::ne(self, column, obj)
+            }
+            fn gt<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::gt(self, column, obj)
+            }
+            fn ge<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::ge(self, column, obj)
+            }
+            fn lt<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::lt(self, column, obj)
+            }
+            fn le<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::le(self, column, obj)
+            }
+            fn contains<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::$contains(self, column, obj)
+            }
+            fn starts_with<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::$starts_with(self, column, obj)
+            }
+            fn ends_with<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::$ends_with(self, column, obj)
+            }
+            fn not_like<T: serde::Serialize>(self, column: &str, obj: T) -> Self {
+                <$t>::not_like(self, column, obj)
+            }
+            fn between<T: serde::Serialize>(self, column: &str, min: T, max: T) -> Self {
+                <$t>::between(self, column, min, max)
+            }
+            fn not_between<T: serde::Serialize>(self, column: &str, min: T, max: T) -> Self {
+                <$t>::not_between(self, column, min, max)
+            }
+            fn in_array<T: serde::Serialize>(self, column: &str, obj: &[T]) -> Self {
+                <$t>::in_array(self, column, obj)
+            }
+            fn not_in<T: serde::Serialize>(self, column: &str, obj: &[T]) -> Self {
+                <$t>::not_in(self, column, obj)
+            }
+            fn is_null(self, column: &str) -> Self {
+                <$t>::is_null(self, column)
+            }
+            fn is_not_null(self, column: &str) -> Self {
+                <$t>::is_not_null(self, column)
+            }
+            fn order_bys(self, column_asc: &[(&str, bool)]) -> Self {
+                <$t>::order_bys(self, column_asc)
+            }
+            fn driver_type(&self) -> DriverType {
+                self.driver_type.clone()
+            }
+            fn column(&mut self, column: &str) -> Option<String> {
+                $column(self, column)
+            }
+            fn push_arg(self, before: &str, obj: rbatis::Value, after: &str) -> Self {
+                let mut wp = <$t>::and(self);
+                let mut placeholder = String::new();
+                wp.driver_type.stmt_convert(wp.args.len(), &mut placeholder);
+                wp.sql.push_str(before);
+                wp.sql.push_str(&placeholder);
+                wp.sql.push_str(after);
+                wp.args.push(obj);
+                wp
+            }
+        }
+    };
+}
+
+fn wrapper_column(_: &mut Wrapper, column: &str) -> Option<String> {
+    Some(column.to_string())
+}
+
+// rbatis的Wrapper的like为%v%，like_left为%v，like_right为v%
+impl_search_wrapper!(Wrapper, wrapper_column, like, like_right, like_left);
+impl_search_wrapper!(
+    DynamicWrapper,
+    DynamicWrapper::column_name,
+    like_contains,
+    like_right,
+    like_ends_with
+);
+
+/// 校验后的查询条件
+enum SearchCondition {
+    Compare(&'static str, rbatis::Value),
+    List(bool, Vec<rbatis::Value>),
+    Range(bool, rbatis::Value, rbatis::Value),
+    Null(bool),
+    Dates(String, String, bool), // 开始、结束，结束是否包含
+    JsonContains(String),
+}
+
+fn apply_condition<W: SearchWrapper>(wp: W, column: &str, condition: SearchCondition) -> W {
+    match condition {
+        SearchCondition::Compare(op, value) => match op {
+            "eq" => wp.eq(column, value),
+            "ne" => wp.ne(column, value),
+            "gt" => wp.gt(column, value),
+            "ge" => wp.ge(column, value),
+            "lt" => wp.lt(column, value),
+            "le" => wp.le(column, value),
+            "contains" => wp.contains(column, value),
+            "not_like" => wp.not_like(column, value),
+            "ends_with" => wp.ends_with(column, value),
+            _ => wp.starts_with(column, value),
+        },
+        SearchCondition::List(false, list) => wp.in_array(column, &list),
+        SearchCondition::List(true, list) => wp.not_in(column, &list),
+        SearchCondition::Range(false, min, max) => wp.between(column, min, max),
+        SearchCondition::Range(true, min, max) => wp.not_between(column, min, max),
+        SearchCondition::Null(false) => wp.is_null(column),
+        SearchCondition::Null(true) => wp.is_not_null(column),
+        SearchCondition::Dates(start, end, inclusive) => {
+            let wp = wp.push_sql("(").ge(column, start);
+            let wp = if inclusive {
+                wp.le(column, end)
+            } else {
+                wp.lt(column, end)
+            };
+            wp.push_sql(")")
+        }
+        SearchCondition::JsonContains(json) => wp.json_contains(column, &json),
+    }
+}
+
+/// 解析时区，如 Z、UTC、+08:00、+0800、-05
+fn parse_timezone(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("z") || text.eq_ignore_ascii_case("utc") {
+        return FixedOffset::east_opt(0);
+    }
+    let sign = match text.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits = text[1..].replace(':', "");
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = if digits.len() > 2 {
+        digits.split_at(digits.len() - 2)
+    } else {
+        (digits.as_str(), "0")
+    };
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// 转换为服务器本地时间的字符串，tz为None时按服务器的时区
+fn to_local_datetime(naive: NaiveDateTime, tz: Option<FixedOffset>) -> Option<String> {
+    let local = match tz {
+        Some(offset) => offset
+            .from_local_datetime(&naive)
+            .single()?
+            .with_timezone(&Local),
+        None => Local.from_local_datetime(&naive).earliest()?,
+    };
+    Some(local.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// 解析between_dates的一个值，返回本地时间和是否包含该时间
+/// 结束值为日期时使用下一天的开始，并且不包含
+fn parse_date_bound(text: &str, tz: Option<FixedOffset>, is_end: bool) -> Option<(String, bool)> {
+    let text = text.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(text) {
+        let local = dt.with_timezone(&Local);
+        return Some((local.format("%Y-%m-%d %H:%M:%S").to_string(), true));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(text, format) {
+            return Some((to_local_datetime(naive, tz)?, true));
+        }
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    if is_end {
+        let next = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+        Some((to_local_datetime(next, tz)?, false))
+    } else {
+        Some((to_local_datetime(date.and_hms_opt(0, 0, 0)?, tz)?, true))
+    }
+}
+
+fn is_or(logic: &Option<String>) -> bool {
+    logic
+        .as_deref()
+        .map(|f| f.trim().eq_ignore_ascii_case("or"))
+        .unwrap_or(false)
+}
+
+fn connect<W: SearchWrapper>(wp: W, or: bool) -> W {
+    if or {
+        wp.or()
+    } else {
+        wp.and()
+    }
+}
+
+/// 解析 filter[name][like] 这样的键，返回filter和括号中的部分
+fn split_bracket_key(key: &str) -> Option<(&str, Vec<&str>)> {
+    let (prefix, mut rest) = key.split_once('[')?;
+    let mut parts = vec![];
+    loop {
+        let (part, tail) = rest.split_once(']')?;
+        parts.push(part);
+        if tail.is_empty() {
+            return Some((prefix, parts));
+        }
+        rest = tail.strip_prefix('[')?;
+    }
+}
+
+/// 查询字符串中的操作符，not_like等同于not like
+fn query_operator(operator: &str) -> String {
+    let operator = operator.trim().to_lowercase();
+    match operator.strip_prefix("not_") {
+        Some(t) => format!("not {}", t),
+        None => operator,
+    }
+}
+
+fn query_number(key: &str, value: &str) -> ChimesResult<u64> {
+    value.trim().parse::<u64>().map_err(|_| {
+        ChimesError::custom(
+            10120,
+            format!(
+                "The query parameter {} is not a valid number: {}",
+                key, value
+            ),
+        )
+    })
 }
 
 impl CommonSearch {
-    fn to_value(&self, field: &SearchField) -> Option<rbatis::Value> {
-        let field_value = field.val.clone().unwrap_or_default();
-        self.to_value_inner(field, field_value)
-    }
-
-    fn to_value_inner(&self, field: &SearchField, field_value: String) -> Option<rbatis::Value> {
-        let value = match field.field_type.clone() {
-            Some(t) => {
-                match t.to_lowercase().as_str() {
-                    "integer" => match field_value.parse::<i64>() {
-                        Ok(v) => Some(rbatis::Value::Int64(v)),
-                        Err(_) => None,
-                    },
-                    "float" => match field_value.parse::<f64>() {
-                        Ok(v) => Some(rbatis::Value::Double(v)),
-                        Err(_) => None,
-                    },
-                    "decimal" => Some(rbatis::Value::String(field_value)),
-                    "boolean" => Some(rbatis::Value::from(
-                        field_value.to_lowercase().as_str() == "true",
-                    )),
-                    "datetime" => match rbatis::DateTimeNative::from_str(&field_value) {
-                        Ok(_) => Some(rbatis::Value::from(&field_value)),
-                        Err(_) => None,
-                    },
-                    "date" => match rbatis::DateNative::from_str(&field_value) {
-                        Ok(_) => Some(rbatis::Value::from(&field_value)),
-                        Err(_) => None,
-                    },
-                    _ => Some(rbatis::Value::String(field_value)), // default to string
+    /// 从查询字符串中解析，字段的值按字符串处理，可以配合SearchSchema指定类型
+    /// filter[name]=abc 为eq，in/not in/range/not range的重复键会合并为一个条件
+    /// in/not in/range/not range的值以逗号分隔，先拆分再解码，值中的逗号编码为%2C
+    /// for example:
+    ///     ?filter[name][like]=abc&filter[age][range]=1,9&sort=-id,name&page=2&limit=20
+    pub fn from_query(query_string: &str) -> ChimesResult<CommonSearch> {
+        let mut search = CommonSearch::default();
+        let mut orders = vec![];
+        let mut timezone = None;
+        for (raw_key, raw_value) in split_query_pairs(query_string) {
+            let key = decode_query_component(raw_key);
+            let value = decode_query_component(raw_value);
+            match key.as_str() {
+                "page" => search.page = Some(query_number(&key, &value)?),
+                "limit" | "size" => search.limit = Some(query_number(&key, &value)?),
+                "cursor" => search.cursor = Some(value),
+                "logic" => search.logic = Some(value),
+                "timezone" | "tz" => timezone = Some(value),
+                "sort" | "order" => {
+                    for item in value.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+                        match item.strip_prefix('-') {
+                            Some(t) => orders.push(format!("{} desc", t.trim())),
+                            None => orders.push(item.trim_start_matches('+').to_string()),
+                        }
+                    }
                 }
+                _ => {
+                    let (field, operator) = match split_bracket_key(&key) {
+                        Some(("filter", parts)) if parts.len() == 1 => {
+                            (parts[0].to_string(), "eq".to_string())
+                        }
+                        Some(("filter", parts)) if parts.len() == 2 => {
+                            (parts[0].to_string(), query_operator(parts[1]))
+                        }
+                        Some(("filter", _)) => {
+                            return Err(ChimesError::custom(
+                                10120,
+                                format!("The query parameter {} is invalid", key),
+                            ))
+                        }
+                        _ => continue, // 其它的参数
+                    };
+                    let is_list =
+                        matches!(operator.as_str(), "in" | "not in" | "range" | "not range");
+                    let merged = search.search.iter_mut().find(|f| {
+                        is_list
+                            && f.field.as_deref() == Some(field.as_str())
+                            && f.operator.as_deref() == Some(operator.as_str())
+                    });
+                    let values = if is_list {
+                        raw_value
+                            .split(',')
+                            .map(decode_query_component)
+                            .filter(|f| !f.trim().is_empty())
+                            .collect::<Vec<String>>()
+                    } else {
+                        vec![]
+                    };
+                    match merged {
+                        Some(f) => {
+                            f.values.extend(values);
+                            f.val = Some(f.values.join(","));
+                        }
+                        None => search.search.push(SearchField {
+                            field: Some(field),
+                            operator: Some(operator),
+                            val: Some(if is_list { values.join(",") } else { value }),
+                            values,
+                            ..Default::default()
+                        }),
+                    }
+                }
+            }
+        }
+        if let Some(tz) = timezone {
+            for f in search.search.iter_mut() {
+                f.timezone = Some(tz.clone());
             }
-            None => Some(rbatis::Value::String(field_value)),
+        }
+        if !orders.is_empty() {
+            search.order = Some(orders.join(","));
+        }
+        Ok(search)
+    }
+
+    fn to_value(&self, field: &SearchField, field_value: &str) -> ChimesResult<rbatis::Value> {
+        let field_type = field.field_type.clone().unwrap_or_default().to_lowercase();
+        let value = match field_type.as_str() {
+            "integer" => field_value
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .map(rbatis::Value::Int64),
+            "float" => field_value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(rbatis::Value::Double),
+            "decimal" => field_value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(|_| rbatis::Value::String(field_value.trim().to_string())),
+            "boolean" => match field_value.trim().to_lowercase().as_str() {
+                "true" | "1" => Some(rbatis::Value::from(true)),
+                "false" | "0" => Some(rbatis::Value::from(false)),
+                _ => None,
+            },
+            "datetime" => rbatis::DateTimeNative::from_str(field_value)
+                .ok()
+                .map(|_| rbatis::Value::from(field_value)),
+            "date" => rbatis::DateNative::from_str(field_value)
+                .ok()
+                .map(|_| rbatis::Value::from(field_value)),
+            _ => Some(rbatis::Value::String(field_value.to_string())), // default to string
         };
-        value
+        value.ok_or_else(|| {
+            ChimesError::custom(
+                10118,
+                format!(
+                    "The value {} of field {} is not a valid {}",
+                    field_value,
+                    field.field.clone().unwrap_or_default(),
+                    field_type
+                ),
+            )
+        })
     }
 
-    fn split_value(&self, field: &SearchField) -> Vec<Option<rbatis::Value>> {
+    /// 列表的值，values为空时按逗号拆分val
+    fn split_value(&self, field: &SearchField) -> ChimesResult<Vec<rbatis::Value>> {
+        if !field.values.is_empty() {
+            return field
+                .values
+                .iter()
+                .map(|f| self.to_value(field, f.trim()))
+                .collect();
+        }
         let field_value = field.val.clone().unwrap_or_default();
-        let list: Vec<Option<rbatis::Value>> = field_value
+        field_value
             .split(',')
-            .map(|f| self.to_value_inner(field, f.to_string()))
-            .collect();
-        list
-    }
-
-    pub fn into_wrapper(&self, mut wp: Wrapper) -> Wrapper {
-        for f in self.search.clone() {
-            if let Some(t) = f.operator.clone() {
-                match t.to_lowercase().as_str() {
-                    "eq" => {
-                        wp = wp.and().eq(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "ne" => {
-                        wp = wp.and().ne(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "gt" => {
-                        wp = wp.and().gt(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "egt" => {
-                        wp = wp.and().gt(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "lt" => {
-                        wp = wp.and().lt(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "elt" => {
-                        wp = wp.and().le(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "like" => {
-                        wp = wp.and().like(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "not like" => {
-                        wp = wp.and().not_like(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            self.to_value(&f),
-                        );
-                    }
-                    "in" => {
-                        let list = self.split_value(&f);
-                        wp = wp
-                            .and()
-                            .r#in(f.field.clone().unwrap_or_default().as_str(), &list);
-                    }
-                    "not in" => {
-                        let list = self.split_value(&f);
-                        wp = wp
-                            .and()
-                            .r#not_in(f.field.clone().unwrap_or_default().as_str(), &list);
-                    }
-                    "range" => {
-                        let list = self.split_value(&f);
-                        wp = wp.and().between(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            list[0].clone(),
-                            list[1].clone(),
-                        );
-                    }
-                    "not range" => {
-                        let list = self.split_value(&f);
-                        wp = wp.and().not_between(
-                            f.field.clone().unwrap_or_default().as_str(),
-                            list[0].clone(),
-                            list[1].clone(),
-                        );
-                    }
-                    "null" => {
-                        wp = wp
-                            .and()
-                            .is_null(f.field.clone().unwrap_or_default().as_str());
-                    }
-                    "not null" => {
-                        wp = wp
-                            .and()
-                            .is_not_null(f.field.clone().unwrap_or_default().as_str());
-                    }
-                    _ => {}
+            .filter(|f| !f.trim().is_empty())
+            .map(|f| self.to_value(field, f.trim()))
+            .collect()
+    }
+
+    /// 校验查询条件，错误信息中使用f中的字段名
+    pub(crate) fn check_field(&self, f: &SearchField) -> ChimesResult<()> {
+        self.parse_condition(f).map(|_| ())
+    }
+
+    /// 校验查询条件，返回列名和条件
+    fn parse_condition(&self, f: &SearchField) -> ChimesResult<(String, SearchCondition)> {
+        let column = f.field.clone().unwrap_or_default();
+        if column.trim().is_empty() {
+            return Err(ChimesError::custom(10118, "The field of search is missing"));
+        }
+        if !is_valid_identifier(&column) {
+            return Err(ChimesError::custom(
+                10118,
+                format!("The field {} is not a valid identifier", column),
+            ));
+        }
+        let operator = match f.operator.clone() {
+            Some(t) if !t.trim().is_empty() => t.trim().to_lowercase(),
+            _ => {
+                return Err(ChimesError::custom(
+                    10118,
+                    format!("The operator of field {} is missing", column),
+                ))
+            }
+        };
+        let val = f.val.clone().unwrap_or_default();
+        let text = rbatis::Value::String(val.clone());
+        let condition = match operator.as_str() {
+            "eq" => SearchCondition::Compare("eq", self.to_value(f, &val)?),
+            "ne" => SearchCondition::Compare("ne", self.to_value(f, &val)?),
+            "gt" => SearchCondition::Compare("gt", self.to_value(f, &val)?),
+            "egt" | "ge" => SearchCondition::Compare("ge", self.to_value(f, &val)?),
+            "lt" => SearchCondition::Compare("lt", self.to_value(f, &val)?),
+            "elt" | "le" => SearchCondition::Compare("le", self.to_value(f, &val)?),
+            "like" => SearchCondition::Compare("contains", text),
+            "not like" => SearchCondition::Compare("not_like", text),
+            "starts_with" => SearchCondition::Compare("starts_with", text),
+            "ends_with" => SearchCondition::Compare("ends_with", text),
+            "in" | "not in" => {
+                let list = self.split_value(f)?;
+                if list.is_empty() {
+                    return Err(ChimesError::custom(
+                        10118,
+                        format!(
+                            "The field {} requires at least one value for {}",
+                            column, operator
+                        ),
+                    ));
+                }
+                SearchCondition::List(operator == "not in", list)
+            }
+            "range" | "not range" => {
+                let mut list = self.split_value(f)?;
+                if list.len() != 2 {
+                    return Err(ChimesError::custom(
+                        10118,
+                        format!(
+                            "The field {} requires two values for {}, but got {}",
+                            column,
+                            operator,
+                            list.len()
+                        ),
+                    ));
                 }
+                let max = list.remove(1);
+                SearchCondition::Range(operator == "not range", list.remove(0), max)
+            }
+            "null" => SearchCondition::Null(false),
+            "not null" => SearchCondition::Null(true),
+            "between_dates" => {
+                let tz = match f.timezone.as_deref().filter(|t| !t.trim().is_empty()) {
+                    Some(t) => Some(parse_timezone(t).ok_or_else(|| {
+                        ChimesError::custom(
+                            10118,
+                            format!("The timezone {} of field {} is invalid", t, column),
+                        )
+                    })?),
+                    None => None,
+                };
+                let list = val.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+                if list.len() != 2 {
+                    return Err(ChimesError::custom(
+                        10118,
+                        format!(
+                            "The field {} requires two values for {}, but got {}",
+                            column,
+                            operator,
+                            list.len()
+                        ),
+                    ));
+                }
+                let invalid = |text: &str| {
+                    ChimesError::custom(
+                        10118,
+                        format!(
+                            "The value {} of field {} is not a valid date or datetime",
+                            text, column
+                        ),
+                    )
+                };
+                let (start, _) =
+                    parse_date_bound(list[0], tz, false).ok_or_else(|| invalid(list[0]))?;
+                let (end, inclusive) =
+                    parse_date_bound(list[1], tz, true).ok_or_else(|| invalid(list[1]))?;
+                SearchCondition::Dates(start, end, inclusive)
+            }
+            "json_contains" => {
+                if serde_json::from_str::<serde_json::Value>(&val).is_err() {
+                    return Err(ChimesError::custom(
+                        10118,
+                        format!("The value {} of field {} is not a valid JSON", val, column),
+                    ));
+                }
+                SearchCondition::JsonContains(val)
+            }
+            _ => {
+                return Err(ChimesError::custom(
+                    10118,
+                    format!(
+                        "The operator {} of field {} is not supported",
+                        operator, column
+                    ),
+                ))
+            }
+        };
+        Ok((column, condition))
+    }
+
+    /// 页码，从1开始
+    fn page_no(&self) -> u64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    /// 每页的数量，为空时10条，最多MAX_PAGE_SIZE条
+    fn page_size(&self) -> u64 {
+        self.limit.unwrap_or(10).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// 使用cursor和limit进行键集分页，limit为空时每页10条，最多MAX_PAGE_SIZE条
+    /// for example:
+    ///     let page = search.keyset_wrapper(wp, &[("id", false)]).fetch_keyset::<T>(rb).await?;
+    pub fn keyset_wrapper(&self, wp: DynamicWrapper, keys: &[(&str, bool)]) -> DynamicWrapper {
+        wp.keyset(keys, self.cursor.as_deref(), self.page_size())
+    }
+
+    /// 解析order，只允许allowed中的列，方向为asc或desc，默认为asc
+    /// for example:
+    ///     order: "create_time desc,id asc" [("create_time", false), ("id", true)]
+    pub fn order_list(&self, allowed: &[&str]) -> ChimesResult<Vec<(String, bool)>> {
+        let mut list = vec![];
+        let order = self.order.clone().unwrap_or_default();
+        for item in order.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            let parts = item.split_whitespace().collect::<Vec<&str>>();
+            let is_asc = match parts.get(1).map(|f| f.to_lowercase()) {
+                None => true,
+                Some(t) if parts.len() == 2 && t == "asc" => true,
+                Some(t) if parts.len() == 2 && t == "desc" => false,
+                _ => {
+                    return Err(ChimesError::custom(
+                        10117,
+                        format!("The order {} is invalid", item),
+                    ))
+                }
+            };
+            match allowed.iter().find(|f| f.eq_ignore_ascii_case(parts[0])) {
+                Some(column) => list.push((column.to_string(), is_asc)),
+                None => {
+                    return Err(ChimesError::custom(
+                        10112,
+                        format!("The column {} is not allowed to order", parts[0]),
+                    ))
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    /// 生成分页查询，包括条件、排序和分页，page从1开始，limit为空时每页10条，最多MAX_PAGE_SIZE条
+    /// wp中有无效的列等错误时返回错误
+    /// for example:
+    ///     let wp = search.page_wrapper(DynamicWrapper::new(&driver).select("user", &[]), &["id", "create_time"])?;
+    pub fn page_wrapper(
+        &self,
+        wp: DynamicWrapper,
+        allowed: &[&str],
+    ) -> ChimesResult<DynamicWrapper> {
+        let orders = self.order_list(allowed)?;
+        let orders = orders
+            .iter()
+            .map(|(column, is_asc)| (column.as_str(), *is_asc))
+            .collect::<Vec<(&str, bool)>>();
+        let mut wp = self.try_into_dynamic_wrapper(wp)?;
+        if !orders.is_empty() {
+            wp = wp.order_bys(&orders);
+        }
+        wp.check()?;
+        Ok(wp.page(self.page_no(), self.page_size()))
+    }
+
+    /// 执行count和列表两个查询，wp需要使用select设置查询的表
+    /// for example:
+    ///     let page = search.fetch_page::<User>(rb, DynamicWrapper::new(&driver).select("user", &[]), &["id"]).await?;
+    pub async fn fetch_page<T>(
+        &self,
+        rb: &Rbatis,
+        wp: DynamicWrapper,
+        allowed: &[&str],
+    ) -> ChimesResult<PageResult<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let wp = self.page_wrapper(wp, allowed)?;
+        let total = wp.count_wrapper().fetch::<u64>(rb).await?;
+        let page = self.page_no();
+        let size = self.page_size();
+        let records = if total > page.saturating_sub(1).saturating_mul(size) {
+            wp.fetch::<Vec<T>>(rb).await?
+        } else {
+            vec![]
+        };
+        Ok(PageResult {
+            total,
+            page,
+            size,
+            records,
+        })
+    }
+
+    /// 生成一个条件，or为true时和前面的条件使用or连接
+    /// 无效的条件记录到errors中，并生成恒假的条件，避免去掉条件后扩大结果
+    fn apply_field<W: SearchWrapper>(
+        &self,
+        wp: W,
+        f: &SearchField,
+        or: bool,
+        errors: &mut Vec<ChimesError>,
+    ) -> W {
+        if !f.children.is_empty() {
+            return self.apply_group(wp, &f.children, is_or(&f.logic), or, errors);
+        }
+        let (column, condition) = match self.parse_condition(f) {
+            Ok(condition) => condition,
+            Err(err) => {
+                errors.push(err);
+                return connect(wp, or).push_sql("1 = 0");
             }
+        };
+        let len = wp.sql_len();
+        let wp = connect(wp, or);
+        let start = wp.sql_len();
+        let wp = apply_condition(wp, &column, condition);
+        if wp.sql_len() == start {
+            wp.truncate_sql(len)
+        } else {
+            wp
+        }
+    }
+
+    /// 生成加括号的一组条件，组内没有有效的条件时不生成
+    fn apply_group<W: SearchWrapper>(
+        &self,
+        wp: W,
+        fields: &[SearchField],
+        inner_or: bool,
+        or: bool,
+        errors: &mut Vec<ChimesError>,
+    ) -> W {
+        let len = wp.sql_len();
+        let mut wp = connect(wp, or).push_sql("(");
+        let start = wp.sql_len();
+        for f in fields {
+            wp = self.apply_field(wp, f, inner_or, errors);
+        }
+        if wp.sql_len() == start {
+            wp.truncate_sql(len)
+        } else {
+            wp.push_sql(")")
+        }
+    }
+
+    fn apply_search<W: SearchWrapper>(&self, mut wp: W, errors: &mut Vec<ChimesError>) -> W {
+        if is_or(&self.logic) && self.search.len() > 1 {
+            return self.apply_group(wp, &self.search, true, false, errors);
+        }
+        for f in self.search.iter() {
+            wp = self.apply_field(wp, f, false, errors);
+        }
+        wp
+    }
+
+    pub(crate) fn try_apply_search<W: SearchWrapper>(&self, wp: W) -> ChimesResult<W> {
+        let mut errors = vec![];
+        let wp = self.apply_search(wp, &mut errors);
+        // 返回所有无效的条件，错误码使用第一个错误的
+        let code = match errors.first().map(|f| &f.kind) {
+            Some(ErrorKind::Custom { code, .. }) => *code,
+            Some(_) => 10118,
+            None => return Ok(wp),
+        };
+        let msg = errors
+            .iter()
+            .map(|f| match &f.kind {
+                ErrorKind::Custom { msg, .. } => msg.clone(),
+                _ => f.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join("; ");
+        Err(ChimesError::custom(code, msg))
+    }
+
+    fn apply_search_lossy<W: SearchWrapper>(&self, wp: W) -> W {
+        let mut errors = vec![];
+        let wp = self.apply_search(wp, &mut errors);
+        for err in errors {
+            log::warn!("The search field is invalid and matches nothing: {}", err);
         }
         wp
     }
+
+    /// 生成条件，无效的条件替换为恒假的条件（1 = 0），只会减少结果
+    pub fn into_wrapper(&self, wp: Wrapper) -> Wrapper {
+        self.apply_search_lossy(wp)
+    }
+
+    /// 生成DynamicWrapper的条件，列名会经过DynamicWrapper的检查
+    pub fn into_dynamic_wrapper(&self, wp: DynamicWrapper) -> DynamicWrapper {
+        self.apply_search_lossy(wp)
+    }
+
+    /// 生成条件，有无效的条件时返回错误，如不支持的操作符、无法解析的值，错误中包括所有无效的条件
+    pub fn try_into_wrapper(&self, wp: Wrapper) -> ChimesResult<Wrapper> {
+        self.try_apply_search(wp)
+    }
+
+    pub fn try_into_dynamic_wrapper(&self, wp: DynamicWrapper) -> ChimesResult<DynamicWrapper> {
+        self.try_apply_search(wp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(search: &CommonSearch, index: usize) -> (String, String, String) {
+        let f = &search.search[index];
+        (
+            f.field.clone().unwrap_or_default(),
+            f.operator.clone().unwrap_or_default(),
+            f.val.clone().unwrap_or_default(),
+        )
+    }
+
+    #[test]
+    fn from_query_decodes_values() {
+        let search = CommonSearch::from_query(
+            "?filter[name][like]=a%20b+c&filter[city]=%E5%8C%97%E4%BA%AC&filter%5Bage%5D%5Bgt%5D=18",
+        )
+        .unwrap();
+        assert_eq!(search.search.len(), 3);
+        assert_eq!(
+            field(&search, 0),
+            ("name".to_string(), "like".to_string(), "a b c".to_string())
+        );
+        assert_eq!(
+            field(&search, 1),
+            ("city".to_string(), "eq".to_string(), "北京".to_string())
+        );
+        assert_eq!(
+            field(&search, 2),
+            ("age".to_string(), "gt".to_string(), "18".to_string())
+        );
+    }
+
+    #[test]
+    fn from_query_merges_repeated_keys() {
+        let search = CommonSearch::from_query(
+            "filter[id][in]=1&filter[id][in]=2,3&filter[age][range]=1&filter[age][range]=9\
+             &filter[name]=a&filter[name]=b",
+        )
+        .unwrap();
+        assert_eq!(search.search.len(), 4);
+        assert_eq!(
+            field(&search, 0),
+            ("id".to_string(), "in".to_string(), "1,2,3".to_string())
+        );
+        assert_eq!(
+            field(&search, 1),
+            ("age".to_string(), "range".to_string(), "1,9".to_string())
+        );
+        // 不是列表的操作符不合并
+        assert_eq!(field(&search, 2).2, "a");
+        assert_eq!(field(&search, 3).2, "b");
+    }
+
+    #[test]
+    fn from_query_paging_and_sort() {
+        let search =
+            CommonSearch::from_query("page=2&limit=20&sort=-id,+name&tz=%2B08:00&filter[a]=1&x=y")
+                .unwrap();
+        assert_eq!(search.page, Some(2));
+        assert_eq!(search.limit, Some(20));
+        assert_eq!(search.order.as_deref(), Some("id desc,name"));
+        assert_eq!(search.search.len(), 1);
+        assert_eq!(search.search[0].timezone.as_deref(), Some("+08:00"));
+        assert!(CommonSearch::from_query("page=abc").is_err());
+        assert!(CommonSearch::from_query("filter[a][b][c]=1").is_err());
+    }
+
+    #[test]
+    fn list_values_split_on_commas() {
+        let text = |list: &[&str]| {
+            list.iter()
+                .map(|f| rbatis::Value::String(f.to_string()))
+                .collect::<Vec<rbatis::Value>>()
+        };
+        // 先按逗号拆分再解码，编码的逗号是值的一部分
+        let search =
+            CommonSearch::from_query("filter[tag][in]=a%2Cb,c&filter[tag][in]=d%2Ce").unwrap();
+        assert_eq!(search.search[0].values, vec!["a,b", "c", "d,e"]);
+        assert_eq!(
+            search.split_value(&search.search[0]).unwrap(),
+            text(&["a,b", "c", "d,e"])
+        );
+        let search = CommonSearch::from_query("filter[name]=a%2Cb").unwrap();
+        assert_eq!(field(&search, 0).2, "a,b");
+        assert!(search.search[0].values.is_empty());
+
+        // JSON中没有values时仍然按逗号拆分val
+        let f: SearchField = serde_json::from_value(
+            serde_json::json!({"field": "tag", "operator": "in", "val": "a,b"}),
+        )
+        .unwrap();
+        assert_eq!(
+            CommonSearch::default().split_value(&f).unwrap(),
+            text(&["a", "b"])
+        );
+        let f: SearchField = serde_json::from_value(
+            serde_json::json!({"field": "tag", "operator": "in", "val": "x", "values": ["a,b"]}),
+        )
+        .unwrap();
+        assert_eq!(
+            CommonSearch::default().split_value(&f).unwrap(),
+            text(&["a,b"])
+        );
+    }
 }
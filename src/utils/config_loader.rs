@@ -0,0 +1,621 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::{config_issues_to_error, AppConfig, ChimesError, ChimesResult, InstanceType};
+
+/// 默认的环境变量前缀
+pub const DEFAULT_ENV_PREFIX: &str = "CHIMES";
+
+/// 分层加载AppConfig
+/// 优先级从低到高依次为：
+///   1. 基础配置文件，如 application.yaml，也支持.toml和.json格式
+///   2. Profile配置文件，如 application-prod.yaml，按添加顺序依次覆盖
+///   3. 环境变量，如 CHIMES_DATABASE__URL 对应 database.url，层级之间使用双下划线分隔
+///   4. 通过set方法设置的覆盖值
+///
+/// 激活的Profile还可以通过环境变量 CHIMES_PROFILES_ACTIVE=dev,local 指定，追加在代码指定的Profile之后
+///
+/// for example:
+///     let conf = AppConfigLoader::new("conf/application.yaml")
+///         .profile("prod")
+///         .set("webserver.port", 8080)
+///         .load()?;
+#[derive(Debug, Clone)]
+pub struct AppConfigLoader {
+    base_path: String,
+    profiles: Vec<String>,
+    env_prefix: Option<String>,
+    overrides: Vec<(String, String)>,
+    strict: bool,
+}
+
+impl AppConfigLoader {
+    pub fn new(base_path: &str) -> Self {
+        Self {
+            base_path: base_path.to_string(),
+            profiles: vec![],
+            env_prefix: Some(DEFAULT_ENV_PREFIX.to_string()),
+            overrides: vec![],
+            strict: false,
+        }
+    }
+
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profiles.push(profile.to_string());
+        self
+    }
+
+    pub fn env_prefix(mut self, prefix: &str) -> Self {
+        self.env_prefix = Some(prefix.trim_end_matches('_').to_uppercase());
+        self
+    }
+
+    /// 不读取环境变量
+    pub fn without_env(mut self) -> Self {
+        self.env_prefix = None;
+        self
+    }
+
+    /// 以点号分隔的路径设置覆盖值，如 set("database.url", "mysql://...")
+    pub fn set(mut self, path: &str, value: impl ToString) -> Self {
+        self.overrides.push((path.to_string(), value.to_string()));
+        self
+    }
+
+    /// 严格模式，校验失败时load返回错误
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    /// 当前激活的Profile，包括环境变量中指定的
+    pub fn active_profiles(&self) -> Vec<String> {
+        let mut profiles = self.profiles.clone();
+        if let Some(prefix) = self.env_prefix.clone() {
+            if let Ok(envs) = std::env::var(format!("{}_PROFILES_ACTIVE", prefix)) {
+                for p in envs.split(',') {
+                    let p = p.trim();
+                    if !p.is_empty() && !profiles.iter().any(|f| f == p) {
+                        profiles.push(p.to_string());
+                    }
+                }
+            }
+        }
+        profiles
+    }
+
+    /// Profile对应的配置文件，application.yaml + prod => application-prod.yaml
+    pub fn profile_path(&self, profile: &str) -> String {
+        let path = Path::new(&self.base_path);
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let filename = match path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, profile, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, profile),
+        };
+        path.with_file_name(filename).to_string_lossy().to_string()
+    }
+
+    /// 合并所有配置层，得到最终的配置文档
+    pub fn load_document(&self) -> ChimesResult<Yaml> {
+        let mut doc = read_config_file(&self.base_path)?;
+
+        for profile in self.active_profiles() {
+            let profile_path = self.profile_path(&profile);
+            if Path::new(&profile_path).exists() {
+                log::info!("Merging the profile {} from {}.", profile, profile_path);
+                let profile_doc = read_config_file(&profile_path)?;
+                merge_yaml(&mut doc, profile_doc);
+            } else {
+                log::warn!(
+                    "The config file {} for profile {} was not found.",
+                    profile_path,
+                    profile
+                );
+            }
+        }
+
+        if let Some(prefix) = self.env_prefix.clone() {
+            let marker = format!("{}_", prefix);
+            let profile_key = format!("{}_PROFILES_ACTIVE", prefix);
+            let mut envs: Vec<(String, String)> = std::env::vars()
+                .filter(|(k, _)| k.starts_with(&marker) && *k != profile_key)
+                .collect();
+            envs.sort();
+            for (key, value) in envs {
+                let path = key[marker.len()..]
+                    .split("__")
+                    .map(|s| s.to_lowercase())
+                    .collect::<Vec<String>>();
+                set_yaml_path(&mut doc, &path, Yaml::String(value));
+            }
+        }
+
+        for (path, value) in self.overrides.clone() {
+            let path = path
+                .split('.')
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+            set_yaml_path(&mut doc, &path, Yaml::String(value));
+        }
+
+        Ok(doc)
+    }
+
+    pub fn load(&self) -> ChimesResult<AppConfig> {
+        let doc = self.load_document()?;
+        let mut conf = AppConfig::default();
+        conf.load_yaml_doc(&doc)?;
+        if let Err(issues) = conf.validate() {
+            for issue in issues.iter() {
+                log::error!("Config error {}", issue);
+            }
+            if self.strict {
+                return Err(config_issues_to_error(&issues));
+            }
+        }
+        Ok(conf)
+    }
+}
+
+/// 配置文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// 根据文件扩展名判断格式，默认为YAML
+    pub fn from_path(path: &str) -> Self {
+        let ext = Path::new(path)
+            .extension()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "toml" => ConfigFormat::Toml,
+            "json" => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+pub(crate) fn read_config_file(conf_path: &str) -> ChimesResult<Yaml> {
+    let mut f = match File::open(conf_path) {
+        Ok(f) => f,
+        Err(err) => {
+            return Err(ChimesError::custom_err(
+                10080,
+                format!("Could not open the config file {}", conf_path),
+                err,
+            ));
+        }
+    };
+    let mut s = String::new();
+    if let Err(err) = f.read_to_string(&mut s) {
+        return Err(ChimesError::custom_err(
+            10080,
+            format!("Could not read the config file {}", conf_path),
+            err,
+        ));
+    }
+    match parse_config_text(ConfigFormat::from_path(conf_path), &s) {
+        Ok(doc) => Ok(doc),
+        Err(err) => Err(ChimesError::custom_err(
+            10081,
+            format!("Could not parse the config file {}", conf_path),
+            err,
+        )),
+    }
+}
+
+/// 解析配置文本，统一转换成YAML文档，便于分层合并和校验
+pub(crate) fn parse_config_text(format: ConfigFormat, text: &str) -> ChimesResult<Yaml> {
+    match format {
+        ConfigFormat::Yaml => match YamlLoader::load_from_str(text) {
+            Ok(mut docs) => {
+                if docs.is_empty() {
+                    Ok(Yaml::Hash(Hash::new()))
+                } else {
+                    Ok(docs.remove(0))
+                }
+            }
+            Err(err) => Err(ChimesError::custom_err(10081, err.to_string(), err)),
+        },
+        ConfigFormat::Toml => match toml::from_str::<Value>(text) {
+            Ok(val) => Ok(json_to_yaml(&val)),
+            Err(err) => Err(ChimesError::custom_err(10081, err.to_string(), err)),
+        },
+        ConfigFormat::Json => match serde_json::from_str::<Value>(text) {
+            Ok(val) => Ok(json_to_yaml(&val)),
+            Err(err) => Err(ChimesError::custom_err(10081, err.to_string(), err)),
+        },
+    }
+}
+
+pub(crate) fn yaml_to_json(node: &Yaml) -> Value {
+    match node {
+        Yaml::String(s) => Value::String(s.clone()),
+        Yaml::Integer(i) => Value::from(*i),
+        Yaml::Real(r) => match r.parse::<f64>() {
+            Ok(f) => Value::from(f),
+            Err(_) => Value::String(r.clone()),
+        },
+        Yaml::Boolean(b) => Value::Bool(*b),
+        Yaml::Array(arr) => Value::Array(arr.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(hash) => {
+            let mut map = Map::new();
+            for (k, v) in hash {
+                let key = match k {
+                    Yaml::String(s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Real(r) => r.clone(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => continue,
+                };
+                map.insert(key, yaml_to_json(v));
+            }
+            Value::Object(map)
+        }
+        _ => Value::Null,
+    }
+}
+
+/// 去掉mapping中值为null的配置项，如只写了 database: 的段按没有配置处理
+pub(crate) fn json_without_nulls(val: Value) -> Value {
+    match val {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, json_without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(arr) => Value::Array(arr.into_iter().map(json_without_nulls).collect()),
+        val => val,
+    }
+}
+
+pub(crate) fn json_to_yaml(val: &Value) -> Yaml {
+    match val {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Yaml::Integer(i),
+            None => Yaml::Real(n.to_string()),
+        },
+        Value::String(s) => Yaml::String(s.clone()),
+        Value::Array(arr) => Yaml::Array(arr.iter().map(json_to_yaml).collect()),
+        Value::Object(map) => {
+            let mut hash = Hash::new();
+            for (k, v) in map {
+                hash.insert(Yaml::String(k.clone()), json_to_yaml(v));
+            }
+            Yaml::Hash(hash)
+        }
+    }
+}
+
+/// 配置项的key同时支持kebab-case和snake_case，比较时统一成snake_case
+fn normalize_key(key: &str) -> String {
+    key.replace('-', "_").to_lowercase()
+}
+
+fn find_key(hash: &Hash, key: &str) -> Option<Yaml> {
+    let nk = normalize_key(key);
+    hash.keys()
+        .find(|k| k.as_str().map(|s| normalize_key(s) == nk).unwrap_or(false))
+        .cloned()
+}
+
+/// 将overlay深度合并到base中，Hash逐项合并，其它类型直接覆盖
+pub(crate) fn merge_yaml(base: &mut Yaml, overlay: Yaml) {
+    match overlay {
+        Yaml::Hash(overlay_hash) => {
+            if let Yaml::Hash(base_hash) = base {
+                for (k, v) in overlay_hash {
+                    let key = match k.as_str() {
+                        Some(s) => find_key(base_hash, s).unwrap_or(k),
+                        None => k,
+                    };
+                    match base_hash.get_mut(&key) {
+                        Some(bv) => merge_yaml(bv, v),
+                        None => {
+                            base_hash.insert(key, v);
+                        }
+                    }
+                }
+            } else {
+                *base = Yaml::Hash(overlay_hash);
+            }
+        }
+        Yaml::BadValue => {}
+        other => {
+            *base = other;
+        }
+    }
+}
+
+pub(crate) fn set_yaml_path(doc: &mut Yaml, path: &[String], value: Yaml) {
+    if path.is_empty() {
+        *doc = value;
+        return;
+    }
+    if !matches!(doc, Yaml::Hash(_)) {
+        *doc = Yaml::Hash(Hash::new());
+    }
+    if let Yaml::Hash(hash) = doc {
+        let key = find_key(hash, &path[0]).unwrap_or_else(|| Yaml::String(path[0].clone()));
+        let child = hash.entry(key).or_insert(Yaml::Null);
+        set_yaml_path(child, &path[1..], value);
+    }
+}
+
+/// 读取字段，kebab-case和snake_case两种写法都可以
+pub(crate) fn yaml_field<'a>(node: &'a Yaml, key: &str) -> &'a Yaml {
+    let val = &node[key];
+    if !val.is_badvalue() {
+        return val;
+    }
+    let alt = if key.contains('-') {
+        key.replace('-', "_")
+    } else {
+        key.replace('_', "-")
+    };
+    &node[alt.as_str()]
+}
+
+pub(crate) fn yaml_is_absent(node: &Yaml) -> bool {
+    node.is_null() || node.is_badvalue()
+}
+
+fn config_scalar_string(val: &Value) -> Option<String> {
+    match val {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// 以下为配置项的宽松反序列化，数字和布尔值可以写成字符串（如来自环境变量），
+/// 无法识别的值使用默认值，由AppConfig::validate负责报告
+pub(crate) fn config_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(config_scalar_string(&Value::deserialize(deserializer)?).unwrap_or_default())
+}
+
+pub(crate) fn config_opt_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(config_scalar_string(&Value::deserialize(deserializer)?))
+}
+
+pub(crate) fn config_i64<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.as_i64().unwrap_or_default(),
+        Value::String(s) => s.trim().parse::<i64>().unwrap_or_default(),
+        _ => 0i64,
+    })
+}
+
+pub(crate) fn config_u64<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(config_i64(deserializer)?.max(0) as u64)
+}
+
+pub(crate) fn config_u32<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(config_i64(deserializer)?.clamp(0, u32::MAX as i64) as u32)
+}
+
+pub(crate) fn config_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bool(b) => b,
+        Value::Number(n) => n.as_i64().map(|f| f != 0).unwrap_or_default(),
+        Value::String(s) => matches!(
+            s.trim().to_lowercase().as_str(),
+            "true" | "yes" | "on" | "1"
+        ),
+        _ => false,
+    })
+}
+
+/// 列表也可以用逗号分隔的字符串表示，方便通过环境变量设置
+pub(crate) fn config_string_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(arr) => arr.iter().filter_map(config_scalar_string).collect(),
+        Value::String(s) => s
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        _ => vec![],
+    })
+}
+
+pub(crate) fn config_default_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+pub(crate) fn config_level_filter<'de, D>(deserializer: D) -> Result<LevelFilter, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(config_scalar_string(&Value::deserialize(deserializer)?)
+        .and_then(|s| LevelFilter::from_str(&s).ok())
+        .unwrap_or(LevelFilter::Info))
+}
+
+pub(crate) fn config_instance_type<'de, D>(
+    deserializer: D,
+) -> Result<Option<InstanceType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        match config_scalar_string(&Value::deserialize(deserializer)?) {
+            Some(s) if s.to_lowercase() == "cluster" => Some(InstanceType::Cluster),
+            _ => Some(InstanceType::Single),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_sections_are_missing() {
+        let conf = AppConfig::from_str(
+            ConfigFormat::Yaml,
+            "database:\nwebserver:\n  port: 8080\n  rsa-key:\nredis:\ndatasources:\nlog-level:\n",
+        )
+        .unwrap();
+        assert_eq!(conf.db_conf, crate::DatabaseConfig::default());
+        assert_eq!(conf.webserver_conf.port, 8080);
+        assert_eq!(conf.webserver_conf.rsa_key, "");
+        assert_eq!(conf.redis_conf, None);
+        assert!(conf.datasources.is_empty());
+        assert_eq!(conf.logger_level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn toml_and_yaml_are_equivalent() {
+        let yaml = r#"
+log-level: debug
+database:
+  url: mysql://root@localhost/db
+  replicas: [mysql://replica/db]
+  retry:
+    attempts: 3
+webserver:
+  port: "8080"
+  upload-store-path: /data/upload
+redis:
+  urls: redis://localhost
+  pool:
+    max-size: 8
+datasources:
+  reporting:
+    url: postgres://reporting
+    log_level: warn
+    pool:
+      max_connections: 4
+"#;
+        let toml = r#"
+log-level = "debug"
+
+[database]
+url = "mysql://root@localhost/db"
+replicas = ["mysql://replica/db"]
+retry = { attempts = 3 }
+
+[webserver]
+port = 8080
+upload_store_path = "/data/upload"
+
+[redis]
+urls = ["redis://localhost"]
+pool = { max_size = 8 }
+
+[datasources.reporting]
+url = "postgres://reporting"
+log-level = "warn"
+pool = { max-connections = 4 }
+"#;
+        let a = AppConfig::from_str(ConfigFormat::Yaml, yaml).unwrap();
+        let b = AppConfig::from_str(ConfigFormat::Toml, toml).unwrap();
+        assert_eq!(a.db_conf, b.db_conf);
+        assert_eq!(a.webserver_conf, b.webserver_conf);
+        assert_eq!(a.redis_conf, b.redis_conf);
+        assert_eq!(a.datasources, b.datasources);
+        assert_eq!(a.logger_level, b.logger_level);
+        assert_eq!(a.logger_level, LevelFilter::Debug);
+        assert_eq!(a.db_conf.retry.attempts, 3);
+        assert_eq!(a.webserver_conf.port, 8080);
+        assert_eq!(a.redis_conf.unwrap().urls, vec!["redis://localhost"]);
+        assert_eq!(a.datasources["reporting"].pool.max_connections, 4);
+        assert_eq!(a.datasources["reporting"].logger_level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn layers_override_in_order() {
+        let dir = std::env::temp_dir().join(format!("chimes_loader_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+        write(
+            "application.yaml",
+            "database:\n  url: mysql://base\nwebserver:\n  port: 8000\n  upload-store-path: /base\nemail:\n  port: 25\n",
+        );
+        write(
+            "application-prod.yaml",
+            "database:\n  url: mysql://prod\nwebserver:\n  upload-store-path: /prod\n",
+        );
+        write("application-local.yaml", "email:\n  port: 465\n");
+        let base = dir.join("application.yaml").to_string_lossy().to_string();
+
+        // 使用单独的前缀，不影响其它测试
+        std::env::set_var("CHIMES_LOADER_T1_PROFILES_ACTIVE", "local, prod");
+        std::env::set_var("CHIMES_LOADER_T1_DATABASE__URL", "mysql://env");
+        std::env::set_var("CHIMES_LOADER_T1_WEBSERVER__PORT", "8500");
+        let loader = AppConfigLoader::new(&base)
+            .env_prefix("chimes_loader_t1_")
+            .profile("prod")
+            .set("webserver.port", 9000);
+        assert_eq!(loader.active_profiles(), vec!["prod", "local"]);
+        let conf = loader.load().unwrap();
+        assert_eq!(conf.db_conf.url, "mysql://env");
+        assert_eq!(conf.webserver_conf.port, 9000);
+        assert_eq!(conf.webserver_conf.upload_store_path, "/prod");
+        assert_eq!(conf.email_conf.port, "465");
+
+        let conf = loader.clone().without_env().load().unwrap();
+        assert_eq!(conf.db_conf.url, "mysql://prod");
+        assert_eq!(conf.email_conf.port, "25");
+
+        let conf = AppConfigLoader::new(&base)
+            .env_prefix("CHIMES_LOADER_T1")
+            .load()
+            .unwrap();
+        assert_eq!(conf.db_conf.url, "mysql://env");
+        assert_eq!(conf.webserver_conf.upload_store_path, "/prod");
+        assert_eq!(conf.webserver_conf.port, 8500);
+
+        std::env::remove_var("CHIMES_LOADER_T1_PROFILES_ACTIVE");
+        std::env::remove_var("CHIMES_LOADER_T1_DATABASE__URL");
+        std::env::remove_var("CHIMES_LOADER_T1_WEBSERVER__PORT");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(AppConfigLoader::new(&base).load().is_err());
+    }
+}
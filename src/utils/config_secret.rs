@@ -0,0 +1,168 @@
+use crate::{
+    rsa_decrypt_with_private_key, rsa_encrypt_with_public_key, AppConfig, ChimesError, ChimesResult,
+};
+
+const ENC_PREFIX: &str = "ENC(";
+const ENC_SUFFIX: &str = ")";
+
+/// 判断配置值是否为加密的形式 ENC(base64...)
+pub fn is_encrypted_config_value(value: &str) -> bool {
+    let value = value.trim();
+    value.starts_with(ENC_PREFIX) && value.ends_with(ENC_SUFFIX)
+}
+
+/// 解密配置值，不是ENC(...)形式的值原样返回
+pub fn decrypt_config_value(private_key: &str, value: &str) -> ChimesResult<String> {
+    if !is_encrypted_config_value(value) {
+        return Ok(value.to_string());
+    }
+    let value = value.trim();
+    let cipher = &value[ENC_PREFIX.len()..value.len() - ENC_SUFFIX.len()];
+    if private_key.is_empty() {
+        return Err(ChimesError::custom(
+            10083,
+            "The webserver.rsa_password_private_key is required to decrypt the ENC(...) value",
+        ));
+    }
+    match rsa_decrypt_with_private_key(private_key, cipher) {
+        Some(text) => Ok(text),
+        None => Err(ChimesError::custom(
+            10083,
+            "Could not decrypt the ENC(...) value",
+        )),
+    }
+}
+
+/// 使用公钥加密，生成可以直接写入配置文件的 ENC(base64...) 值
+pub fn encrypt_config_value_with_key(public_key: &str, plain: &str) -> ChimesResult<String> {
+    match rsa_encrypt_with_public_key(public_key, plain) {
+        Some(cipher) => Ok(format!("{}{}{}", ENC_PREFIX, cipher, ENC_SUFFIX)),
+        None => Err(ChimesError::custom(10083, "Could not encrypt the value")),
+    }
+}
+
+/// 使用当前配置的webserver.rsa_password_public_key加密
+pub fn encrypt_config_value(plain: &str) -> ChimesResult<String> {
+    let public_key = AppConfig::get()
+        .lock()
+        .unwrap()
+        .webserver_conf
+        .rsa_password_public_key
+        .clone();
+    encrypt_config_value_with_key(&public_key, plain)
+}
+
+fn decrypt_field(private_key: &str, path: &str, value: &mut String) -> ChimesResult<()> {
+    match decrypt_config_value(private_key, value) {
+        Ok(text) => {
+            *value = text;
+            Ok(())
+        }
+        Err(err) => Err(ChimesError::custom_err(
+            10083,
+            format!("Could not decrypt the config {}", path),
+            err,
+        )),
+    }
+}
+
+impl AppConfig {
+    /// 解密配置中ENC(...)形式的敏感信息，使用webserver.rsa_password_private_key
+    pub fn decrypt_secrets(&mut self) -> ChimesResult<()> {
+        let private_key = self.webserver_conf.rsa_password_private_key.clone();
+        decrypt_field(&private_key, "database.url", &mut self.db_conf.url)?;
+        for (i, replica) in self.db_conf.replicas.iter_mut().enumerate() {
+            decrypt_field(&private_key, &format!("database.replicas.{}", i), replica)?;
+        }
+        decrypt_field(
+            &private_key,
+            "email.password",
+            &mut self.email_conf.password,
+        )?;
+        if let Some(redis) = self.redis_conf.as_mut() {
+            if let Some(password) = redis.password.as_mut() {
+                decrypt_field(&private_key, "redis.password", password)?;
+            }
+        }
+        if let Some(secret) = self.app_secret.as_mut() {
+            decrypt_field(&private_key, "registry.app_secret", secret)?;
+        }
+        for (name, ds) in self.datasources.iter_mut() {
+            decrypt_field(
+                &private_key,
+                &format!("datasources.{}.url", name),
+                &mut ds.url,
+            )?;
+            for (i, replica) in ds.replicas.iter_mut().enumerate() {
+                decrypt_field(
+                    &private_key,
+                    &format!("datasources.{}.replicas.{}", name, i),
+                    replica,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigFormat;
+    use base64::prelude::*;
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey};
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+
+    fn key_pair() -> (String, String) {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        (
+            BASE64_STANDARD.encode(private_key.to_pkcs8_der().unwrap().as_ref()),
+            BASE64_STANDARD.encode(public_key.to_public_key_der().unwrap().as_ref()),
+        )
+    }
+
+    #[test]
+    fn encrypted_values_round_trip() {
+        let (private_key, public_key) = key_pair();
+        let value = encrypt_config_value_with_key(&public_key, "mysql://root:pw@db/app").unwrap();
+        assert!(is_encrypted_config_value(&value));
+        assert_eq!(
+            decrypt_config_value(&private_key, &format!(" {} ", value)).unwrap(),
+            "mysql://root:pw@db/app"
+        );
+        assert_eq!(decrypt_config_value("", "plain").unwrap(), "plain");
+
+        let text = format!(
+            "webserver:\n  rsa_password_private_key: {}\ndatabase:\n  url: {}\nredis:\n  urls: redis://localhost\n  password: {}\n",
+            private_key,
+            value,
+            encrypt_config_value_with_key(&public_key, "secret").unwrap()
+        );
+        let conf = AppConfig::from_str(ConfigFormat::Yaml, &text).unwrap();
+        assert_eq!(conf.db_conf.url, "mysql://root:pw@db/app");
+        assert_eq!(conf.redis_conf.unwrap().password.unwrap(), "secret");
+    }
+
+    #[test]
+    fn invalid_ciphertext_is_rejected() {
+        let (private_key, _) = key_pair();
+        let (_, other_public_key) = key_pair();
+        let other = encrypt_config_value_with_key(&other_public_key, "pw").unwrap();
+        for value in ["ENC(not base64!)", "ENC()", other.as_str()] {
+            let err = decrypt_config_value(&private_key, value).unwrap_err();
+            assert!(matches!(
+                err.kind,
+                crate::ErrorKind::Custom { code: 10083, .. }
+            ));
+        }
+        assert!(decrypt_config_value("", "ENC(abc)").is_err());
+
+        let text = format!(
+            "webserver:\n  rsa_password_private_key: {}\nemail:\n  password: ENC(abc)\n",
+            private_key
+        );
+        let err = AppConfig::from_str(ConfigFormat::Yaml, &text).unwrap_err();
+        assert!(err.to_string().contains("email.password"), "{}", err);
+    }
+}
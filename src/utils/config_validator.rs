@@ -0,0 +1,465 @@
+use std::fmt;
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde_derive::{Deserialize, Serialize};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::{yaml_field, yaml_is_absent, AppConfig, ChimesError};
+
+/// 配置校验发现的问题
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConfigIssue {
+    pub path: String,     // YAML路径，如 redis.pool.max_size
+    pub expected: String, // 期望的类型或取值范围
+    pub found: String,    // 实际的值
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, found {}",
+            self.path, self.expected, self.found
+        )
+    }
+}
+
+/// 将校验问题合并成一个错误
+pub fn config_issues_to_error(issues: &[ConfigIssue]) -> ChimesError {
+    let details = issues
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>()
+        .join("; ");
+    ChimesError::custom(
+        10082,
+        format!(
+            "Invalid configuration ({} problems): {}",
+            issues.len(),
+            details
+        ),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FieldKind {
+    Section,
+    Map,
+    Text,
+    Integer(i64, i64),
+    Boolean,
+    TextList,
+    LogLevel,
+    Choice(&'static [&'static str]),
+}
+
+impl FieldKind {
+    fn describe(&self) -> String {
+        match self {
+            FieldKind::Section => "a mapping".to_string(),
+            FieldKind::Map => "a mapping of names".to_string(),
+            FieldKind::Text => "a string".to_string(),
+            FieldKind::Integer(min, max) => format!("an integer in {}..={}", min, max),
+            FieldKind::Boolean => "a boolean".to_string(),
+            FieldKind::TextList => "a list of strings".to_string(),
+            FieldKind::LogLevel => "one of off, error, warn, info, debug, trace".to_string(),
+            FieldKind::Choice(list) => format!("one of {}", list.join(", ")),
+        }
+    }
+}
+
+/// 已知的配置项，kebab-case和snake_case两种写法等价，serde的别名也要列出
+const CONFIG_SCHEMA: &[(&str, FieldKind)] = &[
+    ("log-level", FieldKind::LogLevel),
+    ("database", FieldKind::Section),
+    ("database.url", FieldKind::Text),
+    ("database.log-level", FieldKind::LogLevel),
+    ("database.logger_level", FieldKind::LogLevel),
+    ("database.replicas", FieldKind::TextList),
+    (
+        "database.slow_sql_threshold",
+        FieldKind::Integer(0, i64::MAX),
+    ),
+    ("database.retry", FieldKind::Section),
+    (
+        "database.retry.attempts",
+        FieldKind::Integer(1, u32::MAX as i64),
+    ),
+    ("database.retry.backoff", FieldKind::Integer(0, i64::MAX)),
+    (
+        "database.retry.max_backoff",
+        FieldKind::Integer(0, i64::MAX),
+    ),
+    ("webserver", FieldKind::Section),
+    ("webserver.port", FieldKind::Integer(1, 65535)),
+    ("webserver.rsa_key", FieldKind::Text),
+    ("webserver.rsa_cert", FieldKind::Text),
+    ("webserver.rsa_password_private_key", FieldKind::Text),
+    ("webserver.rsa_password_public_key", FieldKind::Text),
+    ("webserver.upload-store-path", FieldKind::Text),
+    ("webserver.upload-temp-path", FieldKind::Text),
+    ("webserver.resources-path", FieldKind::Text),
+    ("webserver.access-url-prefix", FieldKind::Text),
+    ("email", FieldKind::Section),
+    ("email.smtp-server", FieldKind::Text),
+    ("email.account", FieldKind::Text),
+    ("email.mine_email", FieldKind::Text),
+    ("email.password", FieldKind::Text),
+    ("email.port", FieldKind::Integer(1, 65535)),
+    ("email.ssl", FieldKind::Boolean),
+    ("redis", FieldKind::Section),
+    ("redis.urls", FieldKind::TextList),
+    ("redis.database", FieldKind::Integer(0, i64::MAX)),
+    ("redis.username", FieldKind::Text),
+    ("redis.password", FieldKind::Text),
+    (
+        "redis.instance-type",
+        FieldKind::Choice(&["single", "cluster"]),
+    ),
+    ("redis.pool", FieldKind::Section),
+    (
+        "redis.pool.connection_timeout",
+        FieldKind::Integer(0, i64::MAX),
+    ),
+    (
+        "redis.pool.max_size",
+        FieldKind::Integer(1, u32::MAX as i64),
+    ),
+    (
+        "redis.pool.mini_idel",
+        FieldKind::Integer(0, u32::MAX as i64),
+    ),
+    ("registry", FieldKind::Section),
+    ("registry.gateway_address", FieldKind::Text),
+    ("registry.app_id", FieldKind::Text),
+    ("registry.app_secret", FieldKind::Text),
+    ("datasources", FieldKind::Map),
+];
+
+/// datasources下每个数据源的配置项
+const DATASOURCE_SCHEMA: &[(&str, FieldKind)] = &[
+    ("url", FieldKind::Text),
+    ("log-level", FieldKind::LogLevel),
+    ("logger_level", FieldKind::LogLevel),
+    ("pool", FieldKind::Section),
+    (
+        "pool.max_connections",
+        FieldKind::Integer(1, u32::MAX as i64),
+    ),
+    (
+        "pool.min_connections",
+        FieldKind::Integer(0, u32::MAX as i64),
+    ),
+    ("pool.connect_timeout", FieldKind::Integer(0, i64::MAX)),
+    ("pool.max_lifetime", FieldKind::Integer(0, i64::MAX)),
+    ("pool.idle_timeout", FieldKind::Integer(0, i64::MAX)),
+    ("replicas", FieldKind::TextList),
+    ("slow_sql_threshold", FieldKind::Integer(0, i64::MAX)),
+    ("retry", FieldKind::Section),
+    ("retry.attempts", FieldKind::Integer(1, u32::MAX as i64)),
+    ("retry.backoff", FieldKind::Integer(0, i64::MAX)),
+    ("retry.max_backoff", FieldKind::Integer(0, i64::MAX)),
+];
+
+fn normalize_key(key: &str) -> String {
+    key.replace('-', "_").to_lowercase()
+}
+
+fn describe_value(node: &Yaml) -> String {
+    match node {
+        Yaml::String(s) => format!("string {:?}", s),
+        Yaml::Integer(i) => format!("integer {}", i),
+        Yaml::Real(r) => format!("float {}", r),
+        Yaml::Boolean(b) => format!("boolean {}", b),
+        Yaml::Array(a) => format!("a list of {} items", a.len()),
+        Yaml::Hash(_) => "a mapping".to_string(),
+        Yaml::Alias(_) => "an alias".to_string(),
+        Yaml::Null => "null".to_string(),
+        Yaml::BadValue => "nothing".to_string(),
+    }
+}
+
+fn yaml_string_key(key: &Yaml) -> String {
+    match key {
+        Yaml::String(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        _ => "?".to_string(),
+    }
+}
+
+fn lookup<'a>(doc: &'a Yaml, path: &str) -> &'a Yaml {
+    let mut node = doc;
+    for seg in path.split('.') {
+        node = yaml_field(node, seg);
+    }
+    node
+}
+
+fn check_kind(node: &Yaml, kind: FieldKind) -> bool {
+    match kind {
+        FieldKind::Section | FieldKind::Map => matches!(node, Yaml::Hash(_)),
+        FieldKind::Text => matches!(
+            node,
+            Yaml::String(_) | Yaml::Integer(_) | Yaml::Real(_) | Yaml::Boolean(_)
+        ),
+        FieldKind::Integer(min, max) => {
+            let val = match node {
+                Yaml::Integer(i) => Some(*i),
+                Yaml::String(s) => s.trim().parse::<i64>().ok(),
+                _ => None,
+            };
+            val.map(|v| v >= min && v <= max).unwrap_or(false)
+        }
+        FieldKind::Boolean => match node {
+            Yaml::Boolean(_) => true,
+            Yaml::String(s) => matches!(
+                s.trim().to_lowercase().as_str(),
+                "true" | "false" | "yes" | "no" | "on" | "off" | "1" | "0"
+            ),
+            _ => false,
+        },
+        FieldKind::TextList => match node {
+            Yaml::Array(arr) => arr.iter().all(|f| matches!(f, Yaml::String(_))),
+            Yaml::String(_) => true,
+            _ => false,
+        },
+        FieldKind::LogLevel => match node {
+            Yaml::String(s) => LevelFilter::from_str(s).is_ok(),
+            _ => false,
+        },
+        FieldKind::Choice(list) => match node {
+            Yaml::String(s) => list.contains(&s.as_str()),
+            _ => false,
+        },
+    }
+}
+
+fn validate_schema(
+    doc: &Yaml,
+    prefix: &str,
+    schema: &[(&str, FieldKind)],
+    issues: &mut Vec<ConfigIssue>,
+) {
+    for (path, kind) in schema {
+        let node = lookup(doc, path);
+        if yaml_is_absent(node) {
+            continue;
+        }
+        if !check_kind(node, *kind) {
+            issues.push(ConfigIssue {
+                path: format!("{}{}", prefix, path),
+                expected: kind.describe(),
+                found: describe_value(node),
+            });
+            continue;
+        }
+
+        if let (FieldKind::Section, Yaml::Hash(hash)) = (kind, node) {
+            check_unknown_keys(hash, prefix, path, schema, issues);
+        }
+    }
+}
+
+/// 检查拼写错误的配置项，path为空时检查根节点下的配置项
+fn check_unknown_keys(
+    hash: &Hash,
+    prefix: &str,
+    path: &str,
+    schema: &[(&str, FieldKind)],
+    issues: &mut Vec<ConfigIssue>,
+) {
+    let section = if path.is_empty() {
+        String::new()
+    } else {
+        format!("{}.", normalize_key(path))
+    };
+    let key_path = |name: &str| {
+        if path.is_empty() {
+            format!("{}{}", prefix, name)
+        } else {
+            format!("{}{}.{}", prefix, path, name)
+        }
+    };
+    let known = schema
+        .iter()
+        .map(|(p, _)| normalize_key(p))
+        .filter(|p| p.starts_with(&section) && !p[section.len()..].contains('.'))
+        .collect::<Vec<String>>();
+    for key in hash.keys() {
+        let name = match key.as_str() {
+            Some(s) => s.to_string(),
+            None => {
+                issues.push(ConfigIssue {
+                    path: key_path("?"),
+                    expected: "a string key".to_string(),
+                    found: describe_value(key),
+                });
+                continue;
+            }
+        };
+        if !known.contains(&format!("{}{}", section, normalize_key(&name))) {
+            issues.push(ConfigIssue {
+                path: key_path(&name),
+                expected: format!(
+                    "one of the known keys: {}",
+                    known
+                        .iter()
+                        .map(|f| f[section.len()..].to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                found: "an unknown key".to_string(),
+            });
+        }
+    }
+}
+
+/// 按照配置项定义校验配置文档，返回所有发现的问题
+pub fn validate_config_document(doc: &Yaml) -> Vec<ConfigIssue> {
+    let mut issues = vec![];
+    if let Yaml::Hash(hash) = doc {
+        check_unknown_keys(hash, "", "", CONFIG_SCHEMA, &mut issues);
+    }
+    validate_schema(doc, "", CONFIG_SCHEMA, &mut issues);
+
+    if let Yaml::Hash(datasources) = lookup(doc, "datasources") {
+        for (key, entry) in datasources {
+            let name = yaml_string_key(key);
+            let prefix = format!("datasources.{}.", name);
+            let hash = match entry {
+                Yaml::Hash(hash) => hash,
+                _ => {
+                    issues.push(ConfigIssue {
+                        path: format!("datasources.{}", name),
+                        expected: FieldKind::Section.describe(),
+                        found: describe_value(entry),
+                    });
+                    continue;
+                }
+            };
+            check_unknown_keys(hash, &prefix, "", DATASOURCE_SCHEMA, &mut issues);
+            validate_schema(entry, &prefix, DATASOURCE_SCHEMA, &mut issues);
+            let url = lookup(entry, "url");
+            if yaml_is_absent(url) {
+                issues.push(ConfigIssue {
+                    path: format!("{}url", prefix),
+                    expected: "a database url".to_string(),
+                    found: describe_value(url),
+                });
+            }
+        }
+    }
+
+    let redis = lookup(doc, "redis");
+    if !yaml_is_absent(redis) {
+        let urls = lookup(doc, "redis.urls");
+        let empty = match urls {
+            Yaml::Array(arr) => arr.is_empty(),
+            Yaml::String(s) => s.trim().is_empty(),
+            _ => true,
+        };
+        if empty && !issues.iter().any(|f| f.path == "redis.urls") {
+            issues.push(ConfigIssue {
+                path: "redis.urls".to_string(),
+                expected: "at least one redis url".to_string(),
+                found: describe_value(urls),
+            });
+        }
+    }
+
+    issues
+}
+
+impl AppConfig {
+    /// 校验配置，一次性返回所有问题
+    pub fn validate(&self) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = match &self.source {
+            Some(doc) => validate_config_document(doc),
+            None => vec![],
+        };
+
+        // 校验最终生效的值，覆盖没有配置文档的情况
+        if let Some(redis) = &self.redis_conf {
+            if redis.pool.max_size == 0 && !issues.iter().any(|f| f.path == "redis.pool.max_size") {
+                issues.push(ConfigIssue {
+                    path: "redis.pool.max_size".to_string(),
+                    expected: FieldKind::Integer(1, u32::MAX as i64).describe(),
+                    found: "integer 0".to_string(),
+                });
+            }
+            if redis.pool.mini_idel > redis.pool.max_size && redis.pool.max_size > 0 {
+                issues.push(ConfigIssue {
+                    path: "redis.pool.mini_idel".to_string(),
+                    expected: format!(
+                        "an integer not greater than max_size {}",
+                        redis.pool.max_size
+                    ),
+                    found: format!("integer {}", redis.pool.mini_idel),
+                });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_config_text, ConfigFormat};
+
+    fn issues(text: &str) -> Vec<String> {
+        let doc = parse_config_text(ConfigFormat::Yaml, text).unwrap();
+        validate_config_document(&doc)
+            .iter()
+            .map(|f| f.path.clone())
+            .collect()
+    }
+
+    #[test]
+    fn aliases_and_null_sections_are_valid() {
+        let text = "log_level: info\ndatabase:\n  url: mysql://a\n  logger_level: debug\nemail:\n  mine_email: a@b.c\n  port: 465\nwebserver:\nredis:\ndatasources:\n  reporting:\n    url: postgres://b\n    logger_level: warn\n";
+        assert!(issues(text).is_empty(), "{:?}", issues(text));
+        let conf = AppConfig::from_str(ConfigFormat::Yaml, text).unwrap();
+        assert_eq!(conf.db_conf.logger_level, LevelFilter::Debug);
+        assert!(conf.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let text = "databse:\n  url: mysql://a\nwebserver:\n  prot: 80\ndatasources:\n  reporting:\n    url: postgres://b\n    max_connections: 4\n";
+        assert_eq!(
+            issues(text),
+            vec![
+                "databse",
+                "webserver.prot",
+                "datasources.reporting.max_connections"
+            ]
+        );
+    }
+
+    #[test]
+    fn issues_are_aggregated() {
+        let text =
+            "email:\n  port: abc\nredis:\n  urls: redis://localhost\n  pool:\n    max_size: 0\n";
+        let conf = AppConfig::from_str(ConfigFormat::Yaml, text).unwrap();
+        let found = conf.validate().unwrap_err();
+        assert_eq!(
+            found.iter().map(|f| f.path.as_str()).collect::<Vec<&str>>(),
+            vec!["email.port", "redis.pool.max_size"]
+        );
+        assert_eq!(found[0].found, "string \"abc\"");
+        let err = config_issues_to_error(&found);
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10082, .. }
+        ));
+        assert!(err.to_string().contains("2 problems"), "{}", err);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{reload_email_config, reload_global_redis, AppConfig, AppConfigLoader, ChimesResult};
+
+/// 配置的分段，热加载时按分段通知订阅者
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConfigSection {
+    Database,
+    WebServer,
+    Email,
+    Redis,
+    Registry,
+    LogLevel,
+    DataSources,
+}
+
+/// 配置变化的回调，参数为旧配置和新配置
+pub type ConfigChangeHandler = Arc<dyn Fn(&AppConfig, &AppConfig) + Send + Sync>;
+
+lazy_static! {
+    static ref CONFIG_SUBSCRIBERS: Mutex<Vec<(ConfigSection, ConfigChangeHandler)>> =
+        Mutex::new(vec![]);
+}
+
+impl AppConfig {
+    /// 比较两个配置，返回发生变化的分段
+    pub fn changed_sections(&self, other: &AppConfig) -> Vec<ConfigSection> {
+        let mut sections = vec![];
+        if self.db_conf != other.db_conf {
+            sections.push(ConfigSection::Database);
+        }
+        if self.webserver_conf != other.webserver_conf {
+            sections.push(ConfigSection::WebServer);
+        }
+        if self.email_conf != other.email_conf {
+            sections.push(ConfigSection::Email);
+        }
+        if self.redis_conf != other.redis_conf {
+            sections.push(ConfigSection::Redis);
+        }
+        if self.gateway_address != other.gateway_address
+            || self.app_id != other.app_id
+            || self.app_secret != other.app_secret
+        {
+            sections.push(ConfigSection::Registry);
+        }
+        if self.logger_level != other.logger_level {
+            sections.push(ConfigSection::LogLevel);
+        }
+        if self.datasources != other.datasources {
+            sections.push(ConfigSection::DataSources);
+        }
+        sections
+    }
+
+    /// 订阅某个分段的配置变化
+    pub fn subscribe<F>(section: ConfigSection, handler: F)
+    where
+        F: Fn(&AppConfig, &AppConfig) + Send + Sync + 'static,
+    {
+        CONFIG_SUBSCRIBERS
+            .lock()
+            .unwrap()
+            .push((section, Arc::new(handler)));
+    }
+
+    /// 重新加载配置，校验失败时保留原有配置
+    /// 返回发生变化的分段
+    pub fn reload(loader: &AppConfigLoader) -> ChimesResult<Vec<ConfigSection>> {
+        let newconf = loader.clone().strict().load()?;
+        let oldconf = {
+            let mut conf = AppConfig::get().lock().unwrap();
+            let oldconf = conf.to_owned();
+            *conf = newconf.clone();
+            oldconf
+        };
+
+        let sections = oldconf.changed_sections(&newconf);
+        if !sections.is_empty() {
+            log::info!("Config was reloaded, changed sections: {:?}", sections);
+            // 复制一份订阅者，回调中可以再订阅或者读取配置
+            let subscribers = CONFIG_SUBSCRIBERS.lock().unwrap().clone();
+            for (section, handler) in subscribers {
+                if sections.contains(&section) {
+                    handler(&oldconf, &newconf);
+                }
+            }
+        }
+        Ok(sections)
+    }
+
+    /// 启动配置文件监视线程，配置文件变化后自动重新加载
+    pub fn watch(loader: AppConfigLoader, interval: Duration) -> ConfigWatcher {
+        let watcher = ConfigWatcher {
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let stopped = watcher.stopped.clone();
+        std::thread::spawn(move || {
+            let mut last = config_files_modified(&loader);
+            log::info!("The config watcher was started for {}.", loader.base_path());
+            while !stopped.load(Ordering::Acquire) {
+                std::thread::sleep(interval);
+                let current = config_files_modified(&loader);
+                if current != last {
+                    last = current;
+                    if let Err(err) = AppConfig::reload(&loader) {
+                        log::error!("Reload the config with an error {}", err);
+                    }
+                }
+            }
+            log::info!("The config watcher was stopped.");
+        });
+        watcher
+    }
+}
+
+fn config_files_modified(loader: &AppConfigLoader) -> HashMap<String, Option<SystemTime>> {
+    let mut files = vec![loader.base_path().to_string()];
+    for profile in loader.active_profiles() {
+        files.push(loader.profile_path(&profile));
+    }
+    files
+        .into_iter()
+        .map(|f| {
+            let modified = Path::new(&f).metadata().and_then(|m| m.modified()).ok();
+            (f, modified)
+        })
+        .collect()
+}
+
+/// 配置文件监视线程的句柄
+#[derive(Debug, Clone)]
+pub struct ConfigWatcher {
+    stopped: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+/// 注册默认的配置变化处理：日志级别、邮件发送队列、Redis连接池
+pub fn install_default_config_handlers() {
+    AppConfig::subscribe(ConfigSection::LogLevel, |_, newconf| {
+        log::set_max_level(newconf.logger_level);
+    });
+    AppConfig::subscribe(ConfigSection::Email, |_, newconf| {
+        reload_email_config(&newconf.email_conf);
+    });
+    AppConfig::subscribe(ConfigSection::Redis, |_, _| {
+        reload_global_redis();
+    });
+    AppConfig::subscribe(ConfigSection::Database, |_, _| {
+        log::warn!("The database config was changed, it will take effect after restarting.");
+    });
+    AppConfig::subscribe(ConfigSection::DataSources, |_, _| {
+        log::warn!("The datasources config was changed, it will take effect after restarting.");
+    });
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::LevelFilter;
+use rbatis::core::db::DBPoolOptions;
+use rbatis::rbatis::Rbatis;
+
+use crate::{
+    AppConfig, ChimesError, ChimesResult, DataSourceConfig, DataSourcePoolConfig,
+    DataSourceRetryConfig, GlobalFilterIntercepter, MultipleDatabaseIntercepter,
+    SqlMetricsLogPlugin,
+};
+
+/// 默认数据源的名称，对应配置中的database段
+/// 如果datasources中也配置了default，以datasources中的为准
+pub const DEFAULT_DATASOURCE: &str = "default";
+
+/// 只读副本名称中的分隔符
+pub const REPLICA_SEPARATOR: &str = "#replica-";
+
+/// 默认的慢查询阈值，毫秒
+pub const DEFAULT_SLOW_SQL_THRESHOLD: u64 = 1000;
+
+lazy_static! {
+    // 数据源在进程内一直存在，所以使用&'static Rbatis
+    static ref DATASOURCE_REGISTRY: RwLock<HashMap<String, &'static Rbatis>> =
+        RwLock::new(HashMap::new());
+    // get_multiple_rbatis按连接地址创建的实例，和命名的数据源分开保存，地址不会与名称冲突
+    static ref URL_DATASOURCES: RwLock<HashMap<String, &'static Rbatis>> =
+        RwLock::new(HashMap::new());
+    // 每个数据源一个连接锁，一个数据源重试时不影响其它数据源的连接
+    static ref DATASOURCE_LINK_LOCKS: Mutex<HashMap<String, Arc<async_std::sync::Mutex<()>>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn datasource_link_lock(name: &str) -> Arc<async_std::sync::Mutex<()>> {
+    DATASOURCE_LINK_LOCKS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone()
+}
+
+pub(crate) fn new_rbatis(logger_level: Option<LevelFilter>, slow_sql_threshold: u64) -> Rbatis {
+    let mut rb = Rbatis::new();
+    rb.set_log_plugin(SqlMetricsLogPlugin::new(
+        logger_level.unwrap_or(LevelFilter::Info),
+        Duration::from_millis(slow_sql_threshold),
+    ));
+    rb.add_sql_intercept(MultipleDatabaseIntercepter());
+    rb.add_sql_intercept(GlobalFilterIntercepter());
+    rb
+}
+
+/// 创建并连接Rbatis，连接失败时记录日志
+pub async fn create_rbatis(
+    url: &str,
+    pool_options: DBPoolOptions,
+    logger_level: Option<LevelFilter>,
+) -> Rbatis {
+    let rb = new_rbatis(logger_level, DEFAULT_SLOW_SQL_THRESHOLD);
+    match rb.link_opt(url, pool_options).await {
+        Ok(_) => {
+            log::info!("Database was connected. Rbatis was initialized successfully.");
+        }
+        Err(err) => {
+            log::warn!("Error: {}", err);
+        }
+    };
+    rb
+}
+
+/// Rbatis是否已经连接成功
+pub fn is_rbatis_linked(rb: &Rbatis) -> bool {
+    rb.pool.get().is_some()
+}
+
+/// 按照重试策略连接Rbatis，全部失败时返回最后一次的错误
+pub async fn link_rbatis_with_retry(
+    rb: &Rbatis,
+    url: &str,
+    pool: &DataSourcePoolConfig,
+    retry: &DataSourceRetryConfig,
+) -> ChimesResult<()> {
+    let attempts = retry.attempts.max(1);
+    let mut failures = 0u32;
+    loop {
+        match rb.link_opt(url, pool.to_pool_options()).await {
+            Ok(_) => {
+                log::info!("Database was connected. Rbatis was initialized successfully.");
+                return Ok(());
+            }
+            Err(err) => {
+                failures += 1;
+                if failures >= attempts {
+                    return Err(ChimesError::custom_err(
+                        10090,
+                        format!(
+                            "Could not connect to the database after {} attempts",
+                            failures
+                        ),
+                        err,
+                    ));
+                }
+                let delay = retry.delay(failures);
+                log::warn!(
+                    "Connect to the database failed ({}/{}): {}, retry after {:?}",
+                    failures,
+                    attempts,
+                    err,
+                    delay
+                );
+                async_std::task::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// 注册数据源，同名的数据源已经存在时返回已存在的
+pub fn register_datasource(name: &str, rb: Rbatis) -> &'static Rbatis {
+    let mut registry = DATASOURCE_REGISTRY.write().unwrap();
+    if let Some(exist) = registry.get(name) {
+        return exist;
+    }
+    let rb: &'static Rbatis = Box::leak(Box::new(rb));
+    registry.insert(name.to_string(), rb);
+    rb
+}
+
+/// 查找按连接地址创建的Rbatis
+pub(crate) fn lookup_url_datasource(url: &str) -> Option<&'static Rbatis> {
+    URL_DATASOURCES.read().unwrap().get(url).copied()
+}
+
+/// 保存按连接地址创建的Rbatis，同一地址已经存在时返回已存在的
+pub(crate) fn register_url_datasource(url: &str, rb: Rbatis) -> &'static Rbatis {
+    let mut registry = URL_DATASOURCES.write().unwrap();
+    if let Some(exist) = registry.get(url) {
+        return exist;
+    }
+    let rb: &'static Rbatis = Box::leak(Box::new(rb));
+    registry.insert(url.to_string(), rb);
+    rb
+}
+
+/// 所有已经注册的数据源
+pub fn registered_datasources() -> Vec<(String, &'static Rbatis)> {
+    let mut list = DATASOURCE_REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), *v))
+        .collect::<Vec<(String, &'static Rbatis)>>();
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    list
+}
+
+/// 查找已经注册的数据源，不会创建
+pub fn lookup_datasource(name: &str) -> Option<&'static Rbatis> {
+    DATASOURCE_REGISTRY.read().unwrap().get(name).copied()
+}
+
+/// 读取数据源的配置，default数据源默认取自database段
+/// 只读副本的名称为 数据源名称#replica-序号，如 default#replica-0
+pub fn datasource_config(name: &str) -> Option<DataSourceConfig> {
+    if let Some((primary, index)) = name.split_once(REPLICA_SEPARATOR) {
+        let index = index.parse::<usize>().ok()?;
+        let ds = datasource_config(primary)?;
+        let url = ds.replicas.get(index)?.clone();
+        return Some(DataSourceConfig {
+            url,
+            replicas: vec![],
+            ..ds
+        });
+    }
+    let conf = AppConfig::get().lock().unwrap();
+    match conf.datasources.get(name) {
+        Some(ds) => Some(ds.clone()),
+        None => {
+            if name == DEFAULT_DATASOURCE {
+                Some(DataSourceConfig {
+                    url: conf.db_conf.url.clone(),
+                    logger_level: conf.db_conf.logger_level,
+                    retry: conf.db_conf.retry.clone(),
+                    replicas: conf.db_conf.replicas.clone(),
+                    slow_sql_threshold: conf.db_conf.slow_sql_threshold,
+                    ..Default::default()
+                })
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// 配置中的所有数据源名称
+pub fn datasource_names() -> Vec<String> {
+    let conf = AppConfig::get().lock().unwrap();
+    let mut names = conf.datasources.keys().cloned().collect::<Vec<String>>();
+    if !names.iter().any(|f| f == DEFAULT_DATASOURCE) {
+        names.push(DEFAULT_DATASOURCE.to_string());
+    }
+    names.sort();
+    names
+}
+
+/// 按名称获取数据源，第一次使用时根据配置创建
+/// 连接失败时记录日志，返回未连接的数据源
+/// for example:
+///     let rb = get_datasource("reporting").unwrap();
+pub fn get_datasource(name: &str) -> Option<&'static Rbatis> {
+    if let Some(rb) = lookup_datasource(name) {
+        return Some(rb);
+    }
+    async_std::task::block_on(get_datasource_async(name))
+}
+
+pub async fn get_datasource_async(name: &str) -> Option<&'static Rbatis> {
+    if let Some(rb) = lookup_datasource(name) {
+        return Some(rb);
+    }
+    match try_get_datasource_async(name).await {
+        Ok(rb) => Some(rb),
+        Err(err) => {
+            log::warn!("Error: {}", err);
+            lookup_datasource(name)
+        }
+    }
+}
+
+/// 按名称获取已经连接的数据源，未连接时按照重试策略重新连接
+pub fn try_get_datasource(name: &str) -> ChimesResult<&'static Rbatis> {
+    if let Some(rb) = lookup_datasource(name) {
+        if is_rbatis_linked(rb) {
+            return Ok(rb);
+        }
+    }
+    async_std::task::block_on(try_get_datasource_async(name))
+}
+
+pub async fn try_get_datasource_async(name: &str) -> ChimesResult<&'static Rbatis> {
+    if let Some(rb) = lookup_datasource(name) {
+        if is_rbatis_linked(rb) {
+            return Ok(rb);
+        }
+    }
+    reconnect_datasource(name).await
+}
+
+/// 重新连接还没有连接成功的数据源，已经连接的直接返回
+/// 适用于启动时数据库还没有就绪的情况
+pub async fn reconnect_datasource(name: &str) -> ChimesResult<&'static Rbatis> {
+    let ds = match datasource_config(name) {
+        Some(ds) => ds,
+        None => {
+            return Err(ChimesError::custom(
+                10091,
+                format!("The datasource {} was not configured", name),
+            ))
+        }
+    };
+    // 同一个数据源同一时间只允许一个连接过程，避免重复创建连接池
+    let link_lock = datasource_link_lock(name);
+    let _guard = link_lock.lock().await;
+    let rb = match lookup_datasource(name) {
+        Some(rb) => rb,
+        None => register_datasource(
+            name,
+            new_rbatis(Some(ds.logger_level), ds.slow_sql_threshold),
+        ),
+    };
+    if is_rbatis_linked(rb) {
+        return Ok(rb);
+    }
+    match link_rbatis_with_retry(rb, &ds.url, &ds.pool, &ds.retry).await {
+        Ok(_) => Ok(rb),
+        Err(err) => Err(ChimesError::custom_err(
+            10090,
+            format!("Could not connect to the datasource {}", name),
+            err,
+        )),
+    }
+}
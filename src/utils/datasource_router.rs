@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rbatis::executor::RBatisTxExecutor;
+use rbatis::rbatis::Rbatis;
+
+use crate::{
+    datasource_config, is_in_transaction, tokenize_with_spans, try_get_datasource,
+    try_get_datasource_async, ChimesError, ChimesResult, SqlToken, DEFAULT_DATASOURCE,
+    REPLICA_SEPARATOR,
+};
+
+/// 只读副本连接失败后，在这段时间内不再使用
+const REPLICA_COOLDOWN: Duration = Duration::from_secs(30);
+
+tokio::task_local! {
+    static FORCE_PRIMARY: bool;
+}
+
+lazy_static! {
+    static ref REPLICA_CURSORS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    static ref REPLICA_FAILURES: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// 判断SQL是否为只读查询，只有只读查询才会路由到只读副本
+/// 包含写入关键字或加锁的查询（如 SELECT ... FOR UPDATE）都视为写入
+/// 注释、字符串和引号中的标识符不参与判断，多条语句视为写入
+pub fn is_read_only_sql(sql: &str) -> bool {
+    let mut words = tokenize_with_spans(sql)
+        .into_iter()
+        .filter_map(|(token, _, _)| match token {
+            SqlToken::Word(w) => Some(w.to_lowercase()),
+            SqlToken::Symbol(f) if f == ";" => Some(f),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+    // 结尾的分号不算多条语句
+    while words.last().map(|f| f == ";").unwrap_or(false) {
+        words.pop();
+    }
+    match words.first().map(|f| f.as_str()) {
+        Some("select") | Some("with") | Some("show") => !words.iter().any(|f| {
+            matches!(
+                f.as_str(),
+                "insert"
+                    | "update"
+                    | "delete"
+                    | "replace"
+                    | "merge"
+                    | "into"
+                    | "lock"
+                    | "share"
+                    | ";"
+            )
+        }),
+        _ => false,
+    }
+}
+
+/// 当前任务是否强制使用主库
+pub fn is_force_primary() -> bool {
+    FORCE_PRIMARY.try_with(|f| *f).unwrap_or(false)
+}
+
+/// 在future内强制使用主库，如写入后需要立即读到最新的数据
+/// for example:
+///     let user = with_primary(async { ... }).await;
+pub async fn with_primary<F: Future>(f: F) -> F::Output {
+    FORCE_PRIMARY.scope(true, f).await
+}
+
+/// with_primary的同步版本
+pub fn with_primary_sync<F: FnOnce() -> R, R>(f: F) -> R {
+    FORCE_PRIMARY.sync_scope(true, f)
+}
+
+/// 数据源的只读副本名称
+pub fn replica_names(name: &str) -> Vec<String> {
+    match datasource_config(name) {
+        Some(ds) => (0..ds.replicas.len())
+            .map(|i| format!("{}{}{}", name, REPLICA_SEPARATOR, i))
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn next_replica_cursor(name: &str) -> usize {
+    let mut cursors = REPLICA_CURSORS.lock().unwrap();
+    let cursor = cursors.entry(name.to_string()).or_insert(0);
+    let current = *cursor;
+    *cursor = cursor.wrapping_add(1);
+    current
+}
+
+fn is_replica_cooling(replica: &str) -> bool {
+    let mut failures = REPLICA_FAILURES.lock().unwrap();
+    match failures.get(replica) {
+        Some(time) if time.elapsed() < REPLICA_COOLDOWN => true,
+        Some(_) => {
+            failures.remove(replica);
+            false
+        }
+        None => false,
+    }
+}
+
+fn mark_replica_failed(replica: &str) {
+    REPLICA_FAILURES
+        .lock()
+        .unwrap()
+        .insert(replica.to_string(), Instant::now());
+}
+
+/// 写入和事务使用的主库
+pub async fn write_datasource_async(name: &str) -> ChimesResult<&'static Rbatis> {
+    try_get_datasource_async(name).await
+}
+
+/// 只读查询使用的数据源，按顺序轮询只读副本
+/// 强制主库、在事务中、没有配置副本或者副本都不可用时使用主库
+pub async fn read_datasource_async(name: &str) -> ChimesResult<&'static Rbatis> {
+    if is_force_primary() || is_in_transaction() {
+        return write_datasource_async(name).await;
+    }
+    let replicas = replica_names(name);
+    if !replicas.is_empty() {
+        let start = next_replica_cursor(name);
+        for i in 0..replicas.len() {
+            let replica = &replicas[(start + i) % replicas.len()];
+            if is_replica_cooling(replica) {
+                continue;
+            }
+            match try_get_datasource_async(replica).await {
+                Ok(rb) => return Ok(rb),
+                Err(err) => {
+                    log::warn!("The replica {} is unavailable: {}", replica, err);
+                    mark_replica_failed(replica);
+                }
+            }
+        }
+        log::warn!("No replica is available for {}, use the primary.", name);
+    }
+    write_datasource_async(name).await
+}
+
+/// 根据SQL语句选择数据源，只读查询使用只读副本，其他使用主库
+pub async fn route_datasource_async(name: &str, sql: &str) -> ChimesResult<&'static Rbatis> {
+    if is_read_only_sql(sql) {
+        read_datasource_async(name).await
+    } else {
+        write_datasource_async(name).await
+    }
+}
+
+pub fn write_datasource(name: &str) -> ChimesResult<&'static Rbatis> {
+    try_get_datasource(name)
+}
+
+pub fn read_datasource(name: &str) -> ChimesResult<&'static Rbatis> {
+    async_std::task::block_on(read_datasource_async(name))
+}
+
+pub fn route_datasource(name: &str, sql: &str) -> ChimesResult<&'static Rbatis> {
+    async_std::task::block_on(route_datasource_async(name, sql))
+}
+
+/// 在主库上开始事务
+pub async fn begin_transaction(name: &str) -> ChimesResult<RBatisTxExecutor<'static>> {
+    let rb = write_datasource_async(name).await?;
+    match rb.acquire_begin().await {
+        Ok(tx) => Ok(tx),
+        Err(err) => Err(ChimesError::custom_err(
+            10092,
+            format!("Could not begin the transaction on {}", name),
+            err,
+        )),
+    }
+}
+
+/// 默认数据源的只读查询实例，出错时返回主库
+pub fn get_read_rbatis() -> &'static Rbatis {
+    match read_datasource(DEFAULT_DATASOURCE) {
+        Ok(rb) => rb,
+        Err(err) => {
+            log::warn!("Error: {}", err);
+            crate::get_rbatis()
+        }
+    }
+}
+
+/// 默认数据源的主库实例，写入和事务使用
+pub fn get_write_rbatis() -> &'static Rbatis {
+    crate::get_rbatis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppConfig;
+
+    #[test]
+    fn read_only_sql() {
+        for sql in [
+            "select * from users where id = ?",
+            "SELECT name FROM users WHERE note = 'delete me' or note = 'insert into x';",
+            "-- delete old rows\nselect * from users",
+            "/* update */ select * from users",
+            "with recent as (select * from orders where created > ?) select * from recent",
+            "(select id from a) union (select id from b)",
+            "select `update`, \"delete\" from users",
+            "select update_time from users",
+            "show tables",
+        ] {
+            assert!(is_read_only_sql(sql), "{}", sql);
+        }
+        for sql in [
+            "insert into users(id) values (?)",
+            "update users set name = ?",
+            "select * from users for update",
+            "select * from users lock in share mode",
+            "select * from users for share",
+            "select * into backup from users",
+            "with moved as (delete from a returning *) insert into b select * from moved",
+            "select 1; delete from users",
+            "call refresh()",
+            "",
+        ] {
+            assert!(!is_read_only_sql(sql), "{}", sql);
+        }
+    }
+
+    async fn sqlite_rbatis(file: &str) -> Rbatis {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", file, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rb = Rbatis::new();
+        rb.link(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        rb
+    }
+
+    #[tokio::test]
+    async fn transaction_forces_primary() {
+        let name = "router_test";
+        AppConfig::get().lock().unwrap().datasources.insert(
+            name.to_string(),
+            crate::DataSourceConfig {
+                replicas: vec!["sqlite://unused".to_string()],
+                ..Default::default()
+            },
+        );
+        let primary =
+            crate::register_datasource(name, sqlite_rbatis("chimes_router_primary").await);
+        let replica = crate::register_datasource(
+            &replica_names(name)[0],
+            sqlite_rbatis("chimes_router_replica").await,
+        );
+        let same = |a: &Rbatis, b: &Rbatis| std::ptr::eq(a, b);
+
+        assert!(same(read_datasource_async(name).await.unwrap(), replica));
+        assert!(same(
+            route_datasource_async(name, "/* a */ select * from t")
+                .await
+                .unwrap(),
+            replica
+        ));
+        assert!(same(
+            route_datasource_async(name, "delete from t").await.unwrap(),
+            primary
+        ));
+        assert!(same(
+            with_primary(read_datasource_async(name)).await.unwrap(),
+            primary
+        ));
+
+        // 事务中的查询要读到事务中写入的数据，使用主库
+        let routed = crate::with_transaction(primary, |_tx| async move {
+            assert!(is_in_transaction());
+            let read = read_datasource_async(name).await?;
+            let routed = route_datasource_async(name, "select * from t").await?;
+            Ok((read, routed))
+        })
+        .await
+        .unwrap();
+        assert!(same(routed.0, primary));
+        assert!(same(routed.1, primary));
+        assert!(!is_in_transaction());
+    }
+}
@@ -120,6 +120,11 @@ pub fn rsa_decrypt_by_private_key(token: &String) -> Option<String> {
         .webserver_conf
         .rsa_password_private_key;
 
+    rsa_decrypt_with_private_key(&private_key, token)
+}
+
+/// 使用指定的私钥（Base64编码的PKCS8 DER）解密
+pub fn rsa_decrypt_with_private_key(private_key: &str, token: &str) -> Option<String> {
     let bs = match BASE64_STANDARD.decode(private_key) {
         Ok(rs) => rs,
         Err(_) => {
@@ -169,6 +174,11 @@ pub fn rsa_encrypt_by_public_key(token: &String) -> Option<String> {
         .webserver_conf
         .rsa_password_public_key;
 
+    rsa_encrypt_with_public_key(&public_key, token)
+}
+
+/// 使用指定的公钥（Base64编码的SPKI DER）加密
+pub fn rsa_encrypt_with_public_key(public_key: &str, token: &str) -> Option<String> {
     let bs = match BASE64_STANDARD.decode(public_key) {
         Ok(rs) => rs,
         Err(_) => {
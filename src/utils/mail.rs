@@ -1,13 +1,29 @@
-use crate::{global_app_data_resizing, AppConfig};
+use crate::{config_bool, config_string, global_app_data_resizing, AppConfig};
 use lettre::message::Mailbox;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::transport::smtp::SmtpTransport;
 use lettre::{Message, Transport};
+use serde_derive::Deserialize;
 use std::mem::MaybeUninit;
 use std::sync::{Arc, Once};
 use std::time::Duration;
 use tokio::sync::{Notify, RwLock};
 
+lazy_static! {
+    // 发送邮件使用的服务器配置，配置热加载时在其它线程中修改，发送时读取一份副本
+    static ref EMAIL_CLIENT_CONF: std::sync::RwLock<EmailServerConfig> =
+        std::sync::RwLock::new(EmailServerConfig::default());
+}
+
+/// 更新发送邮件使用的服务器配置，下一次发送时生效
+pub fn reload_email_config(conf: &EmailServerConfig) {
+    *EMAIL_CLIENT_CONF.write().unwrap() = conf.clone();
+}
+
+fn email_client_conf() -> EmailServerConfig {
+    EMAIL_CLIENT_CONF.read().unwrap().clone()
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct EmailBody {
     pub email_receiver: String,
@@ -30,18 +46,31 @@ impl EmailBody {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct EmailServerConfig {
+    #[serde(
+        rename = "smtp-server",
+        alias = "smtp_server",
+        deserialize_with = "config_string"
+    )]
     pub smtp_server: String,
+    #[serde(deserialize_with = "config_string")]
     pub password: String,
+    #[serde(deserialize_with = "config_string")]
     pub port: String,
+    #[serde(
+        rename = "account",
+        alias = "mine_email",
+        deserialize_with = "config_string"
+    )]
     pub mine_email: String,
+    #[serde(deserialize_with = "config_bool")]
     pub ssl: bool,
 }
 #[derive(Debug, Clone, Default)]
 pub struct EmailSendingQueue {
     queue: Vec<EmailBody>,
-    client_conf: EmailServerConfig,
     started: bool,
     notify: Arc<Notify>,
     lock: Arc<RwLock<u32>>,
@@ -51,13 +80,6 @@ impl EmailSendingQueue {
     pub fn new() -> Self {
         Self {
             queue: vec![],
-            client_conf: EmailServerConfig {
-                smtp_server: String::new(),
-                password: String::new(),
-                port: String::new(),
-                mine_email: String::new(),
-                ssl: false,
-            },
             started: false,
             notify: Arc::new(Notify::new()),
             lock: Arc::new(RwLock::new(2)),
@@ -72,17 +94,19 @@ impl EmailSendingQueue {
         mine_email: String,
         ssl: bool,
     ) {
-        self.client_conf.smtp_server = smtp_server;
-        self.client_conf.password = password.clone();
-        self.client_conf.mine_email = mine_email.clone();
-        self.client_conf.port = port;
-        self.client_conf.ssl = ssl;
+        reload_email_config(&EmailServerConfig {
+            smtp_server,
+            password,
+            port,
+            mine_email,
+            ssl,
+        });
     }
 
     pub async fn queue_send(&mut self, emb: &EmailBody) {
         let ck = self.lock.clone();
         let mut clemb = emb.clone();
-        clemb.mine_email = self.client_conf.mine_email.clone();
+        clemb.mine_email = email_client_conf().mine_email;
         let mu = ck.write().await;
         self.queue.push(clemb);
         drop(mu);
@@ -113,11 +137,6 @@ impl EmailSendingQueue {
 
 fn start_email_queue(msq: &'static mut EmailSendingQueue) {
     tokio::spawn(async move {
-        let creds = Credentials::new(
-            msq.client_conf.mine_email.clone(),
-            msq.client_conf.password.clone(),
-        );
-
         log::info!("The email sending queue was created and processing.");
 
         let notified = msq.notify.clone();
@@ -127,9 +146,12 @@ fn start_email_queue(msq: &'static mut EmailSendingQueue) {
             // Open connection to Gmail
             let mut fst = msq.queue.pop();
             if fst.is_some() {
-                let mut mailer = SmtpTransport::starttls_relay(&msq.client_conf.smtp_server)
+                // 每次发送时读取最新的配置，配置热加载后可以直接生效
+                let client_conf = email_client_conf();
+                let creds = Credentials::new(client_conf.mine_email, client_conf.password);
+                let mut mailer = SmtpTransport::starttls_relay(&client_conf.smtp_server)
                     .unwrap()
-                    .credentials(creds.clone())
+                    .credentials(creds)
                     .build();
 
                 while fst.is_some() {
@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+
+use chrono::Local;
+use openssl::sha::sha256;
+use rbatis::rbatis::Rbatis;
+use rbson::Bson;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    intercept_translation_enabled, rbatis_compatible_sql, split_sql_statements,
+    try_rbatis_compatible_sql, with_transaction, ChimesError, ChimesResult,
+    MultipleDatabaseIntercepter, SqlDialect,
+};
+
+/// 记录已执行的迁移的表
+pub const MIGRATION_TABLE: &str = "__chimes_migrations";
+
+/// 迁移文件，文件名为 V{version}__{description}.sql，如 V1__init.sql
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    pub path: String,
+    pub checksum: String,
+    pub sql: String,
+}
+
+/// 已经执行的迁移，读取自__chimes_migrations
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+    pub checksum: String,
+    pub installed_at: String,
+}
+
+/// 执行的结果，dry_run时statements为将要执行的语句
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub applied: Vec<i64>,
+    pub statements: Vec<String>,
+}
+
+/// 迁移文件的版本和描述，不是迁移文件时返回None
+fn parse_migration_name(file_name: &str) -> Option<(i64, String)> {
+    let stem = file_name.strip_suffix(".sql")?;
+    let stem = stem
+        .strip_prefix('V')
+        .or_else(|| stem.strip_prefix('v'))
+        .unwrap_or(stem);
+    let digits = stem.chars().take_while(|f| f.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let version = stem[..digits].parse::<i64>().ok()?;
+    let description = stem[digits..].trim_start_matches('_').replace('_', " ");
+    Some((version, description))
+}
+
+/// 计算迁移内容的校验和，忽略换行符的差异
+pub fn migration_checksum(sql: &str) -> String {
+    hex::encode(sha256(sql.replace("\r\n", "\n").as_bytes()))
+}
+
+/// 存储过程、函数、触发器的定义被分号拆开了，需要用DELIMITER修改分隔符
+fn is_broken_routine(statement: &str) -> bool {
+    let lower = statement.to_lowercase();
+    let words = lower
+        .split(|f: char| !f.is_alphanumeric() && f != '_')
+        .filter(|f| !f.is_empty())
+        .collect::<Vec<&str>>();
+    let routine = words.first() == Some(&"create")
+        && words
+            .iter()
+            .take(8)
+            .any(|f| matches!(*f, "procedure" | "function" | "trigger"));
+    if !routine {
+        return false;
+    }
+    if lower.contains("$$") {
+        lower.matches("$$").count() % 2 == 1
+    } else {
+        words.contains(&"begin") && words.last() != Some(&"end")
+    }
+}
+
+fn has_compatible_intercept(rb: &Rbatis) -> bool {
+    let name = std::any::type_name::<MultipleDatabaseIntercepter>();
+    rb.sql_intercepts.iter().any(|f| f.name() == name) && intercept_translation_enabled(rb)
+}
+
+/// 迁移的执行器
+/// 迁移文件按分号拆分语句，存储过程、触发器等需要用 DELIMITER 行修改分隔符，否则扫描时报错
+/// for example:
+///     let report = MigrationRunner::new("migrations").up(get_rbatis()).await?;
+#[derive(Debug, Clone)]
+pub struct MigrationRunner {
+    dir: String,
+    dry_run: bool,
+}
+
+impl MigrationRunner {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+            dry_run: false,
+        }
+    }
+
+    /// 只返回将要执行的语句，不修改数据库
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// 读取目录中的迁移文件，按版本排序
+    pub fn scan(&self) -> ChimesResult<Vec<Migration>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                return Err(ChimesError::custom_err(
+                    10100,
+                    format!("Could not read the migration directory {}", self.dir),
+                    err,
+                ))
+            }
+        };
+        let mut migrations: Vec<Migration> = vec![];
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = match path.file_name().and_then(|f| f.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let (version, description) = match parse_migration_name(&file_name) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let sql = match std::fs::read_to_string(&path) {
+                Ok(sql) => sql,
+                Err(err) => {
+                    return Err(ChimesError::custom_err(
+                        10100,
+                        format!("Could not read the migration {}", path.display()),
+                        err,
+                    ))
+                }
+            };
+            if let Some(exist) = migrations.iter().find(|f| f.version == version) {
+                return Err(ChimesError::custom(
+                    10103,
+                    format!(
+                        "The migration version {} is duplicated in {} and {}",
+                        version, exist.path, file_name
+                    ),
+                ));
+            }
+            if let Some(statement) = split_sql_statements(&sql)
+                .into_iter()
+                .find(|f| is_broken_routine(f))
+            {
+                return Err(ChimesError::custom(
+                    10104,
+                    format!(
+                        "The routine in the migration {} is split by ';', use DELIMITER to change the delimiter: {}",
+                        file_name, statement
+                    ),
+                ));
+            }
+            migrations.push(Migration {
+                version,
+                description,
+                path: path.display().to_string(),
+                checksum: migration_checksum(&sql),
+                sql,
+            });
+        }
+        migrations.sort_by_key(|f| f.version);
+        Ok(migrations)
+    }
+
+    fn dialect(rb: &Rbatis) -> SqlDialect {
+        rb.driver_type()
+            .ok()
+            .and_then(|f| SqlDialect::from_driver_type(&f))
+            .unwrap_or(SqlDialect::MySql)
+    }
+
+    /// 执行时会经过MultipleDatabaseIntercepter的不再转换
+    fn compatible_sql(rb: &Rbatis, sql: &str) -> ChimesResult<String> {
+        if has_compatible_intercept(rb) {
+            Ok(sql.to_string())
+        } else {
+            try_rbatis_compatible_sql(rb, sql)
+        }
+    }
+
+    fn create_table_sql(rb: &Rbatis) -> String {
+        let columns = "(version BIGINT NOT NULL PRIMARY KEY, description VARCHAR(200) NOT NULL, \
+                       checksum VARCHAR(64) NOT NULL, installed_at VARCHAR(32) NOT NULL)";
+        match Self::dialect(rb) {
+            SqlDialect::MsSql => format!(
+                "IF OBJECT_ID(N'{}', N'U') IS NULL CREATE TABLE {} {}",
+                MIGRATION_TABLE, MIGRATION_TABLE, columns
+            ),
+            _ => format!("CREATE TABLE IF NOT EXISTS {} {}", MIGRATION_TABLE, columns),
+        }
+    }
+
+    fn insert_sql() -> String {
+        format!(
+            "INSERT INTO {} (version, description, checksum, installed_at) VALUES (?, ?, ?, ?)",
+            MIGRATION_TABLE
+        )
+    }
+
+    fn insert_args(migration: &Migration) -> Vec<Bson> {
+        vec![
+            Bson::Int64(migration.version),
+            Bson::String(migration.description.clone()),
+            Bson::String(migration.checksum.clone()),
+            Bson::String(Local::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+        ]
+    }
+
+    async fn ensure_table(&self, rb: &Rbatis) -> ChimesResult<()> {
+        let sql = Self::create_table_sql(rb);
+        match rb.exec(&sql, vec![]).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(ChimesError::sql(
+                10102,
+                "Could not create the migration table",
+                sql,
+                err,
+            )),
+        }
+    }
+
+    /// 已经执行的迁移，dry_run时迁移表不存在视为没有执行过
+    pub async fn applied(&self, rb: &Rbatis) -> ChimesResult<Vec<AppliedMigration>> {
+        let sql = format!(
+            "SELECT version, description, checksum, installed_at FROM {} ORDER BY version",
+            MIGRATION_TABLE
+        );
+        match rb.fetch::<Vec<AppliedMigration>>(&sql, vec![]).await {
+            Ok(list) => Ok(list),
+            Err(err) => {
+                if self.dry_run {
+                    log::info!("The migration table was not available: {}", err);
+                    Ok(vec![])
+                } else {
+                    Err(ChimesError::sql(
+                        10102,
+                        "Could not read the migration table",
+                        sql,
+                        err,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// 检查已执行的迁移文件是否被修改或删除
+    pub fn check_drift(migrations: &[Migration], applied: &[AppliedMigration]) -> ChimesResult<()> {
+        let files = migrations
+            .iter()
+            .map(|f| (f.version, f))
+            .collect::<HashMap<i64, &Migration>>();
+        let mut problems = vec![];
+        for done in applied {
+            match files.get(&done.version) {
+                Some(file) if file.checksum != done.checksum => problems.push(format!(
+                    "version {} checksum {} was changed to {} ({})",
+                    done.version, done.checksum, file.checksum, file.path
+                )),
+                None => problems.push(format!(
+                    "version {} ({}) was applied but the file is missing",
+                    done.version, done.description
+                )),
+                _ => {}
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ChimesError::custom(
+                10101,
+                format!("Migration checksum drift: {}", problems.join("; ")),
+            ))
+        }
+    }
+
+    /// 执行所有未执行的迁移，每个迁移在一个事务中执行
+    pub async fn up(&self, rb: &'static Rbatis) -> ChimesResult<MigrationReport> {
+        let migrations = self.scan()?;
+        if !self.dry_run {
+            self.ensure_table(rb).await?;
+        }
+        let applied = self.applied(rb).await?;
+        Self::check_drift(&migrations, &applied)?;
+
+        let latest = applied.iter().map(|f| f.version).max();
+        let mut report = MigrationReport {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+        for migration in migrations
+            .into_iter()
+            .filter(|f| !applied.iter().any(|a| a.version == f.version))
+        {
+            if latest.map(|f| migration.version < f).unwrap_or(false) {
+                log::warn!(
+                    "The migration {} is older than the latest applied version, apply it out of order.",
+                    migration.version
+                );
+            }
+            let statements = split_sql_statements(&migration.sql);
+            let translated = statements
+                .iter()
+                .map(|f| try_rbatis_compatible_sql(rb, f))
+                .collect::<ChimesResult<Vec<String>>>()
+                .map_err(|err| {
+                    ChimesError::custom_err(
+                        10102,
+                        format!("Translate the migration {} failed", migration.version),
+                        err,
+                    )
+                })?;
+            report.statements.extend(translated);
+            if self.dry_run {
+                report.applied.push(migration.version);
+                continue;
+            }
+
+            if self.apply(rb, &migration, statements).await? {
+                report.applied.push(migration.version);
+            }
+        }
+        Ok(report)
+    }
+
+    /// 在一个事务中执行迁移，先插入迁移记录，version是主键，同一版本只有一个实例能执行
+    /// 其它实例已经执行了这个版本时返回false
+    /// MySQL的DDL会隐式提交，执行失败时迁移记录可能已经提交，需要手动处理
+    async fn apply(
+        &self,
+        rb: &'static Rbatis,
+        migration: &Migration,
+        statements: Vec<String>,
+    ) -> ChimesResult<bool> {
+        log::info!(
+            "Apply the migration {} {}",
+            migration.version,
+            migration.description
+        );
+        let insert = Self::compatible_sql(rb, &Self::insert_sql())?;
+        let args = Self::insert_args(migration);
+        let claim = insert.clone();
+        let result = with_transaction(rb, |tx| async move {
+            tx.exec(&claim, args).await?;
+            for sql in statements.iter() {
+                tx.exec(&Self::compatible_sql(rb, sql)?, vec![]).await?;
+            }
+            Ok(())
+        })
+        .await;
+        let err = match result {
+            Ok(_) => return Ok(true),
+            Err(err) => err,
+        };
+        if err.statement() == Some(insert.as_str()) {
+            let applied = self.applied(rb).await.unwrap_or_default();
+            if applied
+                .iter()
+                .any(|f| f.version == migration.version && f.checksum == migration.checksum)
+            {
+                log::info!(
+                    "The migration {} was applied by another instance.",
+                    migration.version
+                );
+                return Ok(false);
+            }
+        }
+        let sql = err.statement().unwrap_or_default().to_string();
+        Err(ChimesError::sql(
+            10102,
+            format!("Apply the migration {} failed", migration.version),
+            sql,
+            err,
+        ))
+    }
+
+    /// 将version及以前的迁移标记为已执行，不执行其中的语句
+    /// 用于已有数据库开始使用迁移的情况
+    pub async fn baseline(
+        &self,
+        rb: &'static Rbatis,
+        version: i64,
+    ) -> ChimesResult<MigrationReport> {
+        let migrations = self.scan()?;
+        if !self.dry_run {
+            self.ensure_table(rb).await?;
+        }
+        let applied = self.applied(rb).await?;
+        let mut report = MigrationReport {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+        let insert = Self::compatible_sql(rb, &Self::insert_sql())?;
+        for migration in migrations
+            .iter()
+            .filter(|f| f.version <= version && !applied.iter().any(|a| a.version == f.version))
+        {
+            report.applied.push(migration.version);
+            report
+                .statements
+                .push(rbatis_compatible_sql(rb, &Self::insert_sql()));
+            if self.dry_run {
+                continue;
+            }
+            if let Err(err) = rb.exec(&insert, Self::insert_args(migration)).await {
+                return Err(ChimesError::sql(
+                    10102,
+                    format!("Baseline the migration {} failed", migration.version),
+                    insert,
+                    err,
+                ));
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// 执行目录中未执行的迁移
+pub async fn migrate_up(dir: &str, rb: &'static Rbatis) -> ChimesResult<MigrationReport> {
+    MigrationRunner::new(dir).up(rb).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_routine_detection() {
+        assert!(is_broken_routine(
+            "CREATE TRIGGER tr BEFORE INSERT ON t FOR EACH ROW BEGIN SET NEW.a = 1"
+        ));
+        assert!(is_broken_routine(
+            "create or replace function f() returns int as $$ begin return 1"
+        ));
+        assert!(!is_broken_routine(
+            "CREATE TRIGGER tr BEFORE INSERT ON t FOR EACH ROW BEGIN SET NEW.a = 1; END"
+        ));
+        assert!(!is_broken_routine(
+            "create function f() returns int as $$ begin return 1; end $$ language plpgsql"
+        ));
+        assert!(!is_broken_routine("create table t (a int)"));
+    }
+
+    fn migration_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, sql) in files {
+            std::fs::write(dir.join(file), sql).unwrap();
+        }
+        dir.display().to_string()
+    }
+
+    async fn sqlite_rbatis(file: &str) -> &'static Rbatis {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", file, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rb = Rbatis::new();
+        rb.link(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        Box::leak(Box::new(rb))
+    }
+
+    fn applied_versions(list: &[AppliedMigration]) -> Vec<i64> {
+        list.iter().map(|f| f.version).collect()
+    }
+
+    const FILES: [(&str, &str); 3] = [
+        (
+            "V9__create_items.sql",
+            "CREATE TABLE mig_items (id INTEGER PRIMARY KEY);",
+        ),
+        (
+            "V10__add_item.sql",
+            "INSERT INTO mig_items (id) VALUES (1);\r\nINSERT INTO mig_items (id) VALUES (2);",
+        ),
+        ("README.md", "not a migration"),
+    ];
+
+    #[test]
+    fn migration_names_and_order() {
+        assert_eq!(
+            parse_migration_name("V10__add_index.sql"),
+            Some((10, "add index".to_string()))
+        );
+        assert_eq!(
+            parse_migration_name("v2_init.sql"),
+            Some((2, "init".to_string()))
+        );
+        assert_eq!(parse_migration_name("Vx__init.sql"), None);
+        assert_eq!(parse_migration_name("V1__init.txt"), None);
+
+        let dir = migration_dir("chimes_mig_scan", &FILES);
+        let migrations = MigrationRunner::new(&dir).scan().unwrap();
+        assert_eq!(
+            migrations.iter().map(|f| f.version).collect::<Vec<i64>>(),
+            vec![9, 10]
+        );
+    }
+
+    #[test]
+    fn checksum_ignores_line_endings() {
+        assert_eq!(
+            migration_checksum("SELECT 1;\r\nSELECT 2;\r\n"),
+            migration_checksum("SELECT 1;\nSELECT 2;\n")
+        );
+        assert_ne!(
+            migration_checksum("SELECT 1;"),
+            migration_checksum("SELECT 2;")
+        );
+    }
+
+    #[test]
+    fn drift_is_detected() {
+        let dir = migration_dir("chimes_mig_drift", &FILES);
+        let migrations = MigrationRunner::new(&dir).scan().unwrap();
+        let applied = migrations
+            .iter()
+            .map(|f| AppliedMigration {
+                version: f.version,
+                description: f.description.clone(),
+                checksum: f.checksum.clone(),
+                installed_at: String::new(),
+            })
+            .collect::<Vec<AppliedMigration>>();
+        assert!(MigrationRunner::check_drift(&migrations, &applied).is_ok());
+
+        let mut changed = applied.clone();
+        changed[0].checksum = migration_checksum("CREATE TABLE other (id INTEGER);");
+        let err = MigrationRunner::check_drift(&migrations, &changed).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10101, .. }
+        ));
+
+        let err = MigrationRunner::check_drift(&migrations[1..], &applied).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10101, .. }
+        ));
+        assert!(err.to_string().contains("version 9"));
+    }
+
+    #[tokio::test]
+    async fn up_applies_pending_migrations_once() {
+        let rb = sqlite_rbatis("chimes_mig_up").await;
+        let dir = migration_dir("chimes_mig_up", &FILES);
+        let runner = MigrationRunner::new(&dir);
+
+        let report = runner.up(rb).await.unwrap();
+        assert_eq!(report.applied, vec![9, 10]);
+        let rows: Vec<serde_json::Value> = rb
+            .fetch("SELECT id FROM mig_items ORDER BY id", vec![])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            applied_versions(&runner.applied(rb).await.unwrap()),
+            vec![9, 10]
+        );
+
+        let report = runner.up(rb).await.unwrap();
+        assert!(report.applied.is_empty());
+
+        std::fs::write(
+            std::path::Path::new(&dir).join("V9__create_items.sql"),
+            "CREATE TABLE mig_items (id BIGINT PRIMARY KEY);",
+        )
+        .unwrap();
+        let err = runner.up(rb).await.unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10101, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_claimed_by_another_instance_is_skipped() {
+        let rb = sqlite_rbatis("chimes_mig_claim").await;
+        let dir = migration_dir("chimes_mig_claim", &FILES);
+        let runner = MigrationRunner::new(&dir);
+        runner.ensure_table(rb).await.unwrap();
+        let migrations = runner.scan().unwrap();
+        // 另一个实例已经插入了迁移记录
+        rb.exec(
+            &MigrationRunner::insert_sql(),
+            MigrationRunner::insert_args(&migrations[0]),
+        )
+        .await
+        .unwrap();
+
+        let statements = split_sql_statements(&migrations[0].sql);
+        assert!(!runner.apply(rb, &migrations[0], statements).await.unwrap());
+        let tables: Vec<serde_json::Value> = rb
+            .fetch(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'mig_items'",
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert!(tables.is_empty());
+
+        // 记录不同的迁移不能当作已经执行
+        let mut other = migrations[0].clone();
+        other.checksum = migration_checksum("SELECT 1;");
+        let err = runner.apply(rb, &other, vec![]).await.unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10102, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_migration_is_not_recorded() {
+        let rb = sqlite_rbatis("chimes_mig_fail").await;
+        let dir = migration_dir(
+            "chimes_mig_fail",
+            &[(
+                "V1__broken.sql",
+                "CREATE TABLE mig_broken (id INTEGER);\nINSERT INTO missing_table VALUES (1);",
+            )],
+        );
+        let runner = MigrationRunner::new(&dir);
+        let err = runner.up(rb).await.unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::ErrorKind::Custom { code: 10102, .. }
+        ));
+        assert!(err
+            .statement()
+            .unwrap_or_default()
+            .contains("missing_table"));
+        assert!(runner.applied(rb).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_touch_the_database() {
+        let rb = sqlite_rbatis("chimes_mig_dry").await;
+        let dir = migration_dir("chimes_mig_dry", &FILES);
+        let runner = MigrationRunner::new(&dir).dry_run(true);
+
+        let report = runner.up(rb).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.applied, vec![9, 10]);
+        assert_eq!(report.statements.len(), 3);
+        assert!(report.statements[0].contains("CREATE TABLE mig_items"));
+
+        let tables: Vec<serde_json::Value> = rb
+            .fetch(
+                "SELECT name FROM sqlite_master WHERE type = 'table'",
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert!(tables.is_empty());
+    }
+
+    #[tokio::test]
+    async fn baseline_marks_versions_without_running_them() {
+        let rb = sqlite_rbatis("chimes_mig_baseline").await;
+        let dir = migration_dir("chimes_mig_baseline", &FILES);
+        rb.exec("CREATE TABLE mig_items (id INTEGER PRIMARY KEY)", vec![])
+            .await
+            .unwrap();
+        let runner = MigrationRunner::new(&dir);
+
+        let report = runner.baseline(rb, 9).await.unwrap();
+        assert_eq!(report.applied, vec![9]);
+        assert_eq!(
+            applied_versions(&runner.applied(rb).await.unwrap()),
+            vec![9]
+        );
+
+        let report = runner.up(rb).await.unwrap();
+        assert_eq!(report.applied, vec![10]);
+    }
+}
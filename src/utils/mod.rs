@@ -1,20 +1,22 @@
 use chrono::{DateTime, Local};
 use log::LevelFilter;
-use rbatis::DriverType;
+use rbatis::core::db::DBPoolOptions;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::Read;
 use std::mem::MaybeUninit;
-use std::str::FromStr;
 use std::sync::{Mutex, Once};
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 // use openssl::hash::{DigestBytes, MessageDigest};
 use openssl::sha::Sha256;
-use rbatis::log::LogPlugin;
 use rbatis::rbatis::Rbatis;
 use serde_derive::{Deserialize, Serialize};
+use yaml_rust::Yaml;
+
+use crate::{ChimesError, ChimesResult};
 
 mod rbatis_dynamical;
 pub use rbatis_dynamical::*;
@@ -35,6 +37,42 @@ pub use gpt_sdk::*;
 mod common_search;
 pub use common_search::*;
 
+mod search_schema;
+pub use search_schema::*;
+
+mod config_loader;
+pub use config_loader::*;
+
+mod config_validator;
+pub use config_validator::*;
+
+mod config_watcher;
+pub use config_watcher::*;
+
+mod config_secret;
+pub use config_secret::*;
+
+mod datasource;
+pub use datasource::*;
+
+mod datasource_router;
+pub use datasource_router::*;
+
+mod sql_dialect;
+pub use sql_dialect::*;
+
+mod sql_metrics;
+pub use sql_metrics::*;
+
+mod sql_filter;
+pub use sql_filter::*;
+
+mod transaction;
+pub use transaction::*;
+
+mod migration;
+pub use migration::*;
+
 #[cfg(windows)]
 mod windows_service_utils;
 
@@ -58,112 +96,49 @@ pub fn get_local_timestamp() -> u64 {
     date.timestamp_millis() as u64
 }
 
+/// 按连接地址获取Rbatis，和命名的数据源分开保存
 #[allow(dead_code)]
 pub fn get_multiple_rbatis(url: &str) -> &'static Rbatis {
-    // 使用MaybeUninit延迟初始化
-    static mut STATIC_MULTI_RB: MaybeUninit<HashMap<String, Rbatis>> = MaybeUninit::uninit();
-    // Once带锁保证只进行一次初始化
-    static ONCE_HASH: Once = Once::new();
-
-    ONCE_HASH.call_once(|| unsafe {
-        STATIC_MULTI_RB.as_mut_ptr().write(HashMap::new());
-    });
-
-    unsafe {
-        if (*STATIC_MULTI_RB.as_mut_ptr()).contains_key(url) {
-            let hashmap = &*STATIC_MULTI_RB.as_mut_ptr();
-            &hashmap[url]
-        } else {
-            async_std::task::block_on(async {
-                log::info!("Call the block on to create the sql connection.");
-                let mut rb = Rbatis::new();
-                match rb.link(url).await {
-                    Ok(_) => {
-                        log::info!(
-                            "Database {} was connected. Rbatis was initialized successfully.",
-                            url
-                        );
-                    }
-                    Err(err) => {
-                        log::warn!("Error: {}", err);
-                    }
-                };
-                rb.add_sql_intercept(MultipleDatabaseIntercepter());
-                (*STATIC_MULTI_RB.as_mut_ptr()).insert(url.to_string(), rb);
-            });
-            let hashmap = &*STATIC_MULTI_RB.as_mut_ptr();
-            &hashmap[url]
-        }
+    if let Some(rb) = lookup_url_datasource(url) {
+        return rb;
     }
+    async_std::task::block_on(async {
+        log::info!("Call the block on to create the sql connection.");
+        let rb = create_rbatis(url, DBPoolOptions::default(), None).await;
+        register_url_datasource(url, rb)
+    })
 }
 
 #[allow(dead_code)]
 pub async fn get_multiple_rbatis_async(url: &str) -> &'static Rbatis {
-    // 使用MaybeUninit延迟初始化
-    static mut STATIC_MULTI_RB: MaybeUninit<HashMap<String, Rbatis>> = MaybeUninit::uninit();
-    // Once带锁保证只进行一次初始化
-    static ONCE_HASH: Once = Once::new();
-
-    ONCE_HASH.call_once(|| unsafe {
-        STATIC_MULTI_RB.as_mut_ptr().write(HashMap::new());
-    });
-
-    unsafe {
-        if (*STATIC_MULTI_RB.as_mut_ptr()).contains_key(url) {
-            let hashmap = &*STATIC_MULTI_RB.as_mut_ptr();
-            &hashmap[url]
-        } else {
-            log::info!("Call the block on to create the sql connection.");
-            let mut rb = Rbatis::new();
-            match rb.link(url).await {
-                Ok(_) => {
-                    log::info!(
-                        "Database {} was connected. Rbatis was initialized successfully.",
-                        url
-                    );
-                }
-                Err(err) => {
-                    log::warn!("Error: {}", err);
-                }
-            };
-            rb.add_sql_intercept(MultipleDatabaseIntercepter());
-            (*STATIC_MULTI_RB.as_mut_ptr()).insert(url.to_string(), rb);
-            let hashmap = &*STATIC_MULTI_RB.as_mut_ptr();
-            &hashmap[url]
-        }
+    if let Some(rb) = lookup_url_datasource(url) {
+        return rb;
     }
+    let rb = create_rbatis(url, DBPoolOptions::default(), None).await;
+    register_url_datasource(url, rb)
+}
+
+/// 获取默认数据源，连接失败时返回错误
+/// 如果启动时数据库还没有就绪，会按照database.retry重新连接
+pub fn try_get_rbatis() -> ChimesResult<&'static Rbatis> {
+    try_get_datasource(DEFAULT_DATASOURCE)
 }
 
+pub async fn try_get_rbatis_async() -> ChimesResult<&'static Rbatis> {
+    try_get_datasource_async(DEFAULT_DATASOURCE).await
+}
+
+/// 获取默认数据源，连接失败时记录日志，返回未连接的实例，执行时返回错误
+/// 需要处理连接错误时使用try_get_rbatis
 #[allow(dead_code)]
 pub fn get_rbatis() -> &'static Rbatis {
-    // 使用MaybeUninit延迟初始化
-    static mut STATIC_RB: MaybeUninit<Rbatis> = MaybeUninit::uninit();
-    // Once带锁保证只进行一次初始化
-    static ONCE: Once = Once::new();
-
-    ONCE.call_once(|| unsafe {
-        // CONF = 1u64;
-        let conf = AppConfig::get().lock().unwrap().to_owned();
-        let url = conf.db_conf.url.clone();
-
-        async_std::task::block_on(async {
-            let mut rb = Rbatis::new();
-            match rb.link(&url).await {
-                Ok(_) => {
-                    log::info!("Database was connected. Rbatis was initialized successfully.");
-                }
-                Err(err) => {
-                    log::warn!("Error: {}", err);
-                }
-            };
-            let rb_log = rbatis::log::RbatisLogPlugin::default();
-            rb_log.set_level_filter(conf.db_conf.logger_level);
-            rb.set_log_plugin(rb_log);
-            rb.add_sql_intercept(MultipleDatabaseIntercepter());
-            STATIC_RB.as_mut_ptr().write(rb);
-        });
-    });
-    unsafe { &*STATIC_RB.as_ptr() }
+    match get_datasource(DEFAULT_DATASOURCE) {
+        Some(rb) => rb,
+        None => register_datasource(
+            DEFAULT_DATASOURCE,
+            new_rbatis(None, DEFAULT_SLOW_SQL_THRESHOLD),
+        ),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -177,44 +152,53 @@ impl rbatis::intercept::SqlIntercept for MultipleDatabaseIntercepter {
         _args: &mut Vec<rbson::Bson>,
         _is_prepared_sql: bool,
     ) -> Result<(), rbatis::core::Error> {
+        if !intercept_translation_enabled(rb) {
+            return Ok(());
+        }
         log::debug!("Origal: {}", sql.clone());
-        let m_sql = rbatis_compatible_sql(rb, sql.as_str());
+        let m_sql = match try_rbatis_compatible_sql(rb, sql.as_str()) {
+            Ok(m_sql) => m_sql,
+            Err(err) => return Err(rbatis::core::Error::from(err.to_string())),
+        };
         log::debug!("Modify: {}", m_sql.clone());
         *sql = m_sql;
         Ok(())
     }
 }
 
+/// MultipleDatabaseIntercepter是否转换这个数据源的SQL，见set_intercept_translation
+pub fn intercept_translation_enabled(rb: &Rbatis) -> bool {
+    rb.driver_type()
+        .ok()
+        .and_then(|f| SqlDialect::from_driver_type(&f))
+        .map(is_intercept_translation)
+        .unwrap_or(false)
+}
+
 /**
  * 根据数据库类型来产生，对应的兼容的SQL查询语句
  * 原始参考SQL是MySQL
- * 如目标数据库类型是PostgreSQL，就将其转换成为$1这样的参数
+ * 如目标数据库类型是PostgreSQL，就将其转换成为$1这样的参数，详见translate_sql
+ * 无法转换时（如字符串中有\0）记录错误并返回原来的SQL，需要得到错误时使用try_rbatis_compatible_sql
  */
 pub fn rbatis_compatible_sql(rb: &Rbatis, sql: &str) -> String {
+    match try_rbatis_compatible_sql(rb, sql) {
+        Ok(m_sql) => m_sql,
+        Err(err) => {
+            log::error!("Could not translate the sql {}: {}", sql, err);
+            sql.to_owned()
+        }
+    }
+}
+
+/// 同rbatis_compatible_sql，无法转换时返回错误
+pub fn try_rbatis_compatible_sql(rb: &Rbatis, sql: &str) -> ChimesResult<String> {
     match rb.driver_type() {
-        Ok(driver_type) => match driver_type {
-            DriverType::Postgres => {
-                let mut sql_out = String::new();
-                let mut spl = sql.split('?');
-                let mut i = 1;
-                let count = spl.clone().count();
-                loop {
-                    let t = spl.next();
-                    if t.is_some() {
-                        sql_out.push_str(t.unwrap());
-                        if i < count {
-                            sql_out.push_str(format!("${}", i).as_str());
-                        }
-                    } else {
-                        break;
-                    }
-                    i += 1;
-                }
-                sql_out
-            }
-            _ => sql.to_owned(),
+        Ok(driver_type) => match SqlDialect::from_driver_type(&driver_type) {
+            Some(dialect) => translate_sql(sql, dialect),
+            None => Ok(sql.to_owned()),
         },
-        Err(_) => sql.to_owned(),
+        Err(_) => Ok(sql.to_owned()),
     }
 }
 
@@ -224,10 +208,13 @@ pub struct AppConfig {
     pub webserver_conf: WebServerConfig,
     pub email_conf: EmailServerConfig,
     pub redis_conf: Option<RedisConfig>,
+    pub datasources: HashMap<String, DataSourceConfig>,
     pub gateway_address: Option<String>,
     pub app_id: Option<String>,
     pub app_secret: Option<String>,
     pub logger_level: LevelFilter,
+    // 加载时的原始配置文档，只用于校验，其中的ENC(...)没有解密，不对外公开
+    pub(crate) source: Option<Yaml>,
 }
 
 impl Default for AppConfig {
@@ -237,18 +224,24 @@ impl Default for AppConfig {
             webserver_conf: Default::default(),
             email_conf: Default::default(),
             redis_conf: Default::default(),
+            datasources: Default::default(),
             gateway_address: Default::default(),
             app_id: Default::default(),
             app_secret: Default::default(),
             logger_level: LevelFilter::Info,
+            source: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct RedisPoolConfig {
+    #[serde(alias = "connection-timeout", deserialize_with = "config_u64")]
     pub connection_timeout: u64,
+    #[serde(alias = "max-size", deserialize_with = "config_u32")]
     pub max_size: u32,
+    #[serde(alias = "mini-idel", deserialize_with = "config_u32")]
     pub mini_idel: u32,
 }
 
@@ -259,40 +252,101 @@ pub enum InstanceType {
     Cluster,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct RedisConfig {
+    #[serde(deserialize_with = "config_string_list")]
     pub urls: Vec<String>,
+    #[serde(deserialize_with = "config_i64")]
     pub database: i64,
+    #[serde(deserialize_with = "config_opt_string")]
     pub username: Option<String>,
+    #[serde(deserialize_with = "config_opt_string")]
     pub password: Option<String>,
+    #[serde(
+        alias = "instance-type",
+        default = "RedisConfig::instance_type_option",
+        deserialize_with = "config_instance_type"
+    )]
     pub instance_type: Option<InstanceType>,
     pub pool: RedisPoolConfig,
 }
 
 impl RedisConfig {
-    #[allow(dead_code)]
     fn instance_type_default() -> InstanceType {
         InstanceType::Single
     }
+
+    fn instance_type_option() -> Option<InstanceType> {
+        Some(Self::instance_type_default())
+    }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct WebServerConfig {
+    #[serde(
+        default = "WebServerConfig::port_default",
+        deserialize_with = "config_i64"
+    )]
     pub port: i64,
+    #[serde(alias = "rsa-key", deserialize_with = "config_string")]
     pub rsa_key: String,
+    #[serde(alias = "rsa-cert", deserialize_with = "config_string")]
     pub rsa_cert: String,
+    #[serde(alias = "rsa-password-private-key", deserialize_with = "config_string")]
     pub rsa_password_private_key: String,
+    #[serde(alias = "rsa-password-public-key", deserialize_with = "config_string")]
     pub rsa_password_public_key: String,
+    #[serde(alias = "upload-store-path", deserialize_with = "config_string")]
     pub upload_store_path: String,
+    #[serde(alias = "resources-path", deserialize_with = "config_string")]
     pub resources_path: String,
+    #[serde(alias = "upload-temp-path", deserialize_with = "config_string")]
     pub upload_temp_path: String,
+    #[serde(alias = "access-url-prefix", deserialize_with = "config_string")]
     pub access_url_prefix: String,
 }
 
-#[derive(Debug, Clone)]
+impl Default for WebServerConfig {
+    fn default() -> Self {
+        Self {
+            port: Self::port_default(),
+            rsa_key: Default::default(),
+            rsa_cert: Default::default(),
+            rsa_password_private_key: Default::default(),
+            rsa_password_public_key: Default::default(),
+            upload_store_path: Default::default(),
+            resources_path: Default::default(),
+            upload_temp_path: Default::default(),
+            access_url_prefix: Default::default(),
+        }
+    }
+}
+
+impl WebServerConfig {
+    fn port_default() -> i64 {
+        10089i64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
 pub struct DatabaseConfig {
+    #[serde(deserialize_with = "config_string")]
     pub url: String,
+    #[serde(
+        rename = "log-level",
+        alias = "log_level",
+        alias = "logger_level",
+        deserialize_with = "config_level_filter"
+    )]
     pub logger_level: LevelFilter,
+    pub retry: DataSourceRetryConfig,
+    #[serde(deserialize_with = "config_string_list")]
+    pub replicas: Vec<String>, // 只读副本的连接地址
+    #[serde(alias = "slow-sql-threshold", deserialize_with = "config_u64")]
+    pub slow_sql_threshold: u64, // 慢查询日志的阈值，毫秒，0表示不记录
 }
 
 impl Default for DatabaseConfig {
@@ -300,6 +354,124 @@ impl Default for DatabaseConfig {
         Self {
             url: Default::default(),
             logger_level: LevelFilter::Info,
+            retry: Default::default(),
+            replicas: vec![],
+            slow_sql_threshold: DEFAULT_SLOW_SQL_THRESHOLD,
+        }
+    }
+}
+
+/// 数据源连接失败时的重试策略，时间单位为毫秒
+/// 每次失败后等待的时间加倍，但不超过max_backoff
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DataSourceRetryConfig {
+    #[serde(deserialize_with = "config_u32")]
+    pub attempts: u32,
+    #[serde(deserialize_with = "config_u64")]
+    pub backoff: u64,
+    #[serde(alias = "max-backoff", deserialize_with = "config_u64")]
+    pub max_backoff: u64,
+}
+
+impl Default for DataSourceRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: 1000,
+            max_backoff: 30000,
+        }
+    }
+}
+
+impl DataSourceRetryConfig {
+    /// 第n次失败后需要等待的时间
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u64
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff.saturating_mul(factor).min(self.max_backoff))
+    }
+}
+
+/// 数据源的连接池配置，时间单位为秒，0表示不限制
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DataSourcePoolConfig {
+    #[serde(alias = "max-connections", deserialize_with = "config_u32")]
+    pub max_connections: u32,
+    #[serde(alias = "min-connections", deserialize_with = "config_u32")]
+    pub min_connections: u32,
+    #[serde(alias = "connect-timeout", deserialize_with = "config_u64")]
+    pub connect_timeout: u64,
+    #[serde(alias = "max-lifetime", deserialize_with = "config_u64")]
+    pub max_lifetime: u64,
+    #[serde(alias = "idle-timeout", deserialize_with = "config_u64")]
+    pub idle_timeout: u64,
+}
+
+impl Default for DataSourcePoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 0,
+            connect_timeout: 60,
+            max_lifetime: 1800,
+            idle_timeout: 0,
+        }
+    }
+}
+
+impl DataSourcePoolConfig {
+    pub fn to_pool_options(&self) -> DBPoolOptions {
+        let timeout = |secs: u64| {
+            if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            }
+        };
+        DBPoolOptions {
+            max_connections: self.max_connections,
+            min_connections: self.min_connections,
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            max_lifetime: timeout(self.max_lifetime),
+            idle_timeout: timeout(self.idle_timeout),
+            ..Default::default()
+        }
+    }
+}
+
+/// datasources下的命名数据源
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DataSourceConfig {
+    #[serde(deserialize_with = "config_string")]
+    pub url: String,
+    pub pool: DataSourcePoolConfig,
+    pub retry: DataSourceRetryConfig,
+    #[serde(deserialize_with = "config_string_list")]
+    pub replicas: Vec<String>, // 只读副本的连接地址，使用相同的连接池配置
+    #[serde(alias = "slow-sql-threshold", deserialize_with = "config_u64")]
+    pub slow_sql_threshold: u64,
+    #[serde(
+        rename = "log-level",
+        alias = "log_level",
+        alias = "logger_level",
+        deserialize_with = "config_level_filter"
+    )]
+    pub logger_level: LevelFilter,
+}
+
+impl Default for DataSourceConfig {
+    fn default() -> Self {
+        Self {
+            url: Default::default(),
+            pool: Default::default(),
+            retry: Default::default(),
+            replicas: vec![],
+            slow_sql_threshold: DEFAULT_SLOW_SQL_THRESHOLD,
+            logger_level: LevelFilter::Info,
         }
     }
 }
@@ -321,6 +493,9 @@ impl AppConfig {
                 db_conf: DatabaseConfig {
                     url: "".to_string(),
                     logger_level: LevelFilter::Info,
+                    retry: Default::default(),
+                    replicas: vec![],
+                    slow_sql_threshold: DEFAULT_SLOW_SQL_THRESHOLD,
                 },
                 webserver_conf: WebServerConfig {
                     port: 10089i64,
@@ -335,10 +510,12 @@ impl AppConfig {
                 },
                 email_conf: EmailServerConfig::default(),
                 redis_conf: None,
+                datasources: HashMap::new(),
                 gateway_address: None,
                 app_id: None,
                 app_secret: None,
                 logger_level: LevelFilter::Info,
+                source: None,
             }));
         });
         unsafe { &*CONF.as_ptr() }
@@ -348,155 +525,101 @@ impl AppConfig {
         AppConfig::get().lock().unwrap().load_yaml(conf_path);
     }
 
+    /// 严格模式初始化，配置文件不存在或者校验失败时返回错误，不会修改全局配置
+    pub fn init_strict(conf_path: &str) -> ChimesResult<()> {
+        AppConfig::init_with(&AppConfigLoader::new(conf_path).without_env().strict())
+    }
+
+    /// 使用分层加载器初始化全局配置
+    pub fn init_with(loader: &AppConfigLoader) -> ChimesResult<()> {
+        let conf = loader.load()?;
+        *AppConfig::get().lock().unwrap() = conf;
+        Ok(())
+    }
+
     pub fn load_yaml(&mut self, conf_path: &str) {
-        use yaml_rust::yaml;
-        // open file
-        let mut f = match File::open(conf_path) {
-            Ok(f) => f,
-            Err(_) => return,
-        };
-        let mut s = String::new();
-        let _ = f.read_to_string(&mut s).is_ok();
-        // f.read_to_string(&mut s).unwrap(); // read file content to s
-        // load string to yaml loader
-        let docs = yaml::YamlLoader::load_from_str(&s).unwrap();
-        // get first yaml hash doc
-        // get server value
-        // let server = yaml_doc["weapp"].clone();
-        let doc = &docs[0];
-        let db = &doc["database"];
-        let web = &doc["webserver"];
-        let email = &doc["email"];
-        let redis = &doc["redis"];
-        let reg = &doc["registry"];
-
-        let log_level = doc["log-level"]
-            .as_str()
-            .map(|s| match LevelFilter::from_str(s) {
-                Ok(ll) => ll,
-                Err(_) => LevelFilter::Info,
-            })
-            .unwrap_or_else(|| LevelFilter::Info);
-
-        let gatewaddr = reg["gateway_address"].as_str().map(|s| s.to_string());
-        let app_id = reg["app_id"].as_str().map(|s| s.to_string());
-        let app_secret = reg["app_secret"].as_str().map(|s| s.to_string());
-
-        let dbconf = DatabaseConfig {
-            url: db["url"].as_str().map(|s| s.to_owned()).unwrap_or_default(),
-            logger_level: if let Some(l) = db["log-level"].as_str() {
-                match LevelFilter::from_str(l) {
-                    Ok(level) => level,
-                    Err(_) => LevelFilter::Info,
-                }
-            } else {
-                LevelFilter::Info
-            },
+        let doc = match read_config_file(conf_path) {
+            Ok(doc) => doc,
+            Err(err) => {
+                log::warn!("Load the config file with an error {}", err);
+                return;
+            }
         };
+        if let Err(err) = self.load_yaml_doc(&doc) {
+            log::warn!("Load the config file with an error {}", err);
+        }
+    }
 
-        let webconf = WebServerConfig {
-            port: web["port"].as_i64().unwrap_or(10089i64),
-            rsa_key: web["rsa_key"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            rsa_cert: web["rsa_cert"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            rsa_password_private_key: web["rsa_password_private_key"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            rsa_password_public_key: web["rsa_password_public_key"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            upload_store_path: web["upload-store-path"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            upload_temp_path: web["upload-temp-path"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            resources_path: web["resources-path"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            access_url_prefix: web["access-url-prefix"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-        };
-        let emailconf = EmailServerConfig {
-            smtp_server: email["smtp-server"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            mine_email: email["account"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            password: email["password"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            port: email["port"]
-                .as_str()
-                .map(|s| s.to_owned())
-                .unwrap_or_default(),
-            ssl: email["ssl"].as_bool().unwrap_or_default(),
-        };
+    /// 从文本中解析配置，支持YAML、TOML和JSON
+    pub fn from_str(format: ConfigFormat, text: &str) -> ChimesResult<AppConfig> {
+        let doc = parse_config_text(format, text)?;
+        let mut conf = AppConfig::default();
+        conf.load_yaml_doc(&doc)?;
+        Ok(conf)
+    }
 
-        let redis_conf = if !redis.is_null() {
-            let pool = &redis["pool"];
-            Some(RedisConfig {
-                urls: if let Some(s) = redis["urls"].as_vec() {
-                    let mut u = vec![];
-                    for ps in s.clone() {
-                        if let Some(st) = ps.as_str() {
-                            u.push(st.to_string());
-                        }
-                    }
-                    u
-                } else {
-                    vec![]
-                },
-                database: redis["database"].as_i64().unwrap_or_default(),
-                username: redis["username"].as_str().map(|s| s.to_owned()),
-                password: redis["password"].as_str().map(|s| s.to_owned()),
-                instance_type: if let Some(s) = redis["instance-type"].as_str() {
-                    if s == "cluster" {
-                        Some(InstanceType::Cluster)
-                    } else {
-                        Some(InstanceType::Single)
-                    }
-                } else {
-                    Some(InstanceType::Single)
-                },
-                pool: RedisPoolConfig {
-                    connection_timeout: pool["connection_timeout"].as_i64().unwrap_or_default()
-                        as u64,
-                    max_size: pool["max_size"].as_i64().unwrap_or_default() as u32,
-                    mini_idel: pool["mini_idel"].as_i64().unwrap_or_default() as u32,
-                },
-            })
-        } else {
-            None
-        };
+    pub fn load_yaml_doc(&mut self, doc: &Yaml) -> ChimesResult<()> {
+        let file: AppConfigFile =
+            match serde_json::from_value(json_without_nulls(yaml_to_json(doc))) {
+                Ok(t) => t,
+                Err(err) => {
+                    return Err(ChimesError::custom_err(
+                        10081,
+                        "Could not deserialize the config",
+                        err,
+                    ));
+                }
+            };
 
-        self.db_conf = dbconf;
-        self.webserver_conf = webconf;
-        self.email_conf = emailconf;
-        self.redis_conf = redis_conf;
-        self.gateway_address = gatewaddr;
-        self.app_id = app_id;
-        self.app_secret = app_secret;
-        self.logger_level = log_level;
+        self.db_conf = file.database;
+        self.webserver_conf = file.webserver;
+        self.email_conf = file.email;
+        self.redis_conf = file.redis;
+        self.datasources = file.datasources;
+        self.gateway_address = file.registry.gateway_address;
+        self.app_id = file.registry.app_id;
+        self.app_secret = file.registry.app_secret;
+        self.logger_level = file.log_level;
+        self.source = Some(doc.clone());
+        self.decrypt_secrets()
     }
 }
 
+/// 配置文件的结构，与AppConfig的字段一一对应
+#[derive(Debug, Clone, Deserialize)]
+struct AppConfigFile {
+    #[serde(default)]
+    database: DatabaseConfig,
+    #[serde(default)]
+    webserver: WebServerConfig,
+    #[serde(default)]
+    email: EmailServerConfig,
+    #[serde(default)]
+    redis: Option<RedisConfig>,
+    #[serde(default)]
+    registry: RegistryConfig,
+    #[serde(default)]
+    datasources: HashMap<String, DataSourceConfig>,
+    #[serde(
+        rename = "log-level",
+        alias = "log_level",
+        default = "config_default_level",
+        deserialize_with = "config_level_filter"
+    )]
+    log_level: LevelFilter,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct RegistryConfig {
+    #[serde(alias = "gateway-address", deserialize_with = "config_opt_string")]
+    gateway_address: Option<String>,
+    #[serde(alias = "app-id", deserialize_with = "config_opt_string")]
+    app_id: Option<String>,
+    #[serde(alias = "app-secret", deserialize_with = "config_opt_string")]
+    app_secret: Option<String>,
+}
+
 #[cfg(unix)]
 pub fn set_file_permission(fcp: &str, mode: u32) {
     use std::os::unix::fs::PermissionsExt;
@@ -620,6 +743,33 @@ pub fn parse_query_as_value(query_string: &str) -> Value {
     }
 }
 
+/// 解析查询字符串，保留重复的键和顺序，+解码为空格，%XX按UTF-8解码，无效的编码使用替换字符
+/// for example:
+///     parse_query_pairs("?a=1&a=2&b=x+y") [("a", "1"), ("a", "2"), ("b", "x y")]
+pub fn parse_query_pairs(query_string: &str) -> Vec<(String, String)> {
+    split_query_pairs(query_string)
+        .into_iter()
+        .map(|(k, v)| (decode_query_component(k), decode_query_component(v)))
+        .collect()
+}
+
+/// 拆分查询字符串，键和值都没有解码
+pub(crate) fn split_query_pairs(query_string: &str) -> Vec<(&str, &str)> {
+    query_string
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|f| !f.is_empty())
+        .map(|f| f.split_once('=').unwrap_or((f, "")))
+        .collect()
+}
+
+/// 解码查询字符串中的键或值，+解码为空格
+pub(crate) fn decode_query_component(text: &str) -> String {
+    percent_encoding::percent_decode_str(&text.replace('+', " "))
+        .decode_utf8_lossy()
+        .to_string()
+}
+
 #[allow(dead_code)]
 pub fn get_hash_value(query_params: &HashMap<String, String>, key: &str) -> String {
     match query_params.get(key) {
@@ -674,3 +824,47 @@ pub fn pure_datetime_to_date(dt: &rbatis::DateTimeNative) -> rbatis::DateNative
 pub fn datetime_diff(last: &rbatis::DateTimeNative, prev: &rbatis::DateTimeNative) -> i64 {
     last.and_utc().timestamp() - prev.and_utc().timestamp()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn query_pairs_decode() {
+        assert_eq!(
+            parse_query_pairs("?a=1&a=2&b=x+y"),
+            pairs(&[("a", "1"), ("a", "2"), ("b", "x y")])
+        );
+        assert_eq!(
+            parse_query_pairs("name=%E5%BC%A0%20%2B1&k%5B0%5D=a%26b&flag&&c="),
+            pairs(&[("name", "张 +1"), ("k[0]", "a&b"), ("flag", ""), ("c", "")])
+        );
+        assert_eq!(
+            parse_query_pairs("a=b=c&bad=%FF"),
+            pairs(&[("a", "b=c"), ("bad", "\u{fffd}")])
+        );
+        assert!(parse_query_pairs("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn url_datasources_are_separate() {
+        let path = std::env::temp_dir().join(format!("chimes_url_{}.db", std::process::id()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        // 与连接地址同名的数据源不会被get_multiple_rbatis使用
+        let named = register_datasource(&url, Rbatis::new());
+        let by_url = get_multiple_rbatis_async(&url).await;
+        assert!(!std::ptr::eq(named, by_url));
+        assert!(is_rbatis_linked(by_url));
+        assert!(std::ptr::eq(by_url, get_multiple_rbatis_async(&url).await));
+        assert!(std::ptr::eq(lookup_datasource(&url).unwrap(), named));
+        assert!(!registered_datasources()
+            .iter()
+            .any(|(_, rb)| std::ptr::eq(*rb, by_url)));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::get_local_timestamp;
+use crate::get_sql_metrics;
 use crate::ChimesError;
+use crate::SqlMetricsInfo;
 use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::sync::{atomic::AtomicU64, Mutex};
@@ -22,6 +24,8 @@ pub struct ChimesPerformanceInfo {
     pub handlers: u64,           // handlers
     pub success: bool,           // success or not
     pub counter: CustomCounterInfo,
+    #[serde(default)]
+    pub sql: SqlMetricsInfo, // SQL执行统计和数据源状态
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -331,6 +335,7 @@ impl ChimesPerformanceInfo {
             handlers: hc as u64,
             success: true,
             counter: get_custom_performance_counter().into_counting(),
+            sql: get_sql_metrics(),
         };
 
         Ok(newitem)
@@ -366,6 +371,7 @@ impl ChimesPerformanceInfo {
             handlers: hc as u64,
             success: true,
             counter: get_custom_performance_counter().into_counting(),
+            sql: get_sql_metrics(),
         };
         Ok(newitem)
     }
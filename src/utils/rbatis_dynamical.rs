@@ -2,11 +2,21 @@ use rbatis::rbatis_sql::ops::AsProxy;
 use rbson::Bson;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::RwLock;
 
+use base64::prelude::*;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_derive::Deserialize;
 
 use rbatis::core::convert::StmtConvert;
-use rbatis::core::db::DriverType;
+use rbatis::core::db::{DBExecResult, DriverType};
+use rbatis::rbatis::Rbatis;
+
+use crate::{
+    inline_sql_args, parse_identifier, shift_placeholders, split_top_level_keywords,
+    tokenize_with_spans, ChimesError, ChimesResult, ChimesTransaction, SqlDialect, SqlToken,
+};
 
 /// The packing/Wrapper of the SQL
 /// SQL passed into the Wrapper keep the keyword uppercase
@@ -41,6 +51,166 @@ pub struct DynamicWrapper {
     pub args: Vec<Bson>,
     //formats map[String]String. for example: map["x"] "{}::uuid"
     pub formats: HashMap<String, String>,
+    // 语句的开头部分，如 INSERT INTO ... / UPDATE t SET ... / DELETE FROM t，sql为其后的条件
+    pub head: String,
+    // args中属于head的参数个数，这些参数排在条件的参数之前
+    pub head_args: usize,
+    // 分页，在to_sql中按数据库生成
+    pub page_limit: Option<u64>,
+    pub page_offset: Option<u64>,
+    // 为true时按数据库引用列名，如 `name` "name" [name]
+    pub quote_columns: bool,
+    // 允许使用的列，为None时不限制
+    pub allowed_columns: Option<Vec<String>>,
+    // 构造时的错误，如无效或不允许的列名，执行时返回，有错误时to_sql不生成语句
+    pub errors: Vec<(i32, String)>,
+    // 键集分页的列名和每页的数量，用于生成下一页的游标
+    pub keyset_keys: Vec<String>,
+    pub keyset_size: u64,
+    // 乐观锁的版本列，执行后没有更新任何行时返回错误
+    pub version_column: Option<String>,
+}
+
+/// 键集分页的结果，next_cursor为None时没有下一页
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct KeysetPage<T> {
+    pub records: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// 生成游标，内容为row中keys的值按Bson扩展JSON组成的数组，使用base64编码
+/// 保留值的类型，如rbatis的DateTimeNative解析后仍按日期绑定，不会变为字符串
+pub fn encode_keyset_cursor<T: Serialize>(keys: &[String], row: &T) -> Option<String> {
+    let document = match rbson::to_bson(row).ok()? {
+        Bson::Document(document) => document,
+        _ => return None,
+    };
+    let values = keys
+        .iter()
+        .map(|key| {
+            document
+                .get(key)
+                .cloned()
+                .or_else(|| {
+                    document
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                        .map(|(_, v)| v.clone())
+                })
+                .map(|f| f.into_canonical_extjson())
+        })
+        .collect::<Option<Vec<serde_json::Value>>>()?;
+    let text = serde_json::to_string(&values).ok()?;
+    Some(BASE64_URL_SAFE_NO_PAD.encode(text))
+}
+
+/// 解析游标中的值
+pub fn decode_keyset_cursor(cursor: &str) -> Option<Vec<Bson>> {
+    let bytes = BASE64_URL_SAFE_NO_PAD.decode(cursor.trim()).ok()?;
+    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes)
+        .ok()?
+        .into_iter()
+        .map(|f| Bson::try_from(f).ok())
+        .collect()
+}
+
+/// 表达式中允许的关键字
+const EXPRESSION_KEYWORDS: &[&str] = &[
+    "and", "or", "not", "null", "is", "in", "between", "like", "distinct", "as", "case", "when",
+    "then", "else", "end", "true", "false",
+];
+
+/// 表达式中不允许的关键字，不能作为列名
+const EXPRESSION_FORBIDDEN: &[&str] = &[
+    "select", "union", "from", "where", "join", "into", "insert", "update", "delete", "drop",
+    "alter", "create", "truncate", "exec", "execute", "grant",
+];
+
+/// 表达式中允许的函数
+const EXPRESSION_FUNCTIONS: &[&str] = &[
+    "count",
+    "sum",
+    "avg",
+    "min",
+    "max",
+    "coalesce",
+    "ifnull",
+    "isnull",
+    "nullif",
+    "lower",
+    "upper",
+    "length",
+    "len",
+    "abs",
+    "round",
+    "floor",
+    "ceil",
+    "ceiling",
+    "concat",
+    "substring",
+    "date",
+    "year",
+    "month",
+    "day",
+];
+
+/// 表名，可以带有别名，如 user、db.user u、user as u
+fn is_valid_table(table_name: &str) -> bool {
+    let parts = table_name.split_whitespace().collect::<Vec<&str>>();
+    let names = match parts.as_slice() {
+        [table] => vec![*table],
+        [table, alias] => vec![*table, *alias],
+        [table, r#as, alias] if r#as.eq_ignore_ascii_case("as") => vec![*table, *alias],
+        _ => return false,
+    };
+    names.iter().all(|f| parse_identifier(f).is_some())
+}
+
+/// join的条件，只允许用and连接的 列 = 列，如 d.id = u.dept_id and d.tenant_id = u.tenant_id
+fn is_valid_join_on(on: &str) -> bool {
+    let chars = on.chars().collect::<Vec<char>>();
+    let text = |s: usize, e: usize| chars[s..e].iter().collect::<String>();
+    let mut pairs = vec![];
+    let mut start = 0;
+    for (token, s, e) in tokenize_with_spans(on) {
+        if matches!(&token, SqlToken::Word(w) if w.eq_ignore_ascii_case("and")) {
+            pairs.push(text(start, s));
+            start = e;
+        }
+    }
+    pairs.push(text(start, chars.len()));
+    pairs.iter().all(|pair| match pair.split_once('=') {
+        Some((left, right)) => {
+            parse_identifier(left.trim()).is_some() && parse_identifier(right.trim()).is_some()
+        }
+        None => false,
+    })
+}
+
+lazy_static! {
+    static ref TABLE_COLUMNS: RwLock<HashMap<String, Vec<String>>> = RwLock::new(HashMap::new());
+}
+
+fn table_key(table_name: &str) -> Option<String> {
+    let table = table_name.split_whitespace().next()?;
+    parse_identifier(table).and_then(|f| f.last().map(|t| t.to_lowercase()))
+}
+
+/// 注册表允许使用的列，使用这个表的DynamicWrapper只能使用这些列
+/// for example:
+///     register_table_columns("user", &["id", "name", "age"]);
+pub fn register_table_columns(table_name: &str, columns: &[&str]) {
+    if let Some(key) = table_key(table_name) {
+        TABLE_COLUMNS
+            .write()
+            .unwrap()
+            .insert(key, columns.iter().map(|f| f.to_string()).collect());
+    }
+}
+
+/// 表允许使用的列，没有注册时返回None
+pub fn get_table_columns(table_name: &str) -> Option<Vec<String>> {
+    table_key(table_name).and_then(|key| TABLE_COLUMNS.read().unwrap().get(&key).cloned())
 }
 
 macro_rules! push_sql {
@@ -60,6 +230,10 @@ impl Debug for DynamicWrapper {
             .field("sql", &self.sql)
             .field("args", &self.args)
             .field("dml", &self.dml)
+            .field("head", &self.head)
+            .field("page_limit", &self.page_limit)
+            .field("page_offset", &self.page_offset)
+            .field("errors", &self.errors)
             //.field("formats", &formats)
             .finish()
     }
@@ -73,6 +247,182 @@ impl DynamicWrapper {
             sql: String::with_capacity(200),
             args: Vec::with_capacity(5),
             formats: Default::default(),
+            head: String::new(),
+            head_args: 0,
+            page_limit: None,
+            page_offset: None,
+            quote_columns: false,
+            allowed_columns: None,
+            errors: vec![],
+            keyset_keys: vec![],
+            keyset_size: 0,
+            version_column: None,
+        }
+    }
+
+    /// 为true时按数据库引用列名
+    pub fn quote_columns(mut self, quote: bool) -> Self {
+        self.quote_columns = quote;
+        self
+    }
+
+    /// 只允许使用这些列，使用其他列时执行返回错误
+    pub fn allow_columns(mut self, columns: &[&str]) -> Self {
+        self.allowed_columns = Some(columns.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    /// 使用表注册的允许的列，表名无效时记录错误
+    fn use_table(&mut self, table_name: &str) {
+        if !is_valid_table(table_name) {
+            self.errors.push((
+                10111,
+                format!("The table {} is not a valid identifier", table_name),
+            ));
+        }
+        if self.allowed_columns.is_none() {
+            self.allowed_columns = get_table_columns(table_name);
+        }
+    }
+
+    /// 检查列名，无效或不允许时记录错误并返回None
+    pub(crate) fn column_name(&mut self, column: &str) -> Option<String> {
+        let parts = match parse_identifier(column) {
+            Some(parts) => parts,
+            None => {
+                self.errors.push((
+                    10111,
+                    format!("The column {} is not a valid identifier", column),
+                ));
+                return None;
+            }
+        };
+        if let Some(allowed) = self.allowed_columns.as_ref() {
+            let name = parts.last().cloned().unwrap_or_default();
+            if !allowed.iter().any(|f| f.eq_ignore_ascii_case(&name)) {
+                self.errors
+                    .push((10112, format!("The column {} is not allowed", column)));
+                return None;
+            }
+        }
+        match self.dialect() {
+            Some(dialect) if self.quote_columns => dialect.quote_qualified_identifier(column),
+            _ => Some(column.to_string()),
+        }
+    }
+
+    /// 检查select的列或having等表达式，只允许列名、常量、运算符和常用的函数
+    /// 无效或使用了不允许的列时记录错误并返回false
+    fn check_expression(&mut self, expr: &str) -> bool {
+        let tokens = tokenize_with_spans(expr)
+            .into_iter()
+            .map(|(token, _, _)| token)
+            .filter(|f| !matches!(f, SqlToken::Space(_)))
+            .collect::<Vec<SqlToken>>();
+        let mut alias = false;
+        let mut depth = 0;
+        let mut i = 0;
+        while i < tokens.len() {
+            let mut name = String::new();
+            let mut words = 0;
+            while let Some(token) = tokens.get(i) {
+                match token {
+                    SqlToken::Word(w) | SqlToken::Backtick(w)
+                        if name.is_empty() || name.ends_with('.') =>
+                    {
+                        if matches!(token, SqlToken::Backtick(_)) {
+                            name.push_str(&format!("`{}`", w));
+                        } else {
+                            name.push_str(w);
+                        }
+                    }
+                    SqlToken::Raw(w)
+                        if w.starts_with('"') && (name.is_empty() || name.ends_with('.')) =>
+                    {
+                        name.push_str(w)
+                    }
+                    SqlToken::Symbol(f) if f == "." && !name.is_empty() && !name.ends_with('.') => {
+                        name.push('.')
+                    }
+                    SqlToken::Symbol(f) if f == "*" && name.ends_with('.') => name.push('*'),
+                    _ => break,
+                }
+                words += 1;
+                i += 1;
+            }
+            if words == 0 {
+                let valid = match &tokens[i] {
+                    SqlToken::Number(_) => true,
+                    SqlToken::Raw(raw) => raw.starts_with('\''),
+                    SqlToken::Symbol(f) if f == "(" => {
+                        depth += 1;
+                        true
+                    }
+                    SqlToken::Symbol(f) if f == ")" => {
+                        depth -= 1;
+                        depth >= 0
+                    }
+                    SqlToken::Symbol(f) => f != ";",
+                    _ => false,
+                };
+                if !valid {
+                    break;
+                }
+                alias = false;
+                i += 1;
+                continue;
+            }
+            let lower = name.to_lowercase();
+            if EXPRESSION_FORBIDDEN.contains(&lower.as_str()) {
+                break;
+            }
+            if words == 1 && matches!(tokens.get(i), Some(SqlToken::Symbol(f)) if f == "(") {
+                if !EXPRESSION_FUNCTIONS.contains(&lower.as_str()) {
+                    break;
+                }
+            } else if words == 1 && EXPRESSION_KEYWORDS.contains(&lower.as_str()) {
+                alias = lower == "as";
+                continue;
+            } else if !alias && !name.ends_with(".*") && self.column_name(&name).is_none() {
+                return false;
+            }
+            alias = false;
+        }
+        if i < tokens.len() || depth != 0 {
+            self.errors
+                .push((10111, format!("The expression {} is not allowed", expr)));
+            return false;
+        }
+        true
+    }
+
+    /// 条件无效时使用的恒假条件，避免去掉条件后扩大结果
+    fn always_false(mut self) -> Self {
+        self = self.and();
+        self.sql.push_str("1 = 0");
+        self
+    }
+
+    fn column_names(&mut self, columns: &[&str]) -> Option<Vec<String>> {
+        let names = columns
+            .iter()
+            .map(|f| self.column_name(f))
+            .collect::<Vec<Option<String>>>();
+        names.into_iter().collect()
+    }
+
+    /// 构造时的错误，如无效或不允许的列名
+    pub fn check(&self) -> ChimesResult<()> {
+        match self.errors.first() {
+            Some((code, _)) => Err(ChimesError::custom(
+                *code,
+                self.errors
+                    .iter()
+                    .map(|f| f.1.clone())
+                    .collect::<Vec<String>>()
+                    .join("; "),
+            )),
+            None => Ok(()),
         }
     }
 
@@ -100,29 +450,26 @@ impl DynamicWrapper {
     /// println!("sql:{:?}", w2.sql.as_str());  // sql:"b = ? and (a = ?)"
     /// println!("arg:{:?}", w2.args.clone()); // arg:[String("2"), String("1")]
     ///
-    pub fn push_wrapper(self, arg: DynamicWrapper) -> Self {
-        self.push(&arg.sql, arg.args)
+    pub fn push_wrapper(mut self, arg: DynamicWrapper) -> Self {
+        if !arg.errors.is_empty() {
+            // 有错误的wrapper不生成语句，使用恒假条件
+            self.errors.extend(arg.errors);
+            self.sql.push_str("1 = 0");
+            return self;
+        }
+        let sql = arg.to_sql();
+        self.push(&sql, arg.args)
     }
 
     /// push sql,args into self
+    /// sql中的占位符从1开始编号，按self中已有的参数个数后移
     pub fn push(mut self, sql: &str, args: Vec<rbson::Bson>) -> Self {
-        let mut new_sql = sql.to_string();
-        match self.driver_type {
-            DriverType::None => {}
-            DriverType::Mysql => {}
-            DriverType::Postgres | DriverType::Mssql => {
-                for index_target in 0..args.len() {
-                    let mut convert_column = String::new();
-                    self.driver_type
-                        .stmt_convert(index_target, &mut convert_column);
-                    let mut convert_column_new = String::new();
-                    self.driver_type
-                        .stmt_convert(index_target + self.args.len(), &mut convert_column_new);
-                    new_sql = new_sql.replace(convert_column.as_str(), convert_column_new.as_str());
-                }
+        let new_sql = match self.dialect() {
+            Some(dialect) if !self.args.is_empty() => {
+                shift_placeholders(sql, dialect, 1, self.args.len() as isize)
             }
-            DriverType::Sqlite => {}
-        }
+            _ => sql.to_string(),
+        };
         self.sql.push_str(new_sql.as_str());
         for x in args {
             self.args.push(x);
@@ -287,7 +634,11 @@ impl DynamicWrapper {
         self
     }
 
+    /// having，只允许列名、常量、运算符和常用的函数，无效时记录错误
     pub fn having(mut self, sql_having: &str) -> Self {
+        if !self.check_expression(sql_having) {
+            return self;
+        }
         self = self.and();
         push_sql!(
             self.sql,
@@ -331,7 +682,11 @@ impl DynamicWrapper {
 
     ///format column
     pub fn do_format_column(&self, column: &str, data: &mut String) {
-        if let Some(source) = self.formats.get(column) {
+        let source = self
+            .formats
+            .get(column)
+            .or_else(|| parse_identifier(column).and_then(|f| self.formats.get(&f.join("."))));
+        if let Some(source) = source {
             *data = source.replace("{}", data);
         }
     }
@@ -343,6 +698,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -358,6 +717,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -373,6 +736,10 @@ impl DynamicWrapper {
         if len == 0 {
             return self;
         }
+        let columns = match self.column_names(columns) {
+            Some(names) => names,
+            None => return self,
+        };
         let mut index = 0;
         self.sql = self
             .sql
@@ -383,7 +750,7 @@ impl DynamicWrapper {
             .to_string();
         self.sql
             .push_str(rbatis::sql::TEMPLATE.order_by.left_right_space);
-        for x in columns {
+        for x in columns.iter() {
             if is_asc {
                 push_sql!(self.sql, x, " ", rbatis::sql::TEMPLATE.asc.value,);
             } else {
@@ -403,6 +770,14 @@ impl DynamicWrapper {
         if len == 0 {
             return self;
         }
+        let names = column_asc.iter().map(|f| f.0).collect::<Vec<&str>>();
+        let column_asc = match self.column_names(&names) {
+            Some(names) => names
+                .into_iter()
+                .zip(column_asc.iter().map(|f| f.1))
+                .collect::<Vec<(String, bool)>>(),
+            None => return self,
+        };
         let mut index = 0;
         self.sql = self
             .sql
@@ -413,7 +788,7 @@ impl DynamicWrapper {
             .to_string();
         self.sql
             .push_str(rbatis::sql::TEMPLATE.order_by.left_right_space);
-        for (x, is_asc) in column_asc {
+        for (x, is_asc) in column_asc.iter() {
             if *is_asc {
                 push_sql!(self.sql, x, " ", rbatis::sql::TEMPLATE.asc.value,);
             } else {
@@ -433,6 +808,10 @@ impl DynamicWrapper {
         if len == 0 {
             return self;
         }
+        let columns = match self.column_names(columns) {
+            Some(names) => names,
+            None => return self,
+        };
         let mut index = 0;
         self.sql = self
             .sql
@@ -443,7 +822,7 @@ impl DynamicWrapper {
             .to_string();
         self.sql
             .push_str(rbatis::sql::TEMPLATE.group_by.left_right_space);
-        for x in columns {
+        for x in columns.iter() {
             self.sql.push_str(x);
             if (index + 1) != len {
                 self.sql.push(',');
@@ -459,6 +838,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -473,6 +856,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -488,6 +875,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -503,6 +894,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let mut convert_column = String::new();
         self.driver_type
@@ -517,6 +912,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
 
         let mut convert_column = String::new();
@@ -554,6 +953,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
 
         let mut convert_column = String::new();
@@ -593,6 +996,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let v = rbatis::as_bson!(&obj);
         let mut v_str = String::new();
@@ -623,6 +1030,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let v = rbatis::as_bson!(&obj);
         let mut v_str = String::new();
@@ -653,6 +1064,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let v = rbatis::as_bson!(&obj);
         let mut v_str = String::new();
@@ -679,10 +1094,66 @@ impl DynamicWrapper {
         self
     }
 
+    /// 包含匹配，值为 %v%
+    /// for example:
+    ///  like_contains("name", "a") " name like ? " 参数为 %a%
+    pub fn like_contains<T>(self, column: &str, obj: T) -> Self
+    where
+        T: Serialize,
+    {
+        let v = rbatis::as_bson!(&obj);
+        let v = v
+            .as_str()
+            .map(|f| f.to_string())
+            .unwrap_or_else(|| v.to_string());
+        self.like_pattern(column, format!("%{}%", v))
+    }
+
+    /// 后缀匹配，值为 %v
+    /// for example:
+    ///  like_ends_with("name", "a") " name like ? " 参数为 %a
+    pub fn like_ends_with<T>(self, column: &str, obj: T) -> Self
+    where
+        T: Serialize,
+    {
+        let v = rbatis::as_bson!(&obj);
+        let v = v
+            .as_str()
+            .map(|f| f.to_string())
+            .unwrap_or_else(|| v.to_string());
+        self.like_pattern(column, format!("%{}", v))
+    }
+
+    fn like_pattern(mut self, column: &str, pattern: String) -> Self {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
+        self = self.and();
+        let mut convert_column = String::new();
+        self.driver_type
+            .stmt_convert(self.args.len(), &mut convert_column);
+        self.do_format_column(column, &mut convert_column);
+        push_sql!(
+            self.sql,
+            column,
+            " ",
+            rbatis::sql::TEMPLATE.like.value,
+            " ",
+            &convert_column.as_str(),
+        );
+        self.args.push(Bson::String(pattern));
+        self
+    }
+
     pub fn not_like<T>(mut self, column: &str, obj: T) -> Self
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         let v = rbatis::as_bson!(&obj);
         let mut v_str = String::new();
@@ -710,6 +1181,10 @@ impl DynamicWrapper {
     }
 
     pub fn is_null(mut self, column: &str) -> Self {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         self.sql.push_str(column);
         self.sql.push_str(rbatis::sql::TEMPLATE.is.left_space);
@@ -718,6 +1193,10 @@ impl DynamicWrapper {
     }
 
     pub fn is_not_null(mut self, column: &str) -> Self {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         self = self.and();
         self.sql.push_str(column);
         self.sql.push_str(rbatis::sql::TEMPLATE.is.left_space);
@@ -731,6 +1210,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         if obj.is_empty() {
             return self;
         }
@@ -776,6 +1259,10 @@ impl DynamicWrapper {
     where
         T: Serialize,
     {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
         if obj.is_empty() {
             return self;
         }
@@ -873,4 +1360,1189 @@ impl DynamicWrapper {
         self.sql.push(' ');
         self
     }
+
+    /// 限制行数，在to_sql中按数据库生成，不写入sql
+    /// for example:
+    ///  limit_rows(1) " limit 1 " / MSSQL " offset 0 rows fetch next 1 rows only "
+    pub fn limit_rows(mut self, limit: u64) -> Self {
+        self.page_limit = Some(limit);
+        self
+    }
+
+    /// offset，在to_sql中按数据库生成，不写入sql
+    /// for example:
+    ///  limit_rows(10).offset(20) " limit 10 offset 20 " / MSSQL " offset 20 rows fetch next 10 rows only "
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.page_offset = Some(offset);
+        self
+    }
+
+    /// 分页，page_no从1开始，在to_sql中按数据库生成
+    /// for example:
+    ///  page(3, 10) " limit 10 offset 20 "
+    pub fn page(self, page_no: u64, size: u64) -> Self {
+        let offset = page_no.max(1).saturating_sub(1).saturating_mul(size);
+        self.limit_rows(size).offset(offset)
+    }
+
+    /// 分页的SQL，MSSQL的OFFSET FETCH需要ORDER BY，没有时使用ORDER BY (SELECT NULL)
+    fn pagination_sql(&self, statement: &str) -> String {
+        if self.page_limit.is_none() && self.page_offset.is_none() {
+            return String::new();
+        }
+        let mut sql = String::new();
+        match self.dialect() {
+            Some(SqlDialect::MsSql) => {
+                if split_top_level_keywords(statement, &["order", "by"]).is_none() {
+                    push_sql!(
+                        sql,
+                        " ",
+                        rbatis::sql::TEMPLATE.order_by.value,
+                        " (select null)",
+                    );
+                }
+                sql.push_str(&format!(
+                    " {} {} rows",
+                    rbatis::sql::TEMPLATE.offset.value,
+                    self.page_offset.unwrap_or_default()
+                ));
+                if let Some(limit) = self.page_limit {
+                    sql.push_str(&format!(
+                        " fetch next {} {}",
+                        limit,
+                        rbatis::sql::TEMPLATE.rows_only.value
+                    ));
+                }
+            }
+            dialect => {
+                match (self.page_limit, dialect) {
+                    (Some(limit), _) => {
+                        sql.push_str(&format!(" {} {}", rbatis::sql::TEMPLATE.limit.value, limit))
+                    }
+                    // MySQL和SQLite的OFFSET必须跟在LIMIT之后
+                    (None, Some(SqlDialect::Sqlite)) => {
+                        sql.push_str(&format!(" {} -1", rbatis::sql::TEMPLATE.limit.value))
+                    }
+                    (None, Some(SqlDialect::Postgres)) => {}
+                    (None, _) => sql.push_str(&format!(
+                        " {} {}",
+                        rbatis::sql::TEMPLATE.limit.value,
+                        u64::MAX
+                    )),
+                }
+                if let Some(offset) = self.page_offset {
+                    sql.push_str(&format!(
+                        " {} {}",
+                        rbatis::sql::TEMPLATE.offset.value,
+                        offset
+                    ));
+                }
+            }
+        }
+        sql
+    }
+
+    fn dialect(&self) -> Option<SqlDialect> {
+        SqlDialect::from_driver_type(&self.driver_type)
+    }
+
+    /// 清除上一个语句的head和它的参数
+    fn reset_head(&mut self) {
+        if self.head_args > 0 {
+            self.args.drain(0..self.head_args);
+            if let Some(dialect) = self.dialect() {
+                self.sql = shift_placeholders(
+                    &self.sql,
+                    dialect,
+                    self.head_args + 1,
+                    -(self.head_args as isize),
+                );
+            }
+        }
+        self.head.clear();
+        self.head_args = 0;
+    }
+
+    /// 为head中的值增加参数，返回占位符
+    /// 参数插入到条件的参数之前，条件中已有的占位符依次后移
+    fn head_value(&mut self, column: &str, value: Bson) -> String {
+        let index = self.head_args;
+        let mut convert_column = String::new();
+        self.driver_type.stmt_convert(index, &mut convert_column);
+        self.do_format_column(column, &mut convert_column);
+        if index < self.args.len() {
+            if let Some(dialect) = self.dialect() {
+                self.sql = shift_placeholders(&self.sql, dialect, index + 1, 1);
+            }
+        }
+        self.args.insert(index, value);
+        self.head_args += 1;
+        convert_column
+    }
+
+    fn insert_rows(&mut self, values: Vec<Bson>) -> Option<(Vec<String>, Vec<String>)> {
+        let rows = values
+            .into_iter()
+            .filter_map(|f| match f {
+                Bson::Document(doc) if !doc.is_empty() => Some(doc),
+                _ => None,
+            })
+            .collect::<Vec<rbson::Document>>();
+        if rows.is_empty() {
+            log::warn!("The values to insert should be objects or maps.");
+            return None;
+        }
+        let mut keys: Vec<String> = vec![];
+        for row in rows.iter() {
+            for (key, value) in row.iter() {
+                if *value != Bson::Null && !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        if keys.is_empty() {
+            log::warn!("The values to insert have no non-null fields.");
+            return None;
+        }
+        let columns = self.column_names(&keys.iter().map(|f| f.as_str()).collect::<Vec<&str>>())?;
+        let mut groups = vec![];
+        for row in rows {
+            let mut holders = vec![];
+            for (key, column) in keys.iter().zip(columns.iter()) {
+                let value = row.get(key).cloned().unwrap_or(Bson::Null);
+                holders.push(self.head_value(column, value));
+            }
+            groups.push(format!("({})", holders.join(", ")));
+        }
+        Some((columns, groups))
+    }
+
+    /// insert
+    /// for example:
+    ///  insert("user", &user) " insert into user (id, name) values (?, ?) "
+    pub fn insert<T>(self, table_name: &str, value: &T) -> Self
+    where
+        T: Serialize,
+    {
+        self.insert_batch(table_name, std::slice::from_ref(value))
+    }
+
+    /// 批量插入，列为所有行中非空字段的并集，为空的字段不插入，使用数据库的默认值
+    /// 其他行缺少或为空的列为NULL
+    /// for example:
+    ///  insert_batch("user", &[a, b]) " insert into user (id, name) values (?, ?), (?, ?) "
+    pub fn insert_batch<T>(mut self, table_name: &str, values: &[T]) -> Self
+    where
+        T: Serialize,
+    {
+        self.reset_head();
+        self.use_table(table_name);
+        let values = values
+            .iter()
+            .map(|f| rbatis::as_bson!(f))
+            .collect::<Vec<Bson>>();
+        if let Some((columns, groups)) = self.insert_rows(values) {
+            self.dml = "insert".to_string();
+            self.head = format!(
+                "{} {} ({}) {} {}",
+                rbatis::sql::TEMPLATE.insert_into.value,
+                table_name,
+                columns.join(", "),
+                rbatis::sql::TEMPLATE.values.value,
+                groups.join(", ")
+            );
+        }
+        self
+    }
+
+    /// 插入，按keys冲突时更新其他列
+    /// MySQL使用ON DUPLICATE KEY UPDATE，Postgres/SQLite使用ON CONFLICT，MSSQL使用MERGE
+    /// keys不能为空，为空时执行返回错误
+    /// for example:
+    ///  upsert("user", &user, &["id"])
+    pub fn upsert<T>(mut self, table_name: &str, value: &T, keys: &[&str]) -> Self
+    where
+        T: Serialize,
+    {
+        self.reset_head();
+        self.use_table(table_name);
+        if keys.is_empty() {
+            self.errors.push((
+                10121,
+                format!("The upsert of {} has no conflict keys", table_name),
+            ));
+            return self;
+        }
+        let keys = match self.column_names(keys) {
+            Some(keys) => keys,
+            None => return self,
+        };
+        let (columns, groups) = match self.insert_rows(vec![rbatis::as_bson!(value)]) {
+            Some(rows) => rows,
+            None => return self,
+        };
+        let updates = columns
+            .iter()
+            .filter(|f| !keys.contains(f))
+            .collect::<Vec<&String>>();
+        let insert = format!(
+            "{} {} ({}) {} {}",
+            rbatis::sql::TEMPLATE.insert_into.value,
+            table_name,
+            columns.join(", "),
+            rbatis::sql::TEMPLATE.values.value,
+            groups.join(", ")
+        );
+        self.dml = "upsert".to_string();
+        self.head = match self.dialect().unwrap_or(SqlDialect::MySql) {
+            SqlDialect::MySql => {
+                let sets = if updates.is_empty() {
+                    let key = keys.first().unwrap_or(&columns[0]);
+                    format!("{} = {}", key, key)
+                } else {
+                    updates
+                        .iter()
+                        .map(|f| format!("{} = VALUES({})", f, f))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                };
+                format!("{} ON DUPLICATE KEY UPDATE {}", insert, sets)
+            }
+            SqlDialect::Postgres | SqlDialect::Sqlite => {
+                if updates.is_empty() {
+                    format!("{} ON CONFLICT ({}) DO NOTHING", insert, keys.join(", "))
+                } else {
+                    let sets = updates
+                        .iter()
+                        .map(|f| format!("{} = EXCLUDED.{}", f, f))
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    format!(
+                        "{} ON CONFLICT ({}) DO UPDATE SET {}",
+                        insert,
+                        keys.join(", "),
+                        sets
+                    )
+                }
+            }
+            SqlDialect::MsSql => {
+                let on = keys
+                    .iter()
+                    .map(|f| format!("target.{} = source.{}", f, f))
+                    .collect::<Vec<String>>()
+                    .join(" AND ");
+                let matched = if updates.is_empty() {
+                    String::new()
+                } else {
+                    format!(
+                        " WHEN MATCHED THEN UPDATE SET {}",
+                        updates
+                            .iter()
+                            .map(|f| format!("{} = source.{}", f, f))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                };
+                format!(
+                    "MERGE INTO {} AS target USING (VALUES {}) AS source ({}) ON {}{} \
+                     WHEN NOT MATCHED THEN INSERT ({}) VALUES ({});",
+                    table_name,
+                    groups.join(", "),
+                    columns.join(", "),
+                    on,
+                    matched,
+                    columns.join(", "),
+                    columns
+                        .iter()
+                        .map(|f| format!("source.{}", f))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            }
+        };
+        self
+    }
+
+    /// select，之后使用join、eq等设置关联和条件
+    /// 列可以是列名、表.*或只包含列名、常量和常用函数的表达式，无效时记录错误
+    /// for example:
+    ///  select("user", &["id", "name"]).eq("id", 1) " select id, name from user where id = ? "
+    pub fn select(mut self, table_name: &str, columns: &[&str]) -> Self {
+        self.reset_head();
+        self.use_table(table_name);
+        self.dml = "select".to_string();
+        let mut names = vec![];
+        for column in columns {
+            if *column == "*" || column.ends_with(".*") {
+                names.push(column.to_string());
+            } else if parse_identifier(column).is_some() {
+                match self.column_name(column) {
+                    Some(name) => names.push(name),
+                    None => return self,
+                }
+            } else if self.check_expression(column) {
+                names.push(column.to_string());
+            } else {
+                return self;
+            }
+        }
+        let columns = if names.is_empty() {
+            "*".to_string()
+        } else {
+            names.join(", ")
+        };
+        self.head = format!(
+            "{} {} {} {}",
+            rbatis::sql::TEMPLATE.select.value,
+            columns,
+            rbatis::sql::TEMPLATE.from.value,
+            table_name
+        );
+        self
+    }
+
+    /// inner join
+    /// for example:
+    ///  select("user u", &["u.id"]).join("dept d", "d.id = u.dept_id") " ... join dept d on d.id = u.dept_id "
+    /// ON只能是用and连接的 列 = 列，表名或条件无效时记录错误
+    pub fn join(self, table_name: &str, on: &str) -> Self {
+        self.push_join(" join ", table_name, on)
+    }
+
+    /// left join
+    pub fn left_join(self, table_name: &str, on: &str) -> Self {
+        self.push_join(" left join ", table_name, on)
+    }
+
+    fn push_join(mut self, keyword: &str, table_name: &str, on: &str) -> Self {
+        if !is_valid_table(table_name) {
+            self.errors.push((
+                10111,
+                format!("The table {} is not a valid identifier", table_name),
+            ));
+            return self;
+        }
+        if !is_valid_join_on(on) {
+            self.errors
+                .push((10111, format!("The join condition {} is not allowed", on)));
+            return self;
+        }
+        push_sql!(self.head, keyword, table_name, " on ", on,);
+        self
+    }
+
+    fn push_nested(mut self, prefix: &str, wrapper: DynamicWrapper) -> Self {
+        self.sql.push_str(prefix);
+        self = self.push_wrapper(wrapper);
+        self.sql.push(')');
+        self
+    }
+
+    /// 新的条件，用于子查询和分组
+    fn child(&self) -> Self {
+        let mut child = Self::new(&self.driver_type).set_formats(self.formats.clone());
+        child.quote_columns = self.quote_columns;
+        child.allowed_columns = self.allowed_columns.clone();
+        child
+    }
+
+    /// in子查询
+    /// for example:
+    ///  in_subquery("id", DynamicWrapper::new(&d).select("orders", &["user_id"]).gt("amount", 100))
+    ///  " id in (select user_id from orders where amount > ?) "
+    pub fn in_subquery(mut self, column: &str, subquery: DynamicWrapper) -> Self {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
+        self = self.and();
+        let prefix = format!("{} {} (", column, rbatis::sql::TEMPLATE.r#in.value);
+        self.push_nested(&prefix, subquery)
+    }
+
+    /// exists子查询
+    /// for example:
+    ///  exists(DynamicWrapper::new(&d).select("orders o", &["1"]).push_sql("o.user_id = u.id"))
+    pub fn exists(mut self, subquery: DynamicWrapper) -> Self {
+        self = self.and();
+        self.push_nested("exists (", subquery)
+    }
+
+    /// 加括号的一组条件，和前面的条件使用and连接
+    /// for example:
+    ///  eq("a", 1).and_group(|w| w.eq("b", 2).or().eq("c", 3)) " a = ? and (b = ? or c = ?) "
+    pub fn and_group<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Self) -> Self,
+    {
+        let group = f(self.child());
+        if group.sql.trim().is_empty() {
+            return self;
+        }
+        self = self.and();
+        self.push_nested("(", group)
+    }
+
+    /// 加括号的一组条件，和前面的条件使用or连接
+    pub fn or_group<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Self) -> Self,
+    {
+        let group = f(self.child());
+        if group.sql.trim().is_empty() {
+            return self;
+        }
+        self = self.or();
+        self.push_nested("(", group)
+    }
+
+    fn push_value(&mut self, column: &str, value: Bson) -> String {
+        let mut convert_column = String::new();
+        self.driver_type
+            .stmt_convert(self.args.len(), &mut convert_column);
+        self.do_format_column(column, &mut convert_column);
+        self.args.push(value);
+        convert_column
+    }
+
+    /// 键集（游标）分页，keys为排序的列和是否升序，最后一列应该唯一，列的值不能为NULL
+    /// cursor为上一页返回的next_cursor，第一页为None
+    /// 方向相同时使用 (a, b) > (?, ?)，MSSQL和方向不同时使用展开的形式
+    /// for example:
+    ///  select("audit", &["*"]).keyset(&[("created", false), ("id", false)], cursor, 50)
+    ///  " select * from audit where (created, id) < (?, ?) order by created desc,id desc limit 51 "
+    pub fn keyset(mut self, keys: &[(&str, bool)], cursor: Option<&str>, size: u64) -> Self {
+        if keys.is_empty() {
+            return self;
+        }
+        let names = keys.iter().map(|f| f.0).collect::<Vec<&str>>();
+        let columns = match self.column_names(&names) {
+            Some(columns) => columns,
+            None => return self,
+        };
+        self.keyset_keys = names
+            .iter()
+            .map(|f| {
+                parse_identifier(f)
+                    .and_then(|p| p.last().cloned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        self.keyset_size = size;
+
+        if let Some(cursor) = cursor.filter(|f| !f.trim().is_empty()) {
+            let values = match decode_keyset_cursor(cursor) {
+                Some(values) if values.len() == columns.len() => values,
+                _ => {
+                    self.errors
+                        .push((10113, format!("The cursor {} is invalid", cursor)));
+                    return self;
+                }
+            };
+            let op = |asc: bool| if asc { " > " } else { " < " };
+            let same_direction = keys.iter().all(|f| f.1 == keys[0].1);
+            self = self.and();
+            if columns.len() == 1 {
+                let holder = self.push_value(&columns[0], values[0].clone());
+                push_sql!(self.sql, &columns[0], op(keys[0].1), &holder,);
+            } else if same_direction && self.dialect() != Some(SqlDialect::MsSql) {
+                let holders = columns
+                    .iter()
+                    .zip(values.iter())
+                    .map(|(c, v)| self.push_value(c, v.clone()))
+                    .collect::<Vec<String>>();
+                self.sql.push_str(&format!(
+                    "({}){}({})",
+                    columns.join(", "),
+                    op(keys[0].1),
+                    holders.join(", ")
+                ));
+            } else {
+                // (a > ? or (a = ? and b > ?) or ...)
+                let mut groups = vec![];
+                for i in 0..columns.len() {
+                    let mut parts = vec![];
+                    for j in 0..i {
+                        let holder = self.push_value(&columns[j], values[j].clone());
+                        parts.push(format!("{} = {}", columns[j], holder));
+                    }
+                    let holder = self.push_value(&columns[i], values[i].clone());
+                    parts.push(format!("{}{}{}", columns[i], op(keys[i].1), holder));
+                    groups.push(format!("({})", parts.join(" and ")));
+                }
+                self.sql.push_str(&format!("({})", groups.join(" or ")));
+            }
+        }
+        self = self.order_bys(keys);
+        self.limit_rows(size.saturating_add(1))
+    }
+
+    /// 执行键集分页的查询，多查询一行用于判断是否有下一页
+    /// 有下一页但记录中没有键的值时返回错误
+    pub async fn fetch_keyset<T>(&self, rb: &Rbatis) -> ChimesResult<KeysetPage<T>>
+    where
+        T: DeserializeOwned + Serialize,
+    {
+        let mut records = self.fetch::<Vec<T>>(rb).await?;
+        let size = self.keyset_size as usize;
+        let next_cursor = if !self.keyset_keys.is_empty() && records.len() > size {
+            records.truncate(size);
+            // 有下一页但生成不了游标时返回错误，不能当作最后一页
+            match records
+                .last()
+                .and_then(|f| encode_keyset_cursor(&self.keyset_keys, f))
+            {
+                Some(cursor) => Some(cursor),
+                None => {
+                    return Err(ChimesError::custom(
+                        10113,
+                        format!(
+                            "Could not build the next cursor, the keys {} should be fields of the records",
+                            self.keyset_keys.join(", ")
+                        ),
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        Ok(KeysetPage {
+            records,
+            next_cursor,
+        })
+    }
+
+    /// update，之后使用set设置更新的列，使用eq等设置条件
+    /// for example:
+    ///  update("user").set("name", "a").eq("id", 1) " update user set name = ? where id = ? "
+    pub fn update(mut self, table_name: &str) -> Self {
+        self.reset_head();
+        self.use_table(table_name);
+        self.dml = "update".to_string();
+        self.head = format!(
+            "{} {} {}",
+            rbatis::sql::TEMPLATE.update.value,
+            table_name,
+            rbatis::sql::TEMPLATE.set.right_space
+        );
+        self
+    }
+
+    fn push_set(&mut self, expr: &str) {
+        if !self.head.ends_with(rbatis::sql::TEMPLATE.set.right_space) {
+            self.head.push_str(", ");
+        }
+        self.head.push_str(expr);
+    }
+
+    /// 设置update的列，在条件之后调用时参数的顺序也是正确的
+    pub fn set<T>(mut self, column: &str, obj: T) -> Self
+    where
+        T: Serialize,
+    {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self,
+        };
+        let convert_column = self.head_value(column, rbatis::as_bson!(&obj));
+        self.push_set(&format!("{} = {}", column, convert_column));
+        self
+    }
+
+    /// 使用表达式设置update的列
+    /// for example:
+    ///  set_expr("hits", "hits + 1") " hits = hits + 1 "
+    pub fn set_expr(mut self, column: &str, expr: &str) -> Self {
+        let column = &match self.column_name(column) {
+            Some(name) => name,
+            None => return self,
+        };
+        self.push_set(&format!("{} = {}", column, expr));
+        self
+    }
+
+    /// 乐观锁，更新时加上 version = version + 1 和 version = ? 的条件
+    /// 执行时没有更新任何行说明记录已被修改，返回10116的错误
+    /// for example:
+    ///  update("user").set("name", "a").eq("id", 1).versioned("version", 3)
+    ///  " update user set name = ?, version = version + 1 where id = ? and version = ? "
+    /// 只能在update之后使用，否则记录错误
+    pub fn versioned<T>(mut self, column: &str, version: T) -> Self
+    where
+        T: Serialize,
+    {
+        if self.dml != "update" {
+            self.errors.push((
+                10123,
+                format!("The version column {} can only be used with update", column),
+            ));
+            return self;
+        }
+        let name = match self.column_name(column) {
+            Some(name) => name,
+            None => return self.always_false(),
+        };
+        self.push_set(&format!("{} = {} + 1", name, name));
+        self.version_column = Some(name);
+        self.eq(column, version)
+    }
+
+    fn check_version(&self, rs: DBExecResult, sql: String) -> ChimesResult<DBExecResult> {
+        match self.version_column.as_ref() {
+            Some(column) if rs.rows_affected == 0 => Err(ChimesError::sql(
+                10116,
+                format!(
+                    "The record was modified by others, the {} does not match",
+                    column
+                ),
+                sql,
+                "optimistic lock conflict",
+            )),
+            _ => Ok(rs),
+        }
+    }
+
+    /// delete，之后使用eq等设置条件
+    /// for example:
+    ///  delete_from("user").eq("id", 1) " delete from user where id = ? "
+    pub fn delete_from(mut self, table_name: &str) -> Self {
+        self.reset_head();
+        self.use_table(table_name);
+        self.dml = "delete".to_string();
+        self.head = format!("{} {}", rbatis::sql::TEMPLATE.delete_from.value, table_name);
+        self
+    }
+
+    /// 完整的语句，head和条件之间加上WHERE，最后加上分页
+    /// 有构造时的错误时不生成语句，返回空字符串
+    pub fn to_sql(&self) -> String {
+        if !self.errors.is_empty() {
+            log::warn!(
+                "The wrapper has errors and is not rendered: {:?}",
+                self.errors
+            );
+            return String::new();
+        }
+        let statement = self.statement_sql();
+        let pagination = self.pagination_sql(&statement);
+        if pagination.is_empty() {
+            statement
+        } else {
+            format!("{}{}", statement.trim_end(), pagination)
+        }
+    }
+
+    /// 参数作为常量替换到语句中的SQL，只用于日志和调试，不能用于执行
+    /// for example:
+    ///  eq("name", "a'b").to_debug_sql() " name = 'a''b' "
+    pub fn to_debug_sql(&self) -> String {
+        let dialect = self.dialect().unwrap_or(SqlDialect::MySql);
+        inline_sql_args(&self.to_sql(), dialect, &self.args)
+    }
+
+    /// 执行EXPLAIN，以JSON返回执行计划
+    /// MySQL和SQLite返回计划的各行，Postgres返回FORMAT JSON的计划，MSSQL不支持
+    pub async fn explain(&self, rb: &Rbatis) -> ChimesResult<serde_json::Value> {
+        self.check()?;
+        let sql = match self.dialect() {
+            Some(SqlDialect::Postgres) => format!("EXPLAIN (FORMAT JSON) {}", self.to_sql()),
+            Some(SqlDialect::Sqlite) => format!("EXPLAIN QUERY PLAN {}", self.to_sql()),
+            Some(SqlDialect::MsSql) | None => {
+                return Err(ChimesError::custom(
+                    10114,
+                    format!("EXPLAIN is not supported for {:?}", self.driver_type),
+                ))
+            }
+            Some(SqlDialect::MySql) => format!("EXPLAIN {}", self.to_sql()),
+        };
+        let rows = match rb
+            .fetch::<Vec<serde_json::Value>>(&sql, self.args.clone())
+            .await
+        {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(ChimesError::sql(
+                    10110,
+                    "Explain the wrapper failed",
+                    sql,
+                    err,
+                ))
+            }
+        };
+        // Postgres返回一行QUERY PLAN，内容为JSON
+        if let [row] = rows.as_slice() {
+            if let Some(plan) = row.get("QUERY PLAN") {
+                return Ok(match plan.as_str() {
+                    Some(text) => serde_json::from_str(text).unwrap_or_else(|_| plan.clone()),
+                    None => plan.clone(),
+                });
+            }
+        }
+        Ok(serde_json::Value::Array(rows))
+    }
+
+    /// 同一条件的count查询，去掉ORDER BY、LIMIT和分页
+    /// 有GROUP BY、HAVING或DISTINCT时作为子查询计数
+    /// for example:
+    ///  select("user", &["id"]).eq("state", 1).order_by(true, &["id"]).page(2, 10).count_wrapper()
+    ///  " select count(*) from user where state = ? "
+    pub fn count_wrapper(&self) -> Self {
+        let mut count = self.child();
+        count.dml = "count".to_string();
+        count.errors = self.errors.clone();
+        count.args = self.args.clone();
+        let mut condition = self.sql.trim().to_string();
+        // limit写入sql时也要去掉
+        for keywords in [&["order", "by"][..], &["limit"][..]] {
+            if let Some((before, _)) = split_top_level_keywords(&condition, keywords) {
+                condition = before.trim().to_string();
+            }
+        }
+        if self.head.is_empty() {
+            count.sql = condition;
+            return count;
+        }
+        let grouped = split_top_level_keywords(&condition, &["group", "by"]).is_some()
+            || split_top_level_keywords(&condition, &["having"]).is_some()
+            || split_top_level_keywords(&self.head, &["distinct"]).is_some();
+        if !grouped {
+            if let Some((_, from)) = split_top_level_keywords(&self.head, &["from"]) {
+                count.head = format!("{} count(*) {}", rbatis::sql::TEMPLATE.select.value, from);
+                count.head_args = self.head_args;
+                count.sql = condition;
+                return count;
+            }
+        }
+        let inner = Self {
+            sql: condition,
+            page_limit: None,
+            page_offset: None,
+            ..self.clone()
+        };
+        count.head = format!(
+            "{} count(*) {} ({}) chimes_count",
+            rbatis::sql::TEMPLATE.select.value,
+            rbatis::sql::TEMPLATE.from.value,
+            inner.statement_sql()
+        );
+        count.head_args = self.args.len();
+        count
+    }
+
+    /// head和条件组成的语句，不包括分页
+    fn statement_sql(&self) -> String {
+        let condition = self.sql.trim();
+        if self.head.is_empty() {
+            return self.sql.clone();
+        }
+        if condition.is_empty() {
+            return self.head.trim_end().to_string();
+        }
+        let upper = condition.to_uppercase();
+        if ["ORDER BY", "GROUP BY", "LIMIT", "HAVING", "OFFSET", "WHERE"]
+            .iter()
+            .any(|f| upper.starts_with(f))
+        {
+            format!("{} {}", self.head.trim_end(), condition)
+        } else {
+            format!(
+                "{} {} {}",
+                self.head.trim_end(),
+                rbatis::sql::TEMPLATE.r#where.value,
+                condition
+            )
+        }
+    }
+
+    /// 执行语句，失败时错误中带有执行的语句
+    pub async fn exec(&self, rb: &Rbatis) -> ChimesResult<DBExecResult> {
+        self.check()?;
+        let sql = self.to_sql();
+        match rb.exec(&sql, self.args.clone()).await {
+            Ok(rs) => self.check_version(rs, sql),
+            Err(err) => Err(ChimesError::sql(
+                10110,
+                "Execute the wrapper failed",
+                sql,
+                err,
+            )),
+        }
+    }
+
+    /// 在事务中执行语句
+    pub async fn exec_tx(&self, tx: &ChimesTransaction) -> ChimesResult<DBExecResult> {
+        self.check()?;
+        let sql = self.to_sql();
+        let rs = tx.exec(&sql, self.args.clone()).await?;
+        self.check_version(rs, sql)
+    }
+
+    /// 执行查询
+    pub async fn fetch<T>(&self, rb: &Rbatis) -> ChimesResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        self.check()?;
+        let sql = self.to_sql();
+        match rb.fetch(&sql, self.args.clone()).await {
+            Ok(rs) => Ok(rs),
+            Err(err) => Err(ChimesError::sql(
+                10110,
+                "Fetch the wrapper failed",
+                sql,
+                err,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bsons(values: &[i32]) -> Vec<Bson> {
+        values.iter().map(|f| Bson::Int32(*f)).collect()
+    }
+
+    #[test]
+    fn renumber_nested_placeholders() {
+        for (d, prefix) in [(DriverType::Postgres, "$"), (DriverType::Mssql, "@p")] {
+            // 子查询自己有10个参数，字符串中的 $1 @p1 不是占位符
+            let sub = DynamicWrapper::new(&d)
+                .select("orders o", &["o.user_id"])
+                .gt("o.amount", 100)
+                .push_sql(" and o.note <> '$1 @p1'")
+                .in_array("o.state", &[11, 12, 13, 14, 15, 16, 17, 18, 19]);
+            let w = DynamicWrapper::new(&d)
+                .select("users u", &["u.id"])
+                .eq("u.a", 1)
+                .ne("u.b", 2)
+                .in_subquery("u.id", sub)
+                .and_group(|g| g.eq("u.c", 3).or().between("u.d", 4, 5))
+                .exists(
+                    DynamicWrapper::new(&d)
+                        .select("logs l", &["1"])
+                        .eq("l.kind", 6)
+                        .push_sql(" and l.user_id = u.id"),
+                )
+                .or_group(|g| g.eq("u.e", 7).eq("u.f", 8));
+            let expected = "select u.id from users u where u.a = #1 and u.b <> #2 and u.id in \
+                 (select o.user_id from orders o where o.amount > #3 and o.note <> '$1 @p1' \
+                 and o.state in ( #4 , #5 , #6 , #7 , #8 , #9 , #10 , #11 , #12 )) \
+                 and (u.c = #13 or u.d between #14 and #15) \
+                 and exists (select 1 from logs l where l.kind = #16 and l.user_id = u.id) \
+                 or (u.e = #17 and u.f = #18)";
+            assert_eq!(w.to_sql(), expected.replace('#', prefix));
+            assert_eq!(
+                w.args,
+                bsons(&[1, 2, 100, 11, 12, 13, 14, 15, 16, 17, 18, 19, 3, 4, 5, 6, 7, 8])
+            );
+        }
+    }
+
+    #[test]
+    fn upsert_per_dialect() {
+        let row = serde_json::json!({"id": 1, "name": "a", "age": null});
+        let expects = [
+            (DriverType::Mysql, "insert into users (id, name) values (?, ?) ON DUPLICATE KEY UPDATE name = VALUES(name)"),
+            (DriverType::Postgres, "insert into users (id, name) values ($1, $2) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name"),
+            (DriverType::Sqlite, "insert into users (id, name) values (?, ?) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name"),
+            (DriverType::Mssql, "MERGE INTO users AS target USING (VALUES (@p1, @p2)) AS source (id, name) ON target.id = source.id WHEN MATCHED THEN UPDATE SET name = source.name WHEN NOT MATCHED THEN INSERT (id, name) VALUES (source.id, source.name);"),
+        ];
+        for (driver, sql) in expects {
+            let w = DynamicWrapper::new(&driver).upsert("users", &row, &["id"]);
+            assert_eq!(w.to_sql(), sql);
+            assert_eq!(w.args, vec![Bson::UInt64(1), Bson::String("a".to_string())]);
+        }
+
+        // 只有冲突键时不更新任何列
+        let keys_only = serde_json::json!({"id": 1});
+        let w = DynamicWrapper::new(&DriverType::Mysql).upsert("users", &keys_only, &["id"]);
+        assert_eq!(
+            w.to_sql(),
+            "insert into users (id) values (?) ON DUPLICATE KEY UPDATE id = id"
+        );
+        let w = DynamicWrapper::new(&DriverType::Postgres).upsert("users", &keys_only, &["id"]);
+        assert_eq!(
+            w.to_sql(),
+            "insert into users (id) values ($1) ON CONFLICT (id) DO NOTHING"
+        );
+        let w = DynamicWrapper::new(&DriverType::Mssql).upsert("users", &keys_only, &["id"]);
+        assert_eq!(w.to_sql(), "MERGE INTO users AS target USING (VALUES (@p1)) AS source (id) ON target.id = source.id WHEN NOT MATCHED THEN INSERT (id) VALUES (source.id);");
+
+        let w = DynamicWrapper::new(&DriverType::Mysql).upsert("users", &row, &[]);
+        assert_eq!(w.to_sql(), "");
+        assert_eq!(w.errors.first().map(|f| f.0), Some(10121));
+    }
+
+    #[test]
+    fn pagination_per_dialect() {
+        let expects = [
+            (DriverType::Mysql, "select id from users where state = ? limit 18446744073709551615 offset 5", "select id from users where state = ? order by id desc limit 10 offset 20"),
+            (DriverType::Postgres, "select id from users where state = $1 offset 5", "select id from users where state = $1 order by id desc limit 10 offset 20"),
+            (DriverType::Sqlite, "select id from users where state = ? limit -1 offset 5", "select id from users where state = ? order by id desc limit 10 offset 20"),
+            (DriverType::Mssql, "select id from users where state = @p1 order by (select null) offset 5 rows", "select id from users where state = @p1 order by id desc offset 20 rows fetch next 10 rows only"),
+        ];
+        for (driver, offset_only, paged) in expects {
+            let w = DynamicWrapper::new(&driver)
+                .select("users", &["id"])
+                .eq("state", 1)
+                .offset(5);
+            assert_eq!(w.to_sql(), offset_only);
+            let w = DynamicWrapper::new(&driver)
+                .select("users", &["id"])
+                .eq("state", 1)
+                .order_by(false, &["id"])
+                .page(3, 10);
+            assert_eq!(w.to_sql(), paged);
+            assert_eq!(w.args, bsons(&[1]));
+        }
+        let w = DynamicWrapper::new(&DriverType::Mssql)
+            .select("users", &["id"])
+            .eq("state", 1)
+            .page(3, 10);
+        assert_eq!(w.to_sql(), "select id from users where state = @p1 order by (select null) offset 20 rows fetch next 10 rows only");
+    }
+
+    #[test]
+    fn count_wrapper_strips_paging() {
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .select("users", &["id"])
+            .eq("state", 1)
+            .order_by(true, &["id"])
+            .page(2, 10);
+        let count = w.count_wrapper();
+        assert_eq!(
+            count.to_sql(),
+            "select count(*) from users where state = $1"
+        );
+        assert_eq!(count.args, bsons(&[1]));
+
+        let w = DynamicWrapper::new(&DriverType::Mssql)
+            .select("users", &["dept", "count(*) as total"])
+            .eq("state", 1)
+            .group_by(&["dept"])
+            .order_by(true, &["dept"])
+            .page(2, 10);
+        let count = w.count_wrapper();
+        assert_eq!(count.to_sql(), "select count(*) from (select dept, count(*) as total from users where state = @p1 group by dept) chimes_count");
+        assert_eq!(count.args, bsons(&[1]));
+
+        // 原条件的错误也复制到count中
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .select("users", &["id"])
+            .eq("na me", 1)
+            .page(1, 10);
+        let count = w.count_wrapper();
+        assert_eq!(count.to_sql(), "");
+        assert_eq!(count.errors.first().map(|f| f.0), Some(10111));
+    }
+
+    #[test]
+    fn invalid_identifiers_fail_closed() {
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .select("users", &["id"])
+            .eq("id; drop table users", 1);
+        assert_eq!(w.to_sql(), "");
+        assert_eq!(w.errors.first().map(|f| f.0), Some(10111));
+        assert!(w.check().is_err());
+
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .allow_columns(&["id", "name"])
+            .select("users", &["id", "password"]);
+        assert_eq!(w.to_sql(), "");
+        assert_eq!(w.errors.first().map(|f| f.0), Some(10112));
+
+        let w = DynamicWrapper::new(&DriverType::Mysql).select("users u; drop table x", &["*"]);
+        assert_eq!(w.to_sql(), "");
+
+        // 无效的子条件使外层条件恒假，错误也带到外层
+        let sub = DynamicWrapper::new(&DriverType::Mysql).eq("na me", 1);
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .select("users", &["id"])
+            .eq("state", 1)
+            .or()
+            .push_wrapper(sub);
+        assert_eq!(w.to_sql(), "");
+        assert!(w.sql.contains("1 = 0"));
+
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .select("users u", &["u.id", "d.name"])
+            .join("dept d", "d.id = u.dept_id and d.tenant_id = u.tenant_id")
+            .left_join("role r", "r.id = u.role_id")
+            .eq("u.state", 1);
+        assert_eq!(
+            w.to_sql(),
+            "select u.id, d.name from users u join dept d on d.id = u.dept_id and d.tenant_id = u.tenant_id left join role r on r.id = u.role_id where u.state = $1"
+        );
+        for (table, on) in [
+            ("dept d on 1 = 1 --", "d.id = u.dept_id"),
+            ("dept d", "d.id = u.dept_id or 1 = 1"),
+            ("dept d", "d.id = (select 1)"),
+            ("dept d", "d.id > u.dept_id"),
+        ] {
+            let w = DynamicWrapper::new(&DriverType::Mysql)
+                .select("users u", &["u.id"])
+                .join(table, on);
+            assert_eq!(w.to_sql(), "", "{} on {}", table, on);
+            assert_eq!(w.errors.first().map(|f| f.0), Some(10111));
+        }
+    }
+
+    #[test]
+    fn keyset_predicates_and_cursor() {
+        #[derive(serde_derive::Serialize)]
+        struct Audit {
+            id: i64,
+            created: rbatis::DateTimeNative,
+            name: String,
+        }
+        let row = Audit {
+            id: 42,
+            created: rbatis::DateTimeNative::from_str("2024-03-01T10:20:30").unwrap(),
+            name: "a".to_string(),
+        };
+        // 日期按rbatis的类型保留，绑定时不会作为字符串比较
+        let created = Bson::String("DateTimeNative(2024-03-01T10:20:30)".to_string());
+        let keys = vec!["created".to_string(), "ID".to_string()];
+        let cursor = encode_keyset_cursor(&keys, &row).unwrap();
+        assert_eq!(
+            decode_keyset_cursor(&cursor),
+            Some(vec![created.clone(), Bson::Int64(42)])
+        );
+        assert_eq!(encode_keyset_cursor(&["missing".to_string()], &row), None);
+
+        let time = rbson::DateTime::from_millis(1_709_288_430_000);
+        let doc = rbson::doc! {"created": time, "id": 7_i32};
+        let cursor2 =
+            encode_keyset_cursor(&["created".to_string(), "id".to_string()], &doc).unwrap();
+        assert_eq!(
+            decode_keyset_cursor(&cursor2),
+            Some(vec![Bson::DateTime(time), Bson::Int32(7)])
+        );
+
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .select("audit", &["*"])
+            .eq("kind", 1)
+            .keyset(&[("created", false), ("id", false)], Some(&cursor), 50);
+        assert_eq!(
+            w.to_sql(),
+            "select * from audit where kind = $1 and (created, id) < ($2, $3) order by created desc,id desc limit 51"
+        );
+        assert_eq!(
+            w.args,
+            vec![Bson::Int32(1), created.clone(), Bson::Int64(42)]
+        );
+        assert_eq!(w.keyset_keys, vec!["created".to_string(), "id".to_string()]);
+        assert_eq!(w.keyset_size, 50);
+
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .select("audit", &["*"])
+            .keyset(&[("created", false), ("id", true)], Some(&cursor), 50);
+        assert_eq!(
+            w.to_sql(),
+            "select * from audit where ((created < $1) or (created = $2 and id > $3)) order by created desc,id asc limit 51"
+        );
+        assert_eq!(
+            w.args,
+            vec![created.clone(), created.clone(), Bson::Int64(42)]
+        );
+
+        // MSSQL不支持行值比较，方向相同时也展开
+        let w = DynamicWrapper::new(&DriverType::Mssql)
+            .select("audit", &["*"])
+            .eq("kind", 1)
+            .keyset(&[("created", false), ("id", false)], Some(&cursor), 50);
+        assert_eq!(
+            w.to_sql(),
+            "select * from audit where kind = @p1 and ((created < @p2) or (created = @p3 and id < @p4)) order by created desc,id desc offset 0 rows fetch next 51 rows only"
+        );
+        assert_eq!(w.args.len(), 4);
+
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .select("audit", &["*"])
+            .keyset(&[("id", true)], None, 20);
+        assert_eq!(w.to_sql(), "select * from audit order by id asc limit 21");
+
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .select("audit", &["*"])
+            .keyset(&[("created", false), ("id", true)], Some("bad"), 50);
+        assert_eq!(w.to_sql(), "");
+        assert_eq!(w.errors.first().map(|f| f.0), Some(10113));
+    }
+
+    #[test]
+    fn debug_sql_escapes_literals() {
+        let build = |driver: DriverType| {
+            DynamicWrapper::new(&driver)
+                .select("users", &["id"])
+                .eq("name", r"o'neil\x")
+                .eq("note", "'; drop table users; --")
+                .eq("state", 1)
+                .eq("deleted", false)
+                .r#in("kind", &[Bson::Null, Bson::Int64(2)])
+        };
+        let w = build(DriverType::Mysql);
+        assert_eq!(
+            w.to_debug_sql(),
+            r"select id from users where name = 'o''neil\\x' and note = '''; drop table users; --' and state = 1 and deleted = FALSE and kind in ( NULL , 2 )"
+        );
+        // 常量中的占位符不会再被替换
+        let w = build(DriverType::Postgres).eq("code", "$1");
+        assert_eq!(
+            w.to_debug_sql(),
+            r"select id from users where name = 'o''neil\x' and note = '''; drop table users; --' and state = 1 and deleted = FALSE and kind in ( NULL , 2 ) and code = '$1'"
+        );
+        let w = build(DriverType::Mssql);
+        assert_eq!(
+            w.to_debug_sql(),
+            r"select id from users where name = N'o''neil\x' and note = N'''; drop table users; --' and state = 1 and deleted = 0 and kind in ( NULL , 2 )"
+        );
+    }
+
+    #[test]
+    fn versioned_update() {
+        let w = DynamicWrapper::new(&DriverType::Postgres)
+            .update("users")
+            .set("name", "a")
+            .eq("id", 1)
+            .versioned("version", 3);
+        assert_eq!(
+            w.to_sql(),
+            "update users set name = $1, version = version + 1 where id = $2 and version = $3"
+        );
+        assert_eq!(
+            w.args,
+            vec![
+                Bson::String("a".to_string()),
+                Bson::Int32(1),
+                Bson::Int32(3)
+            ]
+        );
+        assert_eq!(w.version_column.as_deref(), Some("version"));
+
+        // 只能用于update
+        for w in [
+            DynamicWrapper::new(&DriverType::Mysql)
+                .eq("id", 1)
+                .versioned("version", 3),
+            DynamicWrapper::new(&DriverType::Mysql)
+                .select("users", &["id"])
+                .versioned("version", 3),
+            DynamicWrapper::new(&DriverType::Mysql)
+                .delete_from("users")
+                .versioned("version", 3),
+        ] {
+            assert_eq!(w.to_sql(), "");
+            assert_eq!(w.errors.first().map(|f| f.0), Some(10123));
+            assert_eq!(w.version_column, None);
+        }
+
+        let w = DynamicWrapper::new(&DriverType::Mysql)
+            .update("users")
+            .set("name", "a")
+            .versioned("ver sion", 3);
+        assert_eq!(w.to_sql(), "");
+        assert_eq!(w.errors.first().map(|f| f.0), Some(10111));
+    }
 }
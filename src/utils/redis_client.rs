@@ -3,6 +3,7 @@ use redis::{
     cluster::ClusterClientBuilder, from_redis_value, ConnectionLike, FromRedisValue, RedisError,
     RedisResult,
 };
+use std::sync::RwLock;
 use std::time::Duration;
 
 use crate::AppConfig;
@@ -101,10 +102,10 @@ impl r2d2::ManageConnection for RedisConnectionManager {
     fn is_valid(&self, conn: &mut RedisConnection) -> Result<(), Self::Error> {
         match conn {
             RedisConnection::Single(sc) => {
-                redis::cmd("PING").query(sc)?;
+                redis::cmd("PING").query::<()>(sc)?;
             }
             RedisConnection::Cluster(cc) => {
-                redis::cmd("PING").query(cc)?;
+                redis::cmd("PING").query::<()>(cc)?;
             }
         }
         Ok(())
@@ -147,28 +148,42 @@ pub fn gen_redis_conn_pool() -> Result<Pool<RedisConnectionManager>, ChimesError
     }
 }
 
-use once_cell::sync::OnceCell;
-
-pub static GLOBAL_REDIS_POOL: OnceCell<Option<r2d2::Pool<RedisConnectionManager>>> =
-    OnceCell::new();
+lazy_static! {
+    pub static ref GLOBAL_REDIS_POOL: RwLock<Option<r2d2::Pool<RedisConnectionManager>>> =
+        RwLock::new(None);
+}
 
 pub fn init_global_redis() {
-    GLOBAL_REDIS_POOL.get_or_init(|| match gen_redis_conn_pool() {
-        Ok(it) => Some(it),
+    let mut pool = GLOBAL_REDIS_POOL.write().unwrap();
+    if pool.is_none() {
+        *pool = match gen_redis_conn_pool() {
+            Ok(it) => Some(it),
+            Err(err) => {
+                log::error!("Error for init the Redis connection: {}", err);
+                None
+            }
+        };
+    }
+}
+
+/// 根据最新的配置重建Redis连接池，配置热加载时调用
+/// 重建失败时保留原来的连接池
+pub fn reload_global_redis() {
+    match gen_redis_conn_pool() {
+        Ok(newpool) => {
+            *GLOBAL_REDIS_POOL.write().unwrap() = Some(newpool);
+        }
         Err(err) => {
-            log::error!("Error for init the Redis connection: {}", err);
-            None
+            log::error!(
+                "Error for reload the Redis connection, keep the old pool: {}",
+                err
+            );
         }
-    });
+    }
 }
 
 pub fn get_redis_connection() -> Option<Pool<RedisConnectionManager>> {
-    let conn: Option<&Option<Pool<RedisConnectionManager>>> = GLOBAL_REDIS_POOL.get();
-    if let Some(conn) = conn {
-        conn.to_owned()
-    } else {
-        None
-    }
+    GLOBAL_REDIS_POOL.read().unwrap().to_owned()
 }
 
 pub fn redis_get(key: &str) -> Result<Option<String>, ChimesError> {
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{ChimesError, ChimesResult, CommonSearch, DynamicWrapper, SearchField, SearchWrapper};
+
+/// 可以查询的字段，name为前端使用的字段名，column为数据库的列名
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SchemaField {
+    pub name: String,
+    pub column: String,
+    pub field_type: String, // integer/float/decimal/boolean/datetime/date/json/string
+    pub operators: Vec<String>, // 允许的操作符，为空时按field_type使用默认的操作符
+    pub sortable: bool,
+}
+
+impl SchemaField {
+    /// 允许的操作符
+    pub fn allowed_operators(&self) -> Vec<String> {
+        if !self.operators.is_empty() {
+            return self.operators.clone();
+        }
+        default_operators(&self.field_type)
+            .iter()
+            .map(|f| f.to_string())
+            .collect()
+    }
+
+    pub fn is_operator_allowed(&self, operator: &str) -> bool {
+        let operator = normalize_operator(operator);
+        self.allowed_operators()
+            .iter()
+            .any(|f| normalize_operator(f) == operator)
+    }
+}
+
+/// 实体的查询定义，限制CommonSearch可以使用的字段、操作符和排序
+/// for example:
+///     register_search_schema(SearchSchema::new("user")
+///         .field("name", "u.user_name", "string", &["eq", "like"])
+///         .field("age", "u.age", "integer", &[])
+///         .default_order("age desc"));
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SearchSchema {
+    pub entity: String,
+    pub fields: Vec<SchemaField>,
+    pub default_order: Option<String>, // order为空时使用，如 "create_time desc"，使用字段名
+}
+
+lazy_static! {
+    static ref SEARCH_SCHEMAS: RwLock<HashMap<String, SearchSchema>> = RwLock::new(HashMap::new());
+}
+
+/// 注册实体的查询定义，相同的entity会被替换
+pub fn register_search_schema(schema: SearchSchema) {
+    SEARCH_SCHEMAS
+        .write()
+        .unwrap()
+        .insert(schema.entity.to_lowercase(), schema);
+}
+
+/// 实体的查询定义，没有注册时返回None
+pub fn get_search_schema(entity: &str) -> Option<SearchSchema> {
+    SEARCH_SCHEMAS
+        .read()
+        .unwrap()
+        .get(&entity.to_lowercase())
+        .cloned()
+}
+
+/// egt和elt是ge和le的别名
+fn normalize_operator(operator: &str) -> String {
+    match operator.trim().to_lowercase().as_str() {
+        "egt" => "ge".to_string(),
+        "elt" => "le".to_string(),
+        t => t.to_string(),
+    }
+}
+
+/// 字段类型默认允许的操作符
+fn default_operators(field_type: &str) -> &'static [&'static str] {
+    match field_type.to_lowercase().as_str() {
+        "integer" | "float" | "decimal" => &[
+            "eq",
+            "ne",
+            "gt",
+            "ge",
+            "lt",
+            "le",
+            "in",
+            "not in",
+            "range",
+            "not range",
+            "null",
+            "not null",
+        ],
+        "date" | "datetime" => &[
+            "eq",
+            "ne",
+            "gt",
+            "ge",
+            "lt",
+            "le",
+            "range",
+            "not range",
+            "between_dates",
+            "null",
+            "not null",
+        ],
+        "boolean" => &["eq", "ne", "null", "not null"],
+        "json" => &["json_contains", "null", "not null"],
+        _ => &[
+            "eq",
+            "ne",
+            "like",
+            "not like",
+            "starts_with",
+            "ends_with",
+            "in",
+            "not in",
+            "null",
+            "not null",
+        ],
+    }
+}
+
+impl SearchSchema {
+    pub fn new(entity: &str) -> Self {
+        Self {
+            entity: entity.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// 添加字段，operators为空时按field_type使用默认的操作符，字段默认可以排序
+    pub fn field(mut self, name: &str, column: &str, field_type: &str, operators: &[&str]) -> Self {
+        self.fields.retain(|f| f.name != name);
+        self.fields.push(SchemaField {
+            name: name.to_string(),
+            column: column.to_string(),
+            field_type: field_type.to_string(),
+            operators: operators.iter().map(|f| f.to_string()).collect(),
+            sortable: true,
+        });
+        self
+    }
+
+    /// 字段不能用于排序
+    pub fn unsortable(mut self, name: &str) -> Self {
+        if let Some(f) = self.fields.iter_mut().find(|f| f.name == name) {
+            f.sortable = false;
+        }
+        self
+    }
+
+    pub fn default_order(mut self, order: &str) -> Self {
+        self.default_order = Some(order.to_string());
+        self
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<&SchemaField> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// 可以排序的列名
+    pub fn sortable_columns(&self) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|f| f.sortable)
+            .map(|f| f.column.as_str())
+            .collect()
+    }
+
+    fn map_field(&self, field: &SearchField) -> ChimesResult<SearchField> {
+        let mut mapped = field.clone();
+        if !field.children.is_empty() {
+            mapped.children = field
+                .children
+                .iter()
+                .map(|f| self.map_field(f))
+                .collect::<ChimesResult<Vec<SearchField>>>()?;
+            return Ok(mapped);
+        }
+        let name = field.field.clone().unwrap_or_default();
+        let schema_field = self.get_field(&name).ok_or_else(|| {
+            ChimesError::custom(
+                10119,
+                format!("The field {} is not searchable in {}", name, self.entity),
+            )
+        })?;
+        let operator = field.operator.clone().unwrap_or_default();
+        if !schema_field.is_operator_allowed(&operator) {
+            return Err(ChimesError::custom(
+                10119,
+                format!(
+                    "The operator {} is not allowed for field {}",
+                    operator, name
+                ),
+            ));
+        }
+        mapped.field_type = Some(schema_field.field_type.clone());
+        // 使用字段名校验值，错误信息中不会出现列名
+        CommonSearch::default().check_field(&mapped)?;
+        mapped.field = Some(schema_field.column.clone());
+        Ok(mapped)
+    }
+
+    /// 把order中的字段名转换为列名，order为空时使用default_order
+    fn map_order(&self, search: &CommonSearch) -> ChimesResult<Option<String>> {
+        let order = match search.order.as_deref().filter(|f| !f.trim().is_empty()) {
+            Some(order) => Some(order.to_string()),
+            None => self.default_order.clone(),
+        };
+        let names = self
+            .fields
+            .iter()
+            .filter(|f| f.sortable)
+            .map(|f| f.name.as_str())
+            .collect::<Vec<&str>>();
+        let list = CommonSearch {
+            order,
+            ..Default::default()
+        }
+        .order_list(&names)?;
+        if list.is_empty() {
+            return Ok(None);
+        }
+        let order = list
+            .iter()
+            .filter_map(|(name, is_asc)| {
+                self.get_field(name)
+                    .map(|f| format!("{} {}", f.column, if *is_asc { "asc" } else { "desc" }))
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        Ok(Some(order))
+    }
+
+    /// 按定义转换查询，字段名转换为列名，字段类型使用定义中的类型
+    /// 不能查询的字段、不允许的操作符和不能排序的字段返回错误
+    pub fn map_search(&self, search: &CommonSearch) -> ChimesResult<CommonSearch> {
+        let mut mapped = self.map_fields(search)?;
+        mapped.order = self.map_order(search)?;
+        Ok(mapped)
+    }
+
+    fn map_fields(&self, search: &CommonSearch) -> ChimesResult<CommonSearch> {
+        let mut mapped = search.clone();
+        mapped.search = search
+            .search
+            .iter()
+            .map(|f| self.map_field(f))
+            .collect::<ChimesResult<Vec<SearchField>>>()?;
+        Ok(mapped)
+    }
+
+    /// 用于前端生成查询界面的描述，不包含列名
+    /// for example:
+    ///     {"entity": "user", "fields": [{"name": "age", "type": "integer", "operators": ["eq", ...], "sortable": true}], "default_order": "age desc"}
+    pub fn describe(&self) -> serde_json::Value {
+        let fields = self
+            .fields
+            .iter()
+            .map(|f| {
+                json!({
+                    "name": f.name,
+                    "type": f.field_type,
+                    "operators": f.allowed_operators(),
+                    "sortable": f.sortable,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+        json!({
+            "entity": self.entity,
+            "fields": fields,
+            "default_order": self.default_order,
+        })
+    }
+}
+
+impl CommonSearch {
+    /// 按查询定义生成条件和排序，前端的字段名转换为列名，order为空时使用default_order
+    /// for example:
+    ///     let wp = search.into_wrapper_with(&get_search_schema("user").unwrap(), rb.new_wrapper())?;
+    pub fn into_wrapper_with<W: SearchWrapper>(
+        &self,
+        schema: &SearchSchema,
+        wp: W,
+    ) -> ChimesResult<W> {
+        let mapped = schema.map_search(self)?;
+        let orders = mapped.order_list(&schema.sortable_columns())?;
+        let orders = orders
+            .iter()
+            .map(|(column, is_asc)| (column.as_str(), *is_asc))
+            .collect::<Vec<(&str, bool)>>();
+        Ok(mapped.try_apply_search(wp)?.order_bys(&orders))
+    }
+
+    /// 按查询定义生成分页查询，包括条件、排序和分页
+    pub fn page_wrapper_with(
+        &self,
+        schema: &SearchSchema,
+        wp: DynamicWrapper,
+    ) -> ChimesResult<DynamicWrapper> {
+        schema
+            .map_search(self)?
+            .page_wrapper(wp, &schema.sortable_columns())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbatis::core::db::DriverType;
+    use rbatis::wrapper::Wrapper;
+
+    fn schema() -> SearchSchema {
+        SearchSchema::new("member")
+            .field("name", "u.user_name", "string", &["eq", "like"])
+            .field("age", "u.age", "integer", &[])
+            .field("secret", "u.secret", "string", &["eq"])
+            .unsortable("secret")
+            .default_order("age desc")
+    }
+
+    fn search(fields: serde_json::Value, order: Option<&str>) -> CommonSearch {
+        CommonSearch {
+            search: serde_json::from_value(fields).unwrap(),
+            order: order.map(|f| f.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn message(err: ChimesError) -> String {
+        match err.kind {
+            crate::ErrorKind::Custom { code, msg } => format!("{} {}", code, msg),
+            _ => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn map_field_to_column() {
+        let mapped = schema()
+            .map_search(&search(
+                json!([
+                    {"field": "name", "operator": "like", "val": "a"},
+                    {"logic": "or", "children": [{"field": "age", "operator": "gt", "val": "18"}]}
+                ]),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(mapped.search[0].field.as_deref(), Some("u.user_name"));
+        assert_eq!(mapped.search[0].field_type.as_deref(), Some("string"));
+        assert_eq!(mapped.search[1].children[0].field.as_deref(), Some("u.age"));
+        assert_eq!(
+            mapped.search[1].children[0].field_type.as_deref(),
+            Some("integer")
+        );
+    }
+
+    #[test]
+    fn map_field_errors() {
+        let schema = schema();
+        for (fields, expected) in [
+            (
+                json!([{"field": "password", "operator": "eq", "val": "a"}]),
+                "10119 The field password is not searchable in member",
+            ),
+            (
+                json!([{"field": "name", "operator": "gt", "val": "a"}]),
+                "10119 The operator gt is not allowed for field name",
+            ),
+        ] {
+            let err = schema.map_search(&search(fields, None)).unwrap_err();
+            assert_eq!(message(err), expected);
+        }
+        // 值错误时使用字段名，不会出现列名
+        let err = schema
+            .map_search(&search(
+                json!([{"field": "age", "operator": "eq", "val": "abc"}]),
+                None,
+            ))
+            .unwrap_err();
+        let msg = message(err);
+        assert!(msg.contains("age"), "{}", msg);
+        assert!(!msg.contains("u.age"), "{}", msg);
+    }
+
+    #[test]
+    fn map_order_to_columns() {
+        let schema = schema();
+        assert_eq!(
+            schema
+                .map_order(&search(json!([]), None))
+                .unwrap()
+                .as_deref(),
+            Some("u.age desc")
+        );
+        assert_eq!(
+            schema
+                .map_order(&search(json!([]), Some("name asc,age desc")))
+                .unwrap()
+                .as_deref(),
+            Some("u.user_name asc,u.age desc")
+        );
+        assert!(schema
+            .map_order(&search(json!([]), Some("secret")))
+            .is_err());
+        assert!(schema.map_order(&search(json!([]), Some("u.age"))).is_err());
+        assert_eq!(
+            SearchSchema::new("member")
+                .map_order(&search(json!([]), None))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn into_wrapper_with_order() {
+        let search = search(
+            json!([{"field": "name", "operator": "eq", "val": "a"}]),
+            Some("name desc"),
+        );
+        let wp = search
+            .into_wrapper_with(&schema(), Wrapper::new(&DriverType::Mysql))
+            .unwrap();
+        assert_eq!(wp.sql.trim(), "u.user_name = ? order by u.user_name desc");
+        let wp = search
+            .into_wrapper_with(&schema(), DynamicWrapper::new(&DriverType::Mysql))
+            .unwrap();
+        assert_eq!(wp.sql.trim(), "u.user_name = ? order by u.user_name desc");
+        // order为空时使用default_order
+        let wp = CommonSearch::default()
+            .into_wrapper_with(&schema(), Wrapper::new(&DriverType::Mysql))
+            .unwrap();
+        assert_eq!(wp.sql.trim(), "order by u.age desc");
+    }
+}
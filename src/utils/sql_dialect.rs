@@ -0,0 +1,982 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use rbatis::DriverType;
+use rbson::Bson;
+
+use crate::{ChimesError, ChimesResult};
+
+/// SQL方言，参考SQL使用MySQL的写法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SqlDialect {
+    MySql,
+    Postgres,
+    Sqlite,
+    MsSql,
+}
+
+impl SqlDialect {
+    pub fn from_driver_type(driver_type: &DriverType) -> Option<Self> {
+        match driver_type {
+            DriverType::Mysql => Some(SqlDialect::MySql),
+            DriverType::Postgres => Some(SqlDialect::Postgres),
+            DriverType::Sqlite => Some(SqlDialect::Sqlite),
+            DriverType::Mssql => Some(SqlDialect::MsSql),
+            DriverType::None => None,
+        }
+    }
+
+    /// 按方言引用标识符，如 `name` "name" [name]
+    pub fn quote_identifier(&self, name: &str) -> String {
+        match self {
+            SqlDialect::MySql => format!("`{}`", name.replace('`', "``")),
+            SqlDialect::Postgres | SqlDialect::Sqlite => {
+                format!("\"{}\"", name.replace('"', "\"\""))
+            }
+            SqlDialect::MsSql => format!("[{}]", name.replace(']', "]]")),
+        }
+    }
+
+    /// 引用可能带有表名的标识符，如 u.name => `u`.`name`，无效的标识符返回None
+    pub fn quote_qualified_identifier(&self, name: &str) -> Option<String> {
+        parse_identifier(name).map(|f| {
+            f.iter()
+                .map(|s| self.quote_identifier(s))
+                .collect::<Vec<String>>()
+                .join(".")
+        })
+    }
+
+    /// 字符串常量，MySQL中的反斜杠也需要转义
+    pub fn quote_literal(&self, text: &str) -> String {
+        let text = text.replace('\'', "''");
+        match self {
+            SqlDialect::MySql => format!("'{}'", text.replace('\\', "\\\\")),
+            SqlDialect::MsSql => format!("N'{}'", text),
+            _ => format!("'{}'", text),
+        }
+    }
+
+    /// 参数值的SQL常量形式，只用于日志和调试，不能用于执行
+    pub fn literal(&self, value: &Bson) -> String {
+        match value {
+            Bson::Null => "NULL".to_string(),
+            Bson::Boolean(b) => match self {
+                SqlDialect::MySql | SqlDialect::Postgres => {
+                    if *b { "TRUE" } else { "FALSE" }.to_string()
+                }
+                _ => if *b { "1" } else { "0" }.to_string(),
+            },
+            Bson::Int32(v) => v.to_string(),
+            Bson::Int64(v) => v.to_string(),
+            Bson::UInt32(v) => v.to_string(),
+            Bson::UInt64(v) => v.to_string(),
+            Bson::Double(v) => v.to_string(),
+            Bson::Decimal128(v) => v.to_string(),
+            Bson::String(v) => self.quote_literal(v),
+            Bson::DateTime(v) => self.quote_literal(&v.to_string()),
+            Bson::Timestamp(v) => self.quote_literal(&v.to_string()),
+            Bson::Binary(v) => {
+                let bytes = hex::encode(&v.bytes);
+                match self {
+                    SqlDialect::Postgres => format!("'\\x{}'::bytea", bytes),
+                    SqlDialect::MsSql => format!("0x{}", bytes),
+                    _ => format!("X'{}'", bytes),
+                }
+            }
+            Bson::Array(_) | Bson::Document(_) => {
+                self.quote_literal(&value.clone().into_relaxed_extjson().to_string())
+            }
+        }
+    }
+
+    /// 编号占位符是否是这个数据库的写法，如MySQL中的@p1是用户变量
+    fn is_own_placeholder(&self, text: &str) -> bool {
+        match self {
+            SqlDialect::MySql | SqlDialect::Sqlite => text.starts_with('?'),
+            SqlDialect::Postgres => text.starts_with('$'),
+            SqlDialect::MsSql => text.starts_with('@'),
+        }
+    }
+
+    /// 第index个参数的占位符，index从1开始
+    pub fn placeholder(&self, index: usize) -> String {
+        match self {
+            SqlDialect::MySql | SqlDialect::Sqlite => "?".to_string(),
+            SqlDialect::Postgres => format!("${}", index),
+            SqlDialect::MsSql => format!("@p{}", index),
+        }
+    }
+}
+
+lazy_static! {
+    // MultipleDatabaseIntercepter转换SQL的数据库，默认只转换PostgreSQL
+    static ref INTERCEPT_DIALECTS: RwLock<HashSet<SqlDialect>> =
+        RwLock::new(HashSet::from([SqlDialect::Postgres]));
+}
+
+/// 设置MultipleDatabaseIntercepter是否转换这个数据库的SQL
+/// 默认只转换PostgreSQL，SQLite和MSSQL需要SQL都按MySQL的写法时才打开
+pub fn set_intercept_translation(dialect: SqlDialect, enabled: bool) {
+    let mut dialects = INTERCEPT_DIALECTS.write().unwrap();
+    if enabled {
+        dialects.insert(dialect);
+    } else {
+        dialects.remove(&dialect);
+    }
+}
+
+pub fn is_intercept_translation(dialect: SqlDialect) -> bool {
+    INTERCEPT_DIALECTS.read().unwrap().contains(&dialect)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SqlToken {
+    Word(String),
+    Number(String),
+    Backtick(String), // `ident` 中的名称
+    Raw(String),      // 字符串、双引号标识符等原样输出的内容
+    Comment(String),
+    Placeholder(Option<usize>), // ? 或者 $1 ?1 @p1
+    Space(String),
+    Symbol(String),
+}
+
+fn take_while_from(chars: &[char], start: usize, f: impl Fn(char) -> bool) -> usize {
+    let mut end = start;
+    while end < chars.len() && f(chars[end]) {
+        end += 1;
+    }
+    end
+}
+
+/// 读取引号包围的内容，两个连续的引号表示引号本身
+fn take_quoted(chars: &[char], start: usize, quote: char, backslash: bool) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if backslash && chars[i] == '\\' {
+            i += 2;
+            continue;
+        }
+        if chars[i] == quote {
+            if i + 1 < chars.len() && chars[i + 1] == quote {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+fn tokenize(sql: &str) -> Vec<SqlToken> {
+    tokenize_with_spans(sql)
+        .into_iter()
+        .map(|(token, _, _)| token)
+        .collect()
+}
+
+/// 分词，同时返回每个token在原始SQL中的字符位置
+pub(crate) fn tokenize_with_spans(sql: &str) -> Vec<(SqlToken, usize, usize)> {
+    let chars = sql.chars().collect::<Vec<char>>();
+    let text = |s: usize, e: usize| chars[s..e].iter().collect::<String>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let end;
+        let token;
+        if c.is_whitespace() {
+            end = take_while_from(&chars, i, |f| f.is_whitespace());
+            token = SqlToken::Space(text(i, end));
+        } else if (c == '-' && next == Some('-'))
+            || (c == '#' && !matches!(next, Some('>') | Some('-')))
+        {
+            end = take_while_from(&chars, i, |f| f != '\n');
+            token = SqlToken::Comment(text(i, end));
+        } else if c == '/' && next == Some('*') {
+            let mut pos = i + 2;
+            while pos + 1 < chars.len() && !(chars[pos] == '*' && chars[pos + 1] == '/') {
+                pos += 1;
+            }
+            end = (pos + 2).min(chars.len());
+            token = SqlToken::Comment(text(i, end));
+        } else if c == '\'' {
+            end = take_quoted(&chars, i, '\'', true);
+            token = SqlToken::Raw(text(i, end));
+        } else if c == '"' {
+            end = take_quoted(&chars, i, '"', true);
+            token = SqlToken::Raw(text(i, end));
+        } else if c == '`' {
+            end = take_quoted(&chars, i, '`', false);
+            let close = if end > i + 1 && chars[end - 1] == '`' {
+                end - 1
+            } else {
+                end
+            };
+            token = SqlToken::Backtick(text(i + 1, close).replace("``", "`"));
+        } else if c == '?' {
+            let digits = take_while_from(&chars, i + 1, |f| f.is_ascii_digit());
+            if next == Some('?') {
+                // ?? 表示问号本身，如PostgreSQL的JSON操作符
+                end = i + 2;
+                token = SqlToken::Symbol("?".to_string());
+            } else if (next == Some('|') && chars.get(i + 2) != Some(&'|')) || next == Some('&') {
+                end = i + 2;
+                token = SqlToken::Symbol(text(i, end));
+            } else if digits > i + 1 {
+                end = digits;
+                token = SqlToken::Placeholder(text(i + 1, end).parse::<usize>().ok());
+            } else {
+                end = i + 1;
+                token = SqlToken::Placeholder(None);
+            }
+        } else if c == '$' && next.map(|f| f.is_ascii_digit()).unwrap_or(false) {
+            end = take_while_from(&chars, i + 1, |f| f.is_ascii_digit());
+            token = SqlToken::Placeholder(text(i + 1, end).parse::<usize>().ok());
+        } else if c == '@'
+            && matches!(next, Some('p') | Some('P'))
+            && chars
+                .get(i + 2)
+                .map(|f| f.is_ascii_digit())
+                .unwrap_or(false)
+        {
+            end = take_while_from(&chars, i + 2, |f| f.is_ascii_digit());
+            token = SqlToken::Placeholder(text(i + 2, end).parse::<usize>().ok());
+        } else if c.is_ascii_digit() {
+            end = take_while_from(&chars, i, |f| f.is_ascii_digit() || f == '.');
+            token = SqlToken::Number(text(i, end));
+        } else if c.is_alphanumeric() || c == '_' {
+            end = take_while_from(&chars, i, |f| f.is_alphanumeric() || f == '_' || f == '$');
+            token = SqlToken::Word(text(i, end));
+        } else {
+            end = i + 1;
+            token = SqlToken::Symbol(c.to_string());
+        }
+        tokens.push((token, i, end));
+        i = end;
+    }
+    tokens
+}
+
+pub(crate) fn is_word(token: Option<&SqlToken>, word: &str) -> bool {
+    matches!(token, Some(SqlToken::Word(w)) if w.eq_ignore_ascii_case(word))
+}
+
+pub(crate) fn is_symbol(token: Option<&SqlToken>, symbol: &str) -> bool {
+    matches!(token, Some(SqlToken::Symbol(s)) if s == symbol)
+}
+
+/// 从pos开始跳过空白和注释，返回下一个有效token的位置
+fn skip_space(tokens: &[SqlToken], pos: usize) -> usize {
+    let mut pos = pos;
+    while matches!(
+        tokens.get(pos),
+        Some(SqlToken::Space(_)) | Some(SqlToken::Comment(_))
+    ) {
+        pos += 1;
+    }
+    pos
+}
+
+/// 解析LIMIT的参数：LIMIT count / LIMIT offset, count / LIMIT count OFFSET offset
+/// 返回 (count, offset, 结束位置)
+fn parse_limit(tokens: &[SqlToken], pos: usize) -> Option<(SqlToken, Option<SqlToken>, usize)> {
+    let is_arg = |t: Option<&SqlToken>| {
+        matches!(
+            t,
+            Some(SqlToken::Number(_)) | Some(SqlToken::Placeholder(_))
+        )
+    };
+    let first = skip_space(tokens, pos + 1);
+    if !is_arg(tokens.get(first)) {
+        return None;
+    }
+    let after = skip_space(tokens, first + 1);
+    if is_symbol(tokens.get(after), ",") {
+        let second = skip_space(tokens, after + 1);
+        if !is_arg(tokens.get(second)) {
+            return None;
+        }
+        return Some((
+            tokens[second].clone(),
+            Some(tokens[first].clone()),
+            second + 1,
+        ));
+    }
+    if is_word(tokens.get(after), "offset") {
+        let second = skip_space(tokens, after + 1);
+        if !is_arg(tokens.get(second)) {
+            return None;
+        }
+        return Some((
+            tokens[first].clone(),
+            Some(tokens[second].clone()),
+            second + 1,
+        ));
+    }
+    Some((tokens[first].clone(), None, first + 1))
+}
+
+/// 把MySQL字符串转换为标准写法，如 'it\'s' 和 "it's" 都转换为 'it''s'
+/// \% 和 \_ 保持不变，用于LIKE；其它数据库的字符串中不能有\0，返回错误
+fn mysql_string_literal(raw: &str) -> ChimesResult<String> {
+    let quote = if raw.starts_with('"') { '"' } else { '\'' };
+    if raw.len() < 2 || !raw.ends_with(quote) || (quote == '\'' && !raw.contains('\\')) {
+        return Ok(raw.to_string());
+    }
+    let mut out = String::from("'");
+    let mut chars = raw[1..raw.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quote == '"' => {
+                // "" 表示双引号本身
+                chars.next();
+                out.push('"');
+            }
+            '\'' if quote == '"' => out.push_str("''"),
+            '\\' => match chars.next() {
+                Some('\'') => out.push_str("''"),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('b') => out.push('\u{8}'),
+                Some('Z') => out.push('\u{1a}'),
+                Some('0') => {
+                    return Err(ChimesError::custom(
+                        10130,
+                        format!(
+                            "The string {} contains \\0 which is not supported by other databases",
+                            raw
+                        ),
+                    ))
+                }
+                Some('%') => out.push_str("\\%"),
+                Some('_') => out.push_str("\\_"),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    Ok(out)
+}
+
+/// 将MySQL写法的SQL转换成目标数据库的写法
+/// 处理标识符引用、参数占位符、LIMIT分页、NOW()、IFNULL和字符串中的反斜杠转义
+/// MySQL中双引号包围的是字符串，转换为单引号，标识符需要使用反引号；
+/// 已经使用编号占位符的SQL（如Wrapper生成的$1）中的双引号和问号保持不变
+/// 字符串中的占位符和注释中的内容保持不变；?? 表示问号本身
+/// 不是目标数据库写法的编号占位符（如MySQL的用户变量@p1）原样输出
+/// 字符串中有\0时返回10130的错误
+pub fn translate_sql(sql: &str, dialect: SqlDialect) -> ChimesResult<String> {
+    if dialect == SqlDialect::MySql {
+        return Ok(sql.to_owned());
+    }
+    let chars = sql.chars().collect::<Vec<char>>();
+    let spans = tokenize_with_spans(sql);
+    // E'...' 是PostgreSQL的转义字符串，不需要转换
+    let escape_string = |i: usize| {
+        i > 0
+            && spans[i - 1].2 == spans[i].1
+            && matches!(&spans[i - 1].0, SqlToken::Word(w) if w.eq_ignore_ascii_case("e"))
+    };
+    let mut tokens = spans
+        .iter()
+        .enumerate()
+        .map(|(i, (token, start, end))| {
+            let text = || chars[*start..*end].iter().collect::<String>();
+            Ok(match token {
+                SqlToken::Placeholder(Some(_)) if !dialect.is_own_placeholder(&text()) => {
+                    SqlToken::Raw(text())
+                }
+                SqlToken::Raw(s) if s.starts_with('\'') && !escape_string(i) => {
+                    SqlToken::Raw(mysql_string_literal(s)?)
+                }
+                _ => token.clone(),
+            })
+        })
+        .collect::<ChimesResult<Vec<SqlToken>>>()?;
+
+    // 已经是编号的占位符时（如Wrapper生成的$1），不再转换问号和双引号
+    let numbered = tokens
+        .iter()
+        .any(|f| matches!(f, SqlToken::Placeholder(Some(_))));
+    let mut index = 0;
+    for token in tokens.iter_mut() {
+        match token {
+            SqlToken::Placeholder(None) => {
+                if numbered {
+                    *token = SqlToken::Symbol("?".to_string());
+                } else {
+                    index += 1;
+                    *token = SqlToken::Placeholder(Some(index));
+                }
+            }
+            SqlToken::Raw(s) if s.starts_with('"') && !numbered => {
+                *token = SqlToken::Raw(mysql_string_literal(s)?);
+            }
+            _ => {}
+        }
+    }
+
+    let mut out: Vec<SqlToken> = vec![];
+    // 每一层括号中是否已经出现ORDER BY，MSSQL的OFFSET FETCH需要ORDER BY
+    let mut order_by = vec![false];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        match token {
+            SqlToken::Backtick(name) => {
+                out.push(SqlToken::Raw(dialect.quote_identifier(name)));
+            }
+            SqlToken::Comment(text) if text.starts_with('#') => {
+                out.push(SqlToken::Comment(format!("--{}", &text[1..])));
+            }
+            SqlToken::Symbol(s) if s == "(" => {
+                order_by.push(false);
+                out.push(token.clone());
+            }
+            SqlToken::Symbol(s) if s == ")" => {
+                if order_by.len() > 1 {
+                    order_by.pop();
+                }
+                out.push(token.clone());
+            }
+            SqlToken::Symbol(s) if s == ";" => {
+                if let Some(last) = order_by.last_mut() {
+                    *last = false;
+                }
+                out.push(token.clone());
+            }
+            SqlToken::Word(w) if w.eq_ignore_ascii_case("order") => {
+                if is_word(tokens.get(skip_space(&tokens, i + 1)), "by") {
+                    if let Some(last) = order_by.last_mut() {
+                        *last = true;
+                    }
+                }
+                out.push(token.clone());
+            }
+            SqlToken::Word(w) if w.eq_ignore_ascii_case("now") => {
+                let open = skip_space(&tokens, i + 1);
+                let close = skip_space(&tokens, open + 1);
+                if is_symbol(tokens.get(open), "(") && is_symbol(tokens.get(close), ")") {
+                    let now = match dialect {
+                        SqlDialect::Sqlite => "datetime('now', 'localtime')",
+                        SqlDialect::MsSql => "GETDATE()",
+                        _ => "NOW()",
+                    };
+                    out.push(SqlToken::Raw(now.to_string()));
+                    i = close + 1;
+                    continue;
+                }
+                out.push(token.clone());
+            }
+            SqlToken::Word(w)
+                if w.eq_ignore_ascii_case("ifnull")
+                    && is_symbol(tokens.get(skip_space(&tokens, i + 1)), "(") =>
+            {
+                match dialect {
+                    SqlDialect::Sqlite => out.push(token.clone()),
+                    _ => out.push(SqlToken::Word("COALESCE".to_string())),
+                }
+            }
+            SqlToken::Word(w) if w.eq_ignore_ascii_case("limit") => {
+                if let Some((count, offset, end)) = parse_limit(&tokens, i) {
+                    let space = SqlToken::Space(" ".to_string());
+                    let word = |s: &str| SqlToken::Word(s.to_string());
+                    if dialect == SqlDialect::MsSql {
+                        if !order_by.last().copied().unwrap_or(false) {
+                            out.extend(vec![
+                                word("ORDER"),
+                                space.clone(),
+                                word("BY"),
+                                space.clone(),
+                                SqlToken::Raw("(SELECT NULL)".to_string()),
+                                space.clone(),
+                            ]);
+                        }
+                        out.extend(vec![
+                            word("OFFSET"),
+                            space.clone(),
+                            offset.unwrap_or_else(|| SqlToken::Number("0".to_string())),
+                            space.clone(),
+                            word("ROWS"),
+                            space.clone(),
+                            word("FETCH"),
+                            space.clone(),
+                            word("NEXT"),
+                            space.clone(),
+                            count,
+                            space.clone(),
+                            word("ROWS"),
+                            space,
+                            word("ONLY"),
+                        ]);
+                    } else {
+                        out.extend(vec![word("LIMIT"), space.clone(), count]);
+                        if let Some(offset) = offset {
+                            out.extend(vec![space.clone(), word("OFFSET"), space, offset]);
+                        }
+                    }
+                    i = end;
+                    continue;
+                }
+                out.push(token.clone());
+            }
+            _ => out.push(token.clone()),
+        }
+        i += 1;
+    }
+
+    // SQLite的占位符顺序被调整时（如LIMIT a,b），使用?1这样的编号
+    let placeholders = out
+        .iter()
+        .filter_map(|f| match f {
+            SqlToken::Placeholder(Some(n)) => Some(*n),
+            _ => None,
+        })
+        .collect::<Vec<usize>>();
+    let sequential = placeholders.iter().enumerate().all(|(i, n)| *n == i + 1);
+
+    Ok(out
+        .iter()
+        .map(|f| match f {
+            SqlToken::Word(s)
+            | SqlToken::Number(s)
+            | SqlToken::Raw(s)
+            | SqlToken::Comment(s)
+            | SqlToken::Space(s)
+            | SqlToken::Symbol(s) => s.clone(),
+            SqlToken::Backtick(s) => dialect.quote_identifier(s),
+            SqlToken::Placeholder(Some(n)) => match dialect {
+                SqlDialect::Sqlite if !sequential => format!("?{}", n),
+                _ => dialect.placeholder(*n),
+            },
+            SqlToken::Placeholder(None) => "?".to_string(),
+        })
+        .collect::<String>())
+}
+
+/// 规范化SQL，用于按语句统计
+/// 字符串、数字和占位符都替换为?，去掉注释并合并空白，IN (?, ?, ?) 合并为 IN (?)
+pub fn normalize_sql(sql: &str) -> String {
+    let mut out: Vec<String> = vec![];
+    for token in tokenize(sql) {
+        let text = match token {
+            SqlToken::Space(_) | SqlToken::Comment(_) => {
+                if out.last().map(|f| f != " ").unwrap_or(false) {
+                    out.push(" ".to_string());
+                }
+                continue;
+            }
+            SqlToken::Raw(s) if s.starts_with('\'') => "?".to_string(),
+            SqlToken::Number(_) | SqlToken::Placeholder(_) => "?".to_string(),
+            SqlToken::Backtick(s) => format!("`{}`", s),
+            SqlToken::Word(s) | SqlToken::Raw(s) | SqlToken::Symbol(s) => s,
+        };
+        if text == "?" {
+            // 去掉列表中重复的 ?,
+            let tail = out
+                .iter()
+                .rev()
+                .filter(|f| f.as_str() != " ")
+                .take(2)
+                .map(|f| f.as_str())
+                .collect::<Vec<&str>>();
+            if tail == [",", "?"] {
+                while out.last().map(|f| f != "?").unwrap_or(false) {
+                    out.pop();
+                }
+                continue;
+            }
+        }
+        out.push(text);
+    }
+    out.concat().trim().to_string()
+}
+
+/// 按分号拆分多条SQL语句，字符串和注释中的分号不拆分
+/// 只有空白和注释的语句会被忽略
+/// 存储过程、触发器等包含分号的语句使用DELIMITER行修改分隔符，DELIMITER行本身不输出
+/// for example:
+///     DELIMITER //
+///     CREATE TRIGGER t BEFORE INSERT ON user FOR EACH ROW BEGIN SET NEW.age = 1; END//
+///     DELIMITER ;
+pub fn split_sql_statements(sql: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut delimiter = ";".to_string();
+    let mut block = String::new();
+    for line in sql.split_inclusive('\n') {
+        match parse_delimiter_directive(line) {
+            Some(next) => {
+                statements.extend(split_by_delimiter(&block, &delimiter));
+                block.clear();
+                delimiter = next;
+            }
+            None => block.push_str(line),
+        }
+    }
+    statements.extend(split_by_delimiter(&block, &delimiter));
+    statements
+}
+
+/// 解析 DELIMITER xx 行，返回新的分隔符
+fn parse_delimiter_directive(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("delimiter") {
+        return None;
+    }
+    match (words.next(), words.next()) {
+        (Some(delimiter), None) => Some(delimiter.to_string()),
+        _ => None,
+    }
+}
+
+/// 按分隔符拆分，字符串、标识符和注释中的分隔符不拆分
+fn split_by_delimiter(sql: &str, delimiter: &str) -> Vec<String> {
+    let chars = sql.chars().collect::<Vec<char>>();
+    let delimiter = delimiter.chars().collect::<Vec<char>>();
+    let mut protected = vec![false; chars.len()];
+    for (token, from, to) in tokenize_with_spans(sql) {
+        if matches!(
+            token,
+            SqlToken::Raw(_) | SqlToken::Comment(_) | SqlToken::Backtick(_)
+        ) {
+            protected[from..to].iter_mut().for_each(|f| *f = true);
+        }
+    }
+    let mut statements = vec![];
+    let mut push = |text: String| {
+        let has_content = tokenize(&text)
+            .iter()
+            .any(|f| !matches!(f, SqlToken::Space(_) | SqlToken::Comment(_)));
+        if has_content {
+            statements.push(text.trim().to_string());
+        }
+    };
+    let mut start = 0;
+    let mut i = 0;
+    while i + delimiter.len() <= chars.len() {
+        if !protected[i] && chars[i..i + delimiter.len()] == delimiter[..] {
+            push(chars[start..i].iter().collect::<String>());
+            i += delimiter.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    push(chars[start.min(chars.len())..].iter().collect::<String>());
+    statements
+}
+
+/// 调整编号占位符（$n、@pn、?n）的编号，编号不小于from的加上delta
+/// 字符串和注释中的内容不变，没有编号的?不变
+pub fn shift_placeholders(sql: &str, dialect: SqlDialect, from: usize, delta: isize) -> String {
+    let chars = sql.chars().collect::<Vec<char>>();
+    let mut out = String::with_capacity(sql.len() + 8);
+    for (token, start, end) in tokenize_with_spans(sql) {
+        match token {
+            SqlToken::Placeholder(Some(n))
+                if n >= from
+                    && dialect
+                        .is_own_placeholder(&chars[start..end].iter().collect::<String>()) =>
+            {
+                let n = (n as isize + delta).max(1) as usize;
+                match dialect {
+                    SqlDialect::MySql | SqlDialect::Sqlite => out.push_str(&format!("?{}", n)),
+                    _ => out.push_str(&dialect.placeholder(n)),
+                }
+            }
+            _ => out.extend(chars[start..end].iter()),
+        }
+    }
+    out
+}
+
+/// 查找括号外的关键字序列，如 ["order", "by"]，找到时返回关键字之前和从关键字开始的两部分
+pub fn split_top_level_keywords(sql: &str, words: &[&str]) -> Option<(String, String)> {
+    if words.is_empty() {
+        return None;
+    }
+    let spans = tokenize_with_spans(sql)
+        .into_iter()
+        .filter(|(f, _, _)| !matches!(f, SqlToken::Space(_) | SqlToken::Comment(_)))
+        .collect::<Vec<(SqlToken, usize, usize)>>();
+    let mut depth = 0usize;
+    for (i, (token, start, _)) in spans.iter().enumerate() {
+        if is_symbol(Some(token), "(") {
+            depth += 1;
+        } else if is_symbol(Some(token), ")") {
+            depth = depth.saturating_sub(1);
+        } else if depth == 0
+            && words
+                .iter()
+                .enumerate()
+                .all(|(j, w)| is_word(spans.get(i + j).map(|f| &f.0), w))
+        {
+            let chars = sql.chars().collect::<Vec<char>>();
+            return Some((
+                chars[..*start].iter().collect(),
+                chars[*start..].iter().collect(),
+            ));
+        }
+    }
+    None
+}
+
+/// 标识符的最大长度
+const MAX_IDENTIFIER_LEN: usize = 128;
+
+/// 解析标识符，支持 name、t.name 和 `name` "name" [name] 形式的引用
+/// 返回去掉引号后的各部分，包含其他内容时返回None
+pub fn parse_identifier(name: &str) -> Option<Vec<String>> {
+    let chars = name.chars().collect::<Vec<char>>();
+    let mut parts = vec![];
+    let mut i = 0;
+    loop {
+        let mut part = String::new();
+        match chars.get(i).copied() {
+            Some(open) if open == '`' || open == '"' || open == '[' => {
+                let close = if open == '[' { ']' } else { open };
+                i += 1;
+                loop {
+                    match chars.get(i).copied() {
+                        Some(c) if c == close => {
+                            if chars.get(i + 1) == Some(&close) {
+                                part.push(c);
+                                i += 2;
+                            } else {
+                                i += 1;
+                                break;
+                            }
+                        }
+                        Some(c) if !c.is_control() => {
+                            part.push(c);
+                            i += 1;
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while let Some(c) = chars.get(i).copied() {
+                    if c.is_alphanumeric() || c == '_' || c == '$' {
+                        part.push(c);
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            _ => return None,
+        }
+        if part.is_empty() || part.chars().count() > MAX_IDENTIFIER_LEN {
+            return None;
+        }
+        parts.push(part);
+        match chars.get(i) {
+            None => return Some(parts),
+            Some('.') => i += 1,
+            _ => return None,
+        }
+    }
+}
+
+/// 是否为有效的标识符，用于检查客户端传入的列名
+pub fn is_valid_identifier(name: &str) -> bool {
+    parse_identifier(name).is_some()
+}
+
+/// 将参数作为常量替换到SQL的占位符中，只用于日志和调试
+/// ?按出现的顺序对应参数，$n、@pn、?n按编号对应参数，没有对应的参数时保留占位符
+pub fn inline_sql_args(sql: &str, dialect: SqlDialect, args: &[Bson]) -> String {
+    let chars = sql.chars().collect::<Vec<char>>();
+    let mut out = String::with_capacity(sql.len() + args.len() * 8);
+    let mut next = 0;
+    for (token, start, end) in tokenize_with_spans(sql) {
+        let index = match token {
+            SqlToken::Placeholder(Some(n)) => n.checked_sub(1),
+            SqlToken::Placeholder(None) => {
+                next += 1;
+                Some(next - 1)
+            }
+            _ => None,
+        };
+        match index.and_then(|i| args.get(i)) {
+            Some(value) => out.push_str(&dialect.literal(value)),
+            None => out.extend(chars[start..end].iter()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_placeholders() {
+        let sql = "select * from t where a = ? and b = '?' -- ?\n and c = ?";
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            "select * from t where a = $1 and b = '?' -- ?\n and c = $2"
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::MsSql).unwrap(),
+            "select * from t where a = @p1 and b = '?' -- ?\n and c = @p2"
+        );
+        assert_eq!(translate_sql(sql, SqlDialect::Sqlite).unwrap(), sql);
+        // 已经编号的占位符不再转换问号，?? 表示问号本身
+        assert_eq!(
+            translate_sql(
+                "select * from t where a = $1 and j ?? 'k'",
+                SqlDialect::Postgres
+            )
+            .unwrap(),
+            "select * from t where a = $1 and j ? 'k'"
+        );
+    }
+
+    #[test]
+    fn translate_mysql_user_variables() {
+        let sql = "select @p1 := 1, a from t where b = ?";
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            "select @p1 := 1, a from t where b = $1"
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Sqlite).unwrap(),
+            "select @p1 := 1, a from t where b = ?"
+        );
+        assert_eq!(
+            shift_placeholders("a = @p1 and b = ?1", SqlDialect::MySql, 1, 1),
+            "a = @p1 and b = ?2"
+        );
+    }
+
+    #[test]
+    fn translate_quoting_and_functions() {
+        let sql = "select `name`, ifnull(`a`, 0), now() from `user`";
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            "select \"name\", COALESCE(\"a\", 0), NOW() from \"user\""
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::MsSql).unwrap(),
+            "select [name], COALESCE([a], 0), GETDATE() from [user]"
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Sqlite).unwrap(),
+            "select \"name\", ifnull(\"a\", 0), datetime('now', 'localtime') from \"user\""
+        );
+    }
+
+    #[test]
+    fn translate_limit() {
+        let sql = "select * from t order by id limit 20, 10";
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            "select * from t order by id LIMIT 10 OFFSET 20"
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::MsSql).unwrap(),
+            "select * from t order by id OFFSET 20 ROWS FETCH NEXT 10 ROWS ONLY"
+        );
+        assert_eq!(
+            translate_sql("select * from t limit ?, ?", SqlDialect::MsSql).unwrap(),
+            "select * from t ORDER BY (SELECT NULL) OFFSET @p1 ROWS FETCH NEXT @p2 ROWS ONLY"
+        );
+        assert_eq!(
+            translate_sql("select * from t limit ?, ?", SqlDialect::Sqlite).unwrap(),
+            "select * from t LIMIT ?2 OFFSET ?1"
+        );
+    }
+
+    #[test]
+    fn translate_string_escapes() {
+        let sql = r"select 'it\'s', 'a\\b', 'x''y', 'line\n', 'a\%'";
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            "select 'it''s', 'a\\b', 'x''y', 'line\n', 'a\\%'"
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::MsSql).unwrap(),
+            "select 'it''s', 'a\\b', 'x''y', 'line\n', 'a\\%'"
+        );
+        // PostgreSQL的E'...'保持不变
+        assert_eq!(
+            translate_sql(r"select E'it\'s'", SqlDialect::Postgres).unwrap(),
+            r"select E'it\'s'"
+        );
+        assert_eq!(translate_sql(sql, SqlDialect::MySql).unwrap(), sql);
+    }
+
+    #[test]
+    fn translate_double_quoted_strings() {
+        let sql = r#"select * from `t` where a = "it's" and b = "say ""hi""" and c = "x\"y?""#;
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Postgres).unwrap(),
+            r#"select * from "t" where a = 'it''s' and b = 'say "hi"' and c = 'x"y?'"#
+        );
+        assert_eq!(
+            translate_sql(sql, SqlDialect::Sqlite).unwrap(),
+            r#"select * from "t" where a = 'it''s' and b = 'say "hi"' and c = 'x"y?'"#
+        );
+        assert_eq!(
+            translate_sql(r#"select "a" from t where b = ?"#, SqlDialect::MsSql).unwrap(),
+            "select 'a' from t where b = @p1"
+        );
+        // 已经使用编号占位符的SQL中双引号是标识符
+        let native = r#"select "name" from t where id = $1"#;
+        assert_eq!(translate_sql(native, SqlDialect::Postgres).unwrap(), native);
+        assert_eq!(translate_sql(sql, SqlDialect::MySql).unwrap(), sql);
+    }
+
+    #[test]
+    fn translate_rejects_nul_escape() {
+        for sql in [r"select 'a\0b'", r#"select "a\0b""#] {
+            for dialect in [SqlDialect::Postgres, SqlDialect::Sqlite, SqlDialect::MsSql] {
+                let err = translate_sql(sql, dialect).unwrap_err();
+                assert!(matches!(
+                    err.kind,
+                    crate::ErrorKind::Custom { code: 10130, .. }
+                ));
+            }
+            assert_eq!(translate_sql(sql, SqlDialect::MySql).unwrap(), sql);
+        }
+        // \\0 是反斜杠和0
+        assert_eq!(
+            translate_sql(r"select 'a\\0'", SqlDialect::Postgres).unwrap(),
+            r"select 'a\0'"
+        );
+    }
+
+    #[test]
+    fn split_statements_with_delimiter() {
+        let sql = "create table t (a text); -- x; y\ninsert into t values ('a;b');\n\
+                   DELIMITER //\n\
+                   create trigger tr before insert on t for each row begin set new.a = 'x'; end//\n\
+                   delimiter ;\n\
+                   select 1;";
+        assert_eq!(
+            split_sql_statements(sql),
+            vec![
+                "create table t (a text)",
+                "-- x; y\ninsert into t values ('a;b')",
+                "create trigger tr before insert on t for each row begin set new.a = 'x'; end",
+                "select 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn intercept_translation_defaults() {
+        assert!(is_intercept_translation(SqlDialect::Postgres));
+        assert!(!is_intercept_translation(SqlDialect::Sqlite));
+        assert!(!is_intercept_translation(SqlDialect::MsSql));
+    }
+}
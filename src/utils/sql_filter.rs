@@ -0,0 +1,831 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::RwLock;
+
+use rbatis::rbatis::Rbatis;
+use rbson::Bson;
+use serde::Serialize;
+
+use crate::{
+    is_symbol, is_word, parse_identifier, tokenize_with_spans, ChimesError, ChimesResult,
+    SqlDialect, SqlToken,
+};
+
+/// 租户值在生成的SQL中的标记，输出时替换为占位符
+const TENANT_MARK: char = '\u{0}';
+
+/// 表名后面不是别名的关键字
+const NOT_ALIAS: &[&str] = &[
+    "where",
+    "join",
+    "inner",
+    "left",
+    "right",
+    "full",
+    "outer",
+    "cross",
+    "natural",
+    "on",
+    "using",
+    "group",
+    "order",
+    "limit",
+    "having",
+    "union",
+    "intersect",
+    "except",
+    "set",
+    "offset",
+    "fetch",
+    "for",
+    "window",
+    "returning",
+    "straight_join",
+    "use",
+    "force",
+    "ignore",
+    "with",
+    "lateral",
+    "partition",
+    "values",
+    "select",
+    "into",
+    "tablesample",
+];
+
+/// 结束查询条件部分的关键字
+const TERMINATORS: &[&str] = &[
+    "group",
+    "order",
+    "limit",
+    "having",
+    "union",
+    "intersect",
+    "except",
+    "offset",
+    "fetch",
+    "for",
+    "window",
+    "returning",
+];
+
+/// JOIN前面的关键字，也是上一个ON条件的结束
+const JOIN_WORDS: &[&str] = &[
+    "join",
+    "inner",
+    "left",
+    "right",
+    "full",
+    "outer",
+    "cross",
+    "natural",
+    "straight_join",
+];
+
+/// 全局过滤条件的类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum GlobalFilterKind {
+    SoftDelete(Bson), // 未删除时列的值，如 0
+    Tenant,           // 当前任务的租户，见with_tenant
+}
+
+/// 表的全局过滤条件，由GlobalFilterIntercepter自动加到查询、更新和删除语句中
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalFilter {
+    pub column: String,
+    pub kind: GlobalFilterKind,
+}
+
+tokio::task_local! {
+    static CURRENT_TENANT: Bson;
+    static SKIP_GLOBAL_FILTERS: bool;
+}
+
+lazy_static! {
+    static ref GLOBAL_FILTERS: RwLock<HashMap<String, Vec<GlobalFilter>>> =
+        RwLock::new(HashMap::new());
+}
+
+fn filter_key(table_name: &str) -> String {
+    parse_identifier(table_name)
+        .and_then(|f| f.last().map(|t| t.to_lowercase()))
+        .unwrap_or_else(|| table_name.to_lowercase())
+}
+
+fn add_global_filter(table_name: &str, filter: GlobalFilter) {
+    let mut filters = GLOBAL_FILTERS.write().unwrap();
+    let list = filters.entry(filter_key(table_name)).or_default();
+    list.retain(|f| f.column != filter.column);
+    list.push(filter);
+}
+
+/// 注册软删除的列，查询时只返回列的值为not_deleted的记录
+/// for example:
+///     register_soft_delete_filter("user", "deleted", 0);
+pub fn register_soft_delete_filter<T: Serialize>(table_name: &str, column: &str, not_deleted: T) {
+    add_global_filter(
+        table_name,
+        GlobalFilter {
+            column: column.to_string(),
+            kind: GlobalFilterKind::SoftDelete(rbatis::as_bson!(&not_deleted)),
+        },
+    );
+}
+
+/// 注册租户的列，值为with_tenant设置的当前租户，没有设置时执行失败
+/// for example:
+///     register_tenant_filter("user", "tenant_id");
+pub fn register_tenant_filter(table_name: &str, column: &str) {
+    add_global_filter(
+        table_name,
+        GlobalFilter {
+            column: column.to_string(),
+            kind: GlobalFilterKind::Tenant,
+        },
+    );
+}
+
+/// 删除表的全局过滤条件
+pub fn remove_global_filters(table_name: &str) {
+    GLOBAL_FILTERS
+        .write()
+        .unwrap()
+        .remove(&filter_key(table_name));
+}
+
+pub fn get_global_filters(table_name: &str) -> Vec<GlobalFilter> {
+    GLOBAL_FILTERS
+        .read()
+        .unwrap()
+        .get(&filter_key(table_name))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// 在future内使用tenant作为当前租户
+/// for example:
+///     let list = with_tenant(tenant_id, async { ... }).await;
+pub async fn with_tenant<T: Serialize, F: Future>(tenant: T, f: F) -> F::Output {
+    CURRENT_TENANT.scope(rbatis::as_bson!(&tenant), f).await
+}
+
+/// with_tenant的同步版本
+pub fn with_tenant_sync<T: Serialize, F: FnOnce() -> R, R>(tenant: T, f: F) -> R {
+    CURRENT_TENANT.sync_scope(rbatis::as_bson!(&tenant), f)
+}
+
+/// 当前任务的租户
+pub fn current_tenant() -> Option<Bson> {
+    CURRENT_TENANT.try_with(|f| f.clone()).ok()
+}
+
+/// 在future内不使用全局过滤条件，如管理员的查询
+pub async fn without_global_filters<F: Future>(f: F) -> F::Output {
+    SKIP_GLOBAL_FILTERS.scope(true, f).await
+}
+
+/// without_global_filters的同步版本
+pub fn without_global_filters_sync<F: FnOnce() -> R, R>(f: F) -> R {
+    SKIP_GLOBAL_FILTERS.sync_scope(true, f)
+}
+
+/// 当前任务是否不使用全局过滤条件
+pub fn is_global_filter_skipped() -> bool {
+    SKIP_GLOBAL_FILTERS.try_with(|f| *f).unwrap_or(false)
+}
+
+type FilteredTables = Vec<(String, Vec<GlobalFilter>)>; // 表名或别名，表的过滤条件
+
+/// 查询中的一段，从SELECT、UPDATE或DELETE开始
+#[derive(Default)]
+struct Segment {
+    active: bool,
+    tables: FilteredTables, // 条件加到WHERE中的表
+    where_end: Option<usize>,
+    on_clause: Option<(usize, FilteredTables)>, // 外连接的ON的结束位置，条件加到ON中的表
+}
+
+struct FilterRewriter<'a> {
+    chars: Vec<char>,
+    tokens: Vec<(SqlToken, usize, usize)>,
+    pos: usize,
+    dialect: SqlDialect,
+    filters: &'a HashMap<String, Vec<GlobalFilter>>,
+    edits: Vec<(usize, String)>,
+    missing_tenant: Option<String>,
+    unsupported_join: Option<String>,
+}
+
+impl<'a> FilterRewriter<'a> {
+    fn token(&self, pos: usize) -> Option<&SqlToken> {
+        self.tokens.get(pos).map(|f| &f.0)
+    }
+
+    fn text(&self, start: usize, end: usize) -> String {
+        self.chars[start..end].iter().collect()
+    }
+
+    /// 前一个有效token的结束位置
+    fn prev_end(&self) -> usize {
+        match self.pos {
+            0 => 0,
+            p => self.tokens[p - 1].2,
+        }
+    }
+
+    /// 读取标识符，如 t、s.t、`t`、"t"、[t]，返回文本和最后一部分
+    fn identifier(&mut self) -> Option<(String, String)> {
+        let start = self.tokens.get(self.pos)?.1;
+        let mut name;
+        loop {
+            match self.token(self.pos) {
+                Some(SqlToken::Word(w)) => {
+                    name = w.clone();
+                    self.pos += 1;
+                }
+                Some(SqlToken::Backtick(w)) => {
+                    name = w.clone();
+                    self.pos += 1;
+                }
+                Some(SqlToken::Raw(w)) if w.starts_with('"') => {
+                    name = w.trim_matches('"').to_string();
+                    self.pos += 1;
+                }
+                Some(t) if is_symbol(Some(t), "[") => match self.token(self.pos + 1) {
+                    Some(SqlToken::Word(w)) if is_symbol(self.token(self.pos + 2), "]") => {
+                        name = w.clone();
+                        self.pos += 3;
+                    }
+                    _ => return None,
+                },
+                _ => return None,
+            }
+            if is_symbol(self.token(self.pos), ".") {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Some((self.text(start, self.prev_end()), name))
+    }
+
+    /// JOIN和它前面的LEFT、OUTER等关键字之前的位置
+    fn join_start_end(&self) -> usize {
+        let mut back = self.pos;
+        while back > 0 {
+            match self.token(back - 1) {
+                Some(SqlToken::Word(w)) if JOIN_WORDS.contains(&w.to_lowercase().as_str()) => {
+                    back -= 1
+                }
+                _ => break,
+            }
+        }
+        match back {
+            0 => 0,
+            p => self.tokens[p - 1].2,
+        }
+    }
+
+    fn is_alias(token: Option<&SqlToken>) -> bool {
+        match token {
+            Some(SqlToken::Word(w)) => !NOT_ALIAS.iter().any(|k| w.eq_ignore_ascii_case(k)),
+            Some(SqlToken::Backtick(_)) => true,
+            Some(SqlToken::Raw(w)) => w.starts_with('"'),
+            Some(t) => is_symbol(Some(t), "["),
+            None => false,
+        }
+    }
+
+    /// 读取FROM、JOIN和UPDATE后面的表，如 a, b x, c as y
+    fn table_refs(&mut self, segment: &mut Segment) {
+        loop {
+            let (text, name) = match self.identifier() {
+                Some(table) => table,
+                None => return,
+            };
+            if is_symbol(self.token(self.pos), "(") {
+                // 函数，不是表
+                return;
+            }
+            let mut qualifier = text;
+            if is_word(self.token(self.pos), "as") {
+                self.pos += 1;
+            }
+            if Self::is_alias(self.token(self.pos)) {
+                if let Some((alias, _)) = self.identifier() {
+                    qualifier = alias;
+                }
+            }
+            if let Some(filters) = self.filters.get(&name.to_lowercase()) {
+                segment.tables.push((qualifier, filters.clone()));
+            }
+            if is_symbol(self.token(self.pos), ",") {
+                self.pos += 1;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// 读取JOIN的表，外连接中可能为NULL的表的条件加到ON中，不能加到WHERE中
+    fn join_refs(&mut self, segment: &mut Segment) {
+        let mut kinds = vec![];
+        let mut back = self.pos;
+        while back > 0 {
+            match self.token(back - 1) {
+                Some(SqlToken::Word(w)) if JOIN_WORDS.contains(&w.to_lowercase().as_str()) => {
+                    kinds.push(w.to_lowercase());
+                    back -= 1;
+                }
+                _ => break,
+            }
+        }
+        self.pos += 1;
+        let mut joined = Segment::default();
+        self.table_refs(&mut joined);
+        let nullable = if kinds.iter().any(|f| f == "left") {
+            std::mem::take(&mut joined.tables)
+        } else if kinds.iter().any(|f| f == "right") {
+            std::mem::take(&mut segment.tables)
+        } else if kinds.iter().any(|f| f == "full") {
+            let mut tables = std::mem::take(&mut segment.tables);
+            tables.append(&mut joined.tables);
+            tables
+        } else {
+            vec![]
+        };
+        segment.tables.append(&mut joined.tables);
+        if nullable.is_empty() {
+            return;
+        }
+        if kinds.iter().any(|f| f == "full" || f == "natural")
+            || !is_word(self.token(self.pos), "on")
+        {
+            // FULL JOIN两边都可能为NULL，USING和NATURAL JOIN没有ON，无法加上条件
+            self.unsupported_join = Some(nullable[0].0.clone());
+            return;
+        }
+        segment.on_clause = Some((self.tokens[self.pos].2, nullable));
+        self.pos += 1;
+    }
+
+    /// 结束外连接的ON条件，加上表的过滤条件
+    fn finish_on(&mut self, segment: &mut Segment, end: usize) {
+        if let Some((on_end, tables)) = segment.on_clause.take() {
+            let predicates = self.predicates(&tables);
+            self.edits.push((on_end, format!(" {} and (", predicates)));
+            self.edits.push((end, ")".to_string()));
+        }
+    }
+
+    fn predicates(&mut self, tables: &FilteredTables) -> String {
+        let mut predicates = vec![];
+        for (qualifier, filters) in tables.iter() {
+            for filter in filters {
+                let value = match &filter.kind {
+                    GlobalFilterKind::SoftDelete(value) => self.dialect.literal(value),
+                    GlobalFilterKind::Tenant => {
+                        if current_tenant().is_none() {
+                            self.missing_tenant = Some(qualifier.clone());
+                        }
+                        TENANT_MARK.to_string()
+                    }
+                };
+                predicates.push(format!("{}.{} = {}", qualifier, filter.column, value));
+            }
+        }
+        predicates.join(" and ")
+    }
+
+    fn finish(&mut self, segment: &mut Segment, end: usize) {
+        if !segment.active {
+            *segment = Segment::default();
+            return;
+        }
+        self.finish_on(segment, end);
+        if segment.tables.is_empty() {
+            *segment = Segment::default();
+            return;
+        }
+        let predicates = self.predicates(&segment.tables);
+        match segment.where_end {
+            Some(where_end) => {
+                self.edits
+                    .push((where_end, format!(" {} and (", predicates)));
+                self.edits.push((end, ")".to_string()));
+            }
+            None => self.edits.push((end, format!(" where {}", predicates))),
+        }
+        *segment = Segment::default();
+    }
+
+    /// 处理一层括号内的内容，遇到 ) 或结束时返回
+    fn scope(&mut self) {
+        let mut first = self.pos;
+        let mut segment = Segment::default();
+        while let Some((token, _, end)) = self.tokens.get(self.pos).cloned() {
+            if is_symbol(Some(&token), ")") {
+                break;
+            }
+            if is_symbol(Some(&token), "(") {
+                self.pos += 1;
+                self.scope();
+                if is_symbol(self.token(self.pos), ")") {
+                    self.pos += 1;
+                }
+                continue;
+            }
+            if is_symbol(Some(&token), ";") {
+                let prev = self.prev_end();
+                self.finish(&mut segment, prev);
+                self.pos += 1;
+                first = self.pos;
+                continue;
+            }
+            let word = match &token {
+                SqlToken::Word(w) => w.to_lowercase(),
+                _ => {
+                    self.pos += 1;
+                    continue;
+                }
+            };
+            match word.as_str() {
+                "select" => {
+                    let prev = self.prev_end();
+                    self.finish(&mut segment, prev);
+                    segment.active = true;
+                    self.pos += 1;
+                }
+                "update" if self.pos == first => {
+                    segment.active = true;
+                    self.pos += 1;
+                    self.table_refs(&mut segment);
+                }
+                "delete" if self.pos == first => {
+                    segment.active = true;
+                    self.pos += 1;
+                }
+                "from" if segment.active && segment.where_end.is_none() => {
+                    self.pos += 1;
+                    self.table_refs(&mut segment);
+                }
+                "join" | "straight_join" if segment.active && segment.where_end.is_none() => {
+                    let prev = self.join_start_end();
+                    self.finish_on(&mut segment, prev);
+                    self.join_refs(&mut segment);
+                }
+                "set" if segment.active && segment.where_end.is_none() => {
+                    let prev = self.prev_end();
+                    self.finish_on(&mut segment, prev);
+                    self.pos += 1;
+                }
+                "where" if segment.active && segment.where_end.is_none() => {
+                    let prev = self.prev_end();
+                    self.finish_on(&mut segment, prev);
+                    segment.where_end = Some(end);
+                    self.pos += 1;
+                }
+                w if segment.active && TERMINATORS.contains(&w) => {
+                    let prev = self.prev_end();
+                    self.finish(&mut segment, prev);
+                    self.pos += 1;
+                }
+                _ => self.pos += 1,
+            }
+        }
+        let prev = self.prev_end();
+        self.finish(&mut segment, prev);
+    }
+}
+
+/// 为使用了已注册的表的SELECT、UPDATE、DELETE加上全局过滤条件
+/// prepared为true时租户的值加入args，为false时（rbatis在没有参数时不绑定参数）作为常量写入SQL
+/// 其他语句（如DDL）不变
+/// 外连接中可能为NULL的表的条件加到ON中，FULL JOIN、USING和NATURAL JOIN返回错误
+/// for example:
+///  select * from user u where u.name = ? and u.age > 1
+///  select * from user u where u.deleted = 0 and u.tenant_id = ? and (u.name = ? and u.age > 1)
+pub fn apply_global_filters(
+    sql: &str,
+    dialect: SqlDialect,
+    args: &mut Vec<Bson>,
+    prepared: bool,
+) -> ChimesResult<String> {
+    let filters = GLOBAL_FILTERS.read().unwrap();
+    if filters.is_empty() {
+        return Ok(sql.to_string());
+    }
+    let all = tokenize_with_spans(sql);
+    let tokens = all
+        .iter()
+        .filter(|f| !matches!(f.0, SqlToken::Space(_) | SqlToken::Comment(_)))
+        .cloned()
+        .collect::<Vec<(SqlToken, usize, usize)>>();
+    let statement = tokens
+        .iter()
+        .find(|f| !is_symbol(Some(&f.0), "("))
+        .map(|f| &f.0);
+    if !["select", "with", "update", "delete", "insert", "replace"]
+        .iter()
+        .any(|f| is_word(statement, f))
+    {
+        return Ok(sql.to_string());
+    }
+
+    let mut rewriter = FilterRewriter {
+        chars: sql.chars().collect(),
+        tokens,
+        pos: 0,
+        dialect,
+        filters: &filters,
+        edits: vec![],
+        missing_tenant: None,
+        unsupported_join: None,
+    };
+    while rewriter.pos < rewriter.tokens.len() {
+        rewriter.scope();
+        // 多余的 ) 跳过
+        rewriter.pos += 1;
+    }
+    if let Some(table) = rewriter.unsupported_join {
+        return Err(ChimesError::custom(
+            10122,
+            format!(
+                "The global filters of {} can not be applied to a FULL, NATURAL or USING join, use ON instead",
+                table
+            ),
+        ));
+    }
+    if rewriter.edits.is_empty() {
+        return Ok(sql.to_string());
+    }
+    if let Some(table) = rewriter.missing_tenant {
+        return Err(ChimesError::custom(
+            10115,
+            format!("The tenant of {} was not set, use with_tenant", table),
+        ));
+    }
+
+    // 已有的占位符，用于确定租户参数的位置和编号
+    let placeholders = all
+        .iter()
+        .filter_map(|f| match f.0 {
+            SqlToken::Placeholder(n) => Some((f.1, n)),
+            _ => None,
+        })
+        .collect::<Vec<(usize, Option<usize>)>>();
+    let numbered = match dialect {
+        SqlDialect::Postgres | SqlDialect::MsSql => true,
+        SqlDialect::Sqlite => placeholders.iter().any(|f| f.1.is_some()),
+        SqlDialect::MySql => false,
+    };
+    let tenant = current_tenant().unwrap_or(Bson::Null);
+
+    let mut edits = rewriter.edits;
+    edits.sort_by_key(|f| f.0);
+    let chars = rewriter.chars;
+    let mut out = String::with_capacity(sql.len() + 64);
+    let mut inserted = 0;
+    let mut next_edit = 0;
+    for i in 0..=chars.len() {
+        while next_edit < edits.len() && edits[next_edit].0 == i {
+            for c in edits[next_edit].1.chars() {
+                if c != TENANT_MARK {
+                    out.push(c);
+                } else if !prepared {
+                    out.push_str(&dialect.literal(&tenant));
+                } else if numbered {
+                    args.push(tenant.clone());
+                    match dialect {
+                        SqlDialect::Sqlite => out.push_str(&format!("?{}", args.len())),
+                        _ => out.push_str(&dialect.placeholder(args.len())),
+                    }
+                } else {
+                    let index = placeholders.iter().filter(|f| f.0 < i).count() + inserted;
+                    args.insert(index.min(args.len()), tenant.clone());
+                    inserted += 1;
+                    out.push('?');
+                }
+            }
+            next_edit += 1;
+        }
+        if i < chars.len() {
+            out.push(chars[i]);
+        }
+    }
+    Ok(out)
+}
+
+/// 自动加上全局过滤条件的拦截器，在MultipleDatabaseIntercepter之后执行
+#[derive(Debug, Clone)]
+pub struct GlobalFilterIntercepter();
+
+impl rbatis::intercept::SqlIntercept for GlobalFilterIntercepter {
+    fn do_intercept(
+        &self,
+        rb: &Rbatis,
+        sql: &mut String,
+        args: &mut Vec<Bson>,
+        is_prepared_sql: bool,
+    ) -> Result<(), rbatis::core::Error> {
+        if is_global_filter_skipped() {
+            return Ok(());
+        }
+        let dialect = rb
+            .driver_type()
+            .ok()
+            .and_then(|f| SqlDialect::from_driver_type(&f))
+            .unwrap_or(SqlDialect::MySql);
+        match apply_global_filters(sql.as_str(), dialect, args, is_prepared_sql) {
+            Ok(m_sql) => {
+                *sql = m_sql;
+                Ok(())
+            }
+            Err(err) => Err(rbatis::core::Error::from(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    fn apply(sql: &str, dialect: SqlDialect, args: &mut Vec<Bson>) -> String {
+        apply_global_filters(sql, dialect, args, true).unwrap()
+    }
+
+    #[test]
+    fn filters_joins() {
+        register_soft_delete_filter("jn_user", "deleted", 0);
+        register_soft_delete_filter("jn_dept", "deleted", 0);
+        let mut args = vec![];
+        assert_eq!(
+            apply(
+                "select * from jn_user u join jn_dept d on d.id = u.dept_id where u.age > 1",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "select * from jn_user u join jn_dept d on d.id = u.dept_id \
+             where u.deleted = 0 and d.deleted = 0 and ( u.age > 1)"
+        );
+        // 外连接的表的条件加到ON中，不能把外连接变成内连接
+        assert_eq!(
+            apply(
+                "select * from jn_user u left join jn_dept d on d.id = u.dept_id \
+                 left outer join x on x.id = u.id where u.age > 1 order by u.id",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "select * from jn_user u left join jn_dept d on d.deleted = 0 and ( d.id = u.dept_id) \
+             left outer join x on x.id = u.id where u.deleted = 0 and ( u.age > 1) order by u.id"
+        );
+        assert_eq!(
+            apply(
+                "select * from jn_user u right join jn_dept d on d.id = u.dept_id",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "select * from jn_user u right join jn_dept d on u.deleted = 0 and ( d.id = u.dept_id) \
+             where d.deleted = 0"
+        );
+        for sql in [
+            "select * from jn_user u full join jn_dept d on d.id = u.dept_id",
+            "select * from jn_user u left join jn_dept d using (id)",
+        ] {
+            let err = apply_global_filters(sql, SqlDialect::Postgres, &mut args, true).unwrap_err();
+            assert!(matches!(err.kind, ErrorKind::Custom { code: 10122, .. }));
+        }
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn filters_subqueries_and_dml() {
+        register_soft_delete_filter("sq_user", "deleted", 0);
+        register_soft_delete_filter("sq_dept", "deleted", 0);
+        let mut args = vec![];
+        assert_eq!(
+            apply(
+                "select * from sq_user u where u.dept_id in (select d.id from sq_dept d where d.name = ?) \
+                 and exists (select 1 from sq_dept)",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "select * from sq_user u where u.deleted = 0 and ( u.dept_id in \
+             (select d.id from sq_dept d where d.deleted = 0 and ( d.name = ?)) \
+             and exists (select 1 from sq_dept where sq_dept.deleted = 0))"
+        );
+        assert_eq!(
+            apply(
+                "update sq_user u left join sq_dept d on d.id = u.dept_id set u.name = d.name where u.id = 1",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "update sq_user u left join sq_dept d on d.deleted = 0 and ( d.id = u.dept_id) \
+             set u.name = d.name where u.deleted = 0 and ( u.id = 1)"
+        );
+        assert_eq!(
+            apply(
+                "update sq_user set name = 'a'",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "update sq_user set name = 'a' where sq_user.deleted = 0"
+        );
+        assert_eq!(
+            apply(
+                "delete from sq_user where id = 1",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "delete from sq_user where sq_user.deleted = 0 and ( id = 1)"
+        );
+        assert_eq!(
+            apply(
+                "insert into sq_user (id) values (1)",
+                SqlDialect::MySql,
+                &mut args
+            ),
+            "insert into sq_user (id) values (1)"
+        );
+    }
+
+    #[test]
+    fn filters_tenant_args() {
+        register_tenant_filter("tn_order", "tenant_id");
+        register_tenant_filter("tn_item", "tenant_id");
+        let tenant = Bson::Int32(7);
+        let kind = Bson::String("k".to_string());
+        let name = Bson::String("n".to_string());
+        with_tenant_sync(7, || {
+            // MySQL的参数按占位符的顺序插入
+            let mut args = vec![kind.clone(), name.clone()];
+            assert_eq!(
+                apply(
+                    "select * from tn_order o left join tn_item i on i.order_id = o.id and i.kind = ? \
+                     where o.name = ?",
+                    SqlDialect::MySql,
+                    &mut args
+                ),
+                "select * from tn_order o left join tn_item i on i.tenant_id = ? and \
+                 ( i.order_id = o.id and i.kind = ?) where o.tenant_id = ? and ( o.name = ?)"
+            );
+            assert_eq!(
+                args,
+                vec![tenant.clone(), kind.clone(), tenant.clone(), name.clone()]
+            );
+            // 编号的占位符加在最后
+            let mut args = vec![kind.clone(), name.clone()];
+            assert_eq!(
+                apply(
+                    "select * from tn_order o left join tn_item i on i.order_id = o.id and i.kind = $1 \
+                     where o.name = $2",
+                    SqlDialect::Postgres,
+                    &mut args
+                ),
+                "select * from tn_order o left join tn_item i on i.tenant_id = $3 and \
+                 ( i.order_id = o.id and i.kind = $1) where o.tenant_id = $4 and ( o.name = $2)"
+            );
+            assert_eq!(args, vec![kind, name, tenant.clone(), tenant]);
+        });
+        let err = apply_global_filters(
+            "select * from tn_order",
+            SqlDialect::MySql,
+            &mut vec![],
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Custom { code: 10115, .. }));
+    }
+
+    #[test]
+    fn filters_tenant_without_args() {
+        register_tenant_filter("na_order", "tenant_id");
+        // 没有参数时rbatis不绑定参数，租户的值作为常量写入
+        with_tenant_sync("t'1", || {
+            for dialect in [SqlDialect::MySql, SqlDialect::Postgres] {
+                let mut args = vec![];
+                assert_eq!(
+                    apply_global_filters("select * from na_order", dialect, &mut args, false)
+                        .unwrap(),
+                    "select * from na_order where na_order.tenant_id = 't''1'"
+                );
+                assert!(args.is_empty());
+            }
+        });
+        with_tenant_sync(3, || {
+            let mut args = vec![];
+            assert_eq!(
+                apply_global_filters(
+                    "select count(*) from na_order o",
+                    SqlDialect::MsSql,
+                    &mut args,
+                    false
+                )
+                .unwrap(),
+                "select count(*) from na_order o where o.tenant_id = 3"
+            );
+            assert!(args.is_empty());
+        });
+    }
+}
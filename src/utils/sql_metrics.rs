@@ -0,0 +1,383 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::LevelFilter;
+use rbatis::log::{LogPlugin, RbatisLogPlugin};
+use rbatis::utils::string_util::LOG_SPACE;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{is_rbatis_linked, normalize_sql, registered_datasources};
+
+/// 每个语句保留的耗时样本数，用于计算p50/p99
+const STATEMENT_SAMPLES: usize = 256;
+/// 全部语句保留的耗时样本数
+const TOTAL_SAMPLES: usize = 4096;
+/// 性能信息中最多列出的语句数
+const TOP_STATEMENTS: usize = 10;
+/// 超过这个数量时清理长时间没有结果的语句
+const MAX_PENDING: usize = 1024;
+/// 最多分别统计的语句数，超过后新的语句合并到OTHER_STATEMENTS中
+const MAX_STATEMENTS: usize = 1024;
+const OTHER_STATEMENTS: &str = "(other statements)";
+
+/// 单个规范化语句的统计信息，时间单位为毫秒
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SqlStatementInfo {
+    pub sql: String,
+    pub count: u64,
+    pub errors: u64,
+    pub slow_count: u64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+/// SQL执行的汇总统计，包含在ChimesPerformanceInfo中
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SqlMetricsInfo {
+    pub healthy: bool,            // 已注册的数据源是否都已连接
+    pub unavailable: Vec<String>, // 未连接的数据源
+    pub count: u64,
+    pub errors: u64,
+    pub slow_count: u64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    pub statements: Vec<SqlStatementInfo>, // 按p99排序的最慢的语句
+}
+
+#[derive(Debug, Default)]
+struct StatementStats {
+    count: u64,
+    errors: u64,
+    slow_count: u64,
+    max: Duration,
+    samples: VecDeque<Duration>,
+}
+
+impl StatementStats {
+    fn record(&mut self, elapsed: Duration, success: bool, slow: bool, limit: usize) {
+        self.count += 1;
+        if !success {
+            self.errors += 1;
+        }
+        if slow {
+            self.slow_count += 1;
+        }
+        if elapsed > self.max {
+            self.max = elapsed;
+        }
+        if self.samples.len() >= limit {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(elapsed);
+    }
+
+    fn percentiles(&self) -> (f64, f64) {
+        let mut samples = self.samples.iter().copied().collect::<Vec<Duration>>();
+        samples.sort();
+        (percentile(&samples, 50), percentile(&samples, 99))
+    }
+}
+
+fn to_ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000f64
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0f64;
+    }
+    let idx = (sorted.len() * p).div_ceil(100).clamp(1, sorted.len()) - 1;
+    to_ms(sorted[idx])
+}
+
+#[derive(Debug, Default)]
+struct SqlMetrics {
+    total: StatementStats,
+    statements: HashMap<String, StatementStats>,
+}
+
+impl SqlMetrics {
+    fn record(&mut self, sql: &str, elapsed: Duration, success: bool, slow: bool) {
+        let mut key = normalize_sql(sql);
+        self.total.record(elapsed, success, slow, TOTAL_SAMPLES);
+        if self.statements.len() >= MAX_STATEMENTS && !self.statements.contains_key(&key) {
+            key = OTHER_STATEMENTS.to_string();
+        }
+        self.statements
+            .entry(key)
+            .or_default()
+            .record(elapsed, success, slow, STATEMENT_SAMPLES);
+    }
+}
+
+lazy_static! {
+    static ref SQL_METRICS: Mutex<SqlMetrics> = Mutex::new(SqlMetrics::default());
+}
+
+/// 记录一次SQL执行
+pub fn record_sql_execution(sql: &str, elapsed: Duration, success: bool, slow: bool) {
+    SQL_METRICS
+        .lock()
+        .unwrap()
+        .record(sql, elapsed, success, slow);
+}
+
+/// 清空SQL统计
+pub fn reset_sql_metrics() {
+    *SQL_METRICS.lock().unwrap() = SqlMetrics::default();
+}
+
+/// 当前的SQL统计和数据源状态
+pub fn get_sql_metrics() -> SqlMetricsInfo {
+    let unavailable = registered_datasources()
+        .into_iter()
+        .filter(|(_, rb)| !is_rbatis_linked(rb))
+        .map(|(name, _)| name)
+        .collect::<Vec<String>>();
+
+    let metrics = SQL_METRICS.lock().unwrap();
+    let (p50, p99) = metrics.total.percentiles();
+    let mut statements = metrics
+        .statements
+        .iter()
+        .map(|(sql, stats)| {
+            let (p50, p99) = stats.percentiles();
+            SqlStatementInfo {
+                sql: sql.clone(),
+                count: stats.count,
+                errors: stats.errors,
+                slow_count: stats.slow_count,
+                p50_ms: p50,
+                p99_ms: p99,
+                max_ms: to_ms(stats.max),
+            }
+        })
+        .collect::<Vec<SqlStatementInfo>>();
+    statements.sort_by(|a, b| b.p99_ms.total_cmp(&a.p99_ms));
+    statements.truncate(TOP_STATEMENTS);
+
+    SqlMetricsInfo {
+        healthy: unavailable.is_empty(),
+        unavailable,
+        count: metrics.total.count,
+        errors: metrics.total.errors,
+        slow_count: metrics.total.slow_count,
+        p50_ms: p50,
+        p99_ms: p99,
+        max_ms: to_ms(metrics.total.max),
+        statements,
+    }
+}
+
+/// 统计SQL执行时间的日志插件
+/// rbatis的SqlIntercept只在执行前调用，所以通过日志插件中
+/// 同一id的 Exec/Fetch ==> 和 RowsAffected/ReturnRows/ReturnErr <== 计算耗时
+#[derive(Debug)]
+pub struct SqlMetricsLogPlugin {
+    inner: RbatisLogPlugin,
+    slow_threshold: Option<Duration>,
+    pending: Mutex<HashMap<i64, (Instant, String)>>,
+}
+
+impl SqlMetricsLogPlugin {
+    /// slow_threshold为0时不记录慢查询日志，level为Off时不统计SQL
+    pub fn new(level: LevelFilter, slow_threshold: Duration) -> Self {
+        let inner = RbatisLogPlugin::default();
+        inner.set_level_filter(level);
+        Self {
+            inner,
+            slow_threshold: if slow_threshold.is_zero() {
+                None
+            } else {
+                Some(slow_threshold)
+            },
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn begin(&self, id: i64, data: &str) -> bool {
+        let sql = match data
+            .strip_prefix("Exec   ==> ")
+            .or_else(|| data.strip_prefix("Fetch  ==> "))
+        {
+            // 多行的SQL保留完整的语句，只去掉rbatis附加的参数部分
+            Some(text) => text
+                .rsplit_once(&format!("\n{}[rbatis]", LOG_SPACE))
+                .map(|f| f.0)
+                .unwrap_or(text),
+            None => return false,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.retain(|_, (start, _)| start.elapsed() < Duration::from_secs(600));
+        }
+        pending.insert(id, (Instant::now(), sql.to_string()));
+        true
+    }
+
+    fn finish(&self, id: i64, data: &str) -> bool {
+        let success = if data.starts_with("RowsAffected <==") || data.starts_with("ReturnRows <==")
+        {
+            true
+        } else if data.starts_with("ReturnErr  <==") {
+            false
+        } else {
+            return false;
+        };
+        let (start, sql) = match self.pending.lock().unwrap().remove(&id) {
+            Some(item) => item,
+            None => return false,
+        };
+        let elapsed = start.elapsed();
+        let slow = self.slow_threshold.map(|f| elapsed >= f).unwrap_or(false);
+        if slow {
+            log::warn!(
+                "[rbatis] [{}] Slow SQL ({} ms): {}",
+                id,
+                elapsed.as_millis(),
+                sql
+            );
+        }
+        record_sql_execution(&sql, elapsed, success, slow);
+        true
+    }
+}
+
+impl LogPlugin for SqlMetricsLogPlugin {
+    fn get_level_filter(&self) -> LevelFilter {
+        self.inner.get_level_filter()
+    }
+
+    fn set_level_filter(&self, level: LevelFilter) {
+        self.inner.set_level_filter(level);
+    }
+
+    // rbatis只在启用时调用插件，日志级别为Off时不记录日志，也不统计SQL
+    fn is_enable(&self) -> bool {
+        self.inner.is_enable()
+    }
+
+    fn error(&self, id: i64, data: &str) {
+        self.finish(id, data);
+        self.inner.error(id, data);
+    }
+
+    fn warn(&self, id: i64, data: &str) {
+        self.inner.warn(id, data);
+    }
+
+    fn info(&self, id: i64, data: &str) {
+        if !self.begin(id, data) {
+            self.finish(id, data);
+        }
+        self.inner.info(id, data);
+    }
+
+    fn debug(&self, id: i64, data: &str) {
+        self.inner.debug(id, data);
+    }
+
+    fn trace(&self, id: i64, data: &str) {
+        self.inner.trace(id, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    fn statement(sql: &str) -> Option<(u64, u64)> {
+        SQL_METRICS
+            .lock()
+            .unwrap()
+            .statements
+            .get(&normalize_sql(sql))
+            .map(|f| (f.count, f.errors))
+    }
+
+    #[test]
+    fn percentiles_use_recent_samples() {
+        let mut stats = StatementStats::default();
+        assert_eq!(stats.percentiles(), (0f64, 0f64));
+        for v in (1..=100).rev() {
+            stats.record(ms(v), true, false, 100);
+        }
+        assert_eq!(stats.percentiles(), (50f64, 99f64));
+
+        let mut stats = StatementStats::default();
+        for v in 1..=20 {
+            stats.record(ms(v), v % 5 != 0, v > 18, 10);
+        }
+        // 只保留最近的10个样本，计数和最大值包括全部
+        assert_eq!(stats.percentiles(), (15f64, 20f64));
+        assert_eq!((stats.count, stats.errors, stats.slow_count), (20, 4, 2));
+        assert_eq!(to_ms(stats.max), 20f64);
+    }
+
+    #[test]
+    fn statements_over_limit_are_merged() {
+        let mut metrics = SqlMetrics::default();
+        for i in 0..MAX_STATEMENTS {
+            metrics.record(&format!("select * from t{}", i), ms(1), true, false);
+        }
+        metrics.record("select * from t0 where id = 1", ms(1), true, false);
+        metrics.record("select * from t_new", ms(2), false, false);
+        metrics.record("select * from t_new2", ms(3), true, true);
+        metrics.record("select * from t1", ms(1), true, false);
+        assert_eq!(metrics.statements.len(), MAX_STATEMENTS + 1);
+        let other = &metrics.statements[OTHER_STATEMENTS];
+        assert_eq!((other.count, other.errors, other.slow_count), (3, 1, 1));
+        assert_eq!(metrics.statements["select * from t1"].count, 2);
+        assert_eq!(metrics.total.count, MAX_STATEMENTS as u64 + 4);
+    }
+
+    #[test]
+    fn plugin_parses_rbatis_logs() {
+        let plugin = SqlMetricsLogPlugin::new(LevelFilter::Off, Duration::ZERO);
+        assert!(!plugin.is_enable());
+        plugin.set_level_filter(LevelFilter::Error);
+        assert!(plugin.is_enable());
+
+        // 与rbatis 3.1的RBatisConnExecutor中的格式相同
+        let exec = |sql: &str| {
+            format!(
+                "Exec   ==> {}\n{}[rbatis]                      Args   ==> [1,\"a\"]",
+                sql, LOG_SPACE
+            )
+        };
+        let fetch = |sql: &str| {
+            format!(
+                "Fetch  ==> {}\n{}[rbatis]                      Args   ==> []",
+                sql, LOG_SPACE
+            )
+        };
+        let update = "update metrics_probe set name = ? where id = ?";
+        plugin.info(11, &exec(update));
+        plugin.info(11, "RowsAffected <== 1");
+        assert_eq!(statement(update), Some((1, 0)));
+
+        // 事务中的语句使用同一个tx_id，依次执行
+        let select = "select *\nfrom metrics_probe_tx\nwhere id = ?";
+        let insert = "insert into metrics_probe_tx (id) values (?)";
+        plugin.info(22, &fetch(select));
+        plugin.info(22, "ReturnRows <== 3");
+        plugin.info(22, &exec(insert));
+        plugin.error(22, "ReturnErr  <== duplicate key");
+        plugin.info(22, &fetch(select));
+        plugin.info(22, "ReturnRows <== 0");
+        assert_eq!(statement(select), Some((2, 0)));
+        assert_eq!(statement(insert), Some((1, 1)));
+        assert!(plugin.pending.lock().unwrap().get(&22).is_none());
+
+        // 没有开始的结果和其它日志不统计
+        assert!(!plugin.finish(33, "RowsAffected <== 1"));
+        assert!(!plugin.begin(33, "Begin transaction"));
+    }
+}
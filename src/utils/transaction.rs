@@ -0,0 +1,414 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_std::sync::Mutex;
+use futures_util::FutureExt;
+use rbatis::core::db::DBExecResult;
+use rbatis::executor::{ExecutorMut, RBatisTxExecutor};
+use rbatis::rbatis::Rbatis;
+use rbson::Bson;
+use serde::de::DeserializeOwned;
+
+use crate::{write_datasource_async, ChimesError, ChimesResult, SqlDialect};
+
+tokio::task_local! {
+    // 当前任务中正在进行的事务，嵌套调用with_transaction时使用SAVEPOINT
+    static ACTIVE_TRANSACTION: ChimesTransaction;
+}
+
+/// with_transaction中使用的事务句柄，可以clone，嵌套的事务共享同一个连接
+#[derive(Clone)]
+pub struct ChimesTransaction {
+    rb: &'static Rbatis,
+    executor: Arc<Mutex<Option<RBatisTxExecutor<'static>>>>,
+    savepoints: Arc<AtomicUsize>,
+    depth: usize,
+}
+
+impl ChimesTransaction {
+    pub fn rbatis(&self) -> &'static Rbatis {
+        self.rb
+    }
+
+    /// 嵌套的层数，最外层的事务为0
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn dialect(&self) -> SqlDialect {
+        self.rb
+            .driver_type()
+            .ok()
+            .and_then(|f| SqlDialect::from_driver_type(&f))
+            .unwrap_or(SqlDialect::MySql)
+    }
+
+    fn finished(sql: &str) -> ChimesError {
+        ChimesError::sql(
+            10093,
+            "The transaction was already finished",
+            sql,
+            "transaction finished",
+        )
+    }
+
+    /// 在事务中执行语句，失败时错误中带有执行的语句
+    pub async fn exec(&self, sql: &str, args: Vec<Bson>) -> ChimesResult<DBExecResult> {
+        let mut executor = self.executor.lock().await;
+        match executor.as_mut() {
+            Some(tx) => match tx.exec(sql, args).await {
+                Ok(rs) => Ok(rs),
+                Err(err) => Err(ChimesError::sql(
+                    10093,
+                    "Execute the statement in transaction failed",
+                    sql,
+                    err,
+                )),
+            },
+            None => Err(Self::finished(sql)),
+        }
+    }
+
+    /// 在事务中查询
+    pub async fn fetch<T>(&self, sql: &str, args: Vec<Bson>) -> ChimesResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut executor = self.executor.lock().await;
+        match executor.as_mut() {
+            Some(tx) => match tx.fetch(sql, args).await {
+                Ok(rs) => Ok(rs),
+                Err(err) => Err(ChimesError::sql(
+                    10093,
+                    "Fetch the statement in transaction failed",
+                    sql,
+                    err,
+                )),
+            },
+            None => Err(Self::finished(sql)),
+        }
+    }
+
+    async fn commit(&self) -> ChimesResult<()> {
+        match self.executor.lock().await.take() {
+            Some(mut tx) => match tx.commit().await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(ChimesError::sql(
+                    10094,
+                    "Commit the transaction failed",
+                    "COMMIT",
+                    err,
+                )),
+            },
+            None => Err(Self::finished("COMMIT")),
+        }
+    }
+
+    async fn rollback(&self) {
+        if let Some(mut tx) = self.executor.lock().await.take() {
+            if let Err(err) = tx.rollback().await {
+                log::warn!("Rollback the transaction failed: {}", err);
+            }
+        }
+    }
+
+    /// 嵌套的事务，使用SAVEPOINT实现
+    /// 返回Err或者panic时回滚到SAVEPOINT，外层的事务不受影响
+    pub async fn nested<F, Fut, T>(&self, f: F) -> ChimesResult<T>
+    where
+        F: FnOnce(ChimesTransaction) -> Fut,
+        Fut: Future<Output = ChimesResult<T>>,
+    {
+        let name = format!(
+            "chimes_sp_{}",
+            self.savepoints.fetch_add(1, Ordering::AcqRel) + 1
+        );
+        let (create, release, rollback) = savepoint_sql(self.dialect(), &name);
+        self.exec(&create, vec![]).await?;
+
+        let child = ChimesTransaction {
+            depth: self.depth + 1,
+            ..self.clone()
+        };
+        match run_in_transaction(child, f).await {
+            Ok(Ok(value)) => {
+                if let Some(release) = release {
+                    self.exec(&release, vec![]).await?;
+                }
+                Ok(value)
+            }
+            Ok(Err(err)) => {
+                self.exec(&rollback, vec![]).await?;
+                Err(err)
+            }
+            Err(panic) => {
+                if let Err(err) = self.exec(&rollback, vec![]).await {
+                    log::warn!("Rollback to the savepoint failed: {}", err);
+                }
+                std::panic::resume_unwind(panic)
+            }
+        }
+    }
+}
+
+/// SAVEPOINT的创建、释放和回滚语句，MSSQL没有释放语句
+fn savepoint_sql(dialect: SqlDialect, name: &str) -> (String, Option<String>, String) {
+    match dialect {
+        SqlDialect::MsSql => (
+            format!("SAVE TRANSACTION {}", name),
+            None,
+            format!("ROLLBACK TRANSACTION {}", name),
+        ),
+        _ => (
+            format!("SAVEPOINT {}", name),
+            Some(format!("RELEASE SAVEPOINT {}", name)),
+            format!("ROLLBACK TO SAVEPOINT {}", name),
+        ),
+    }
+}
+
+async fn run_in_transaction<F, Fut, T>(
+    tx: ChimesTransaction,
+    f: F,
+) -> std::thread::Result<ChimesResult<T>>
+where
+    F: FnOnce(ChimesTransaction) -> Fut,
+    Fut: Future<Output = ChimesResult<T>>,
+{
+    let scoped = tx.clone();
+    AssertUnwindSafe(ACTIVE_TRANSACTION.scope(scoped, async move { f(tx).await }))
+        .catch_unwind()
+        .await
+}
+
+/// 当前任务是否在with_transaction中，事务中的查询不使用只读副本
+pub fn is_in_transaction() -> bool {
+    ACTIVE_TRANSACTION.try_with(|_| ()).is_ok()
+}
+
+/// 当前任务中在rb上进行的事务
+pub fn current_transaction(rb: &Rbatis) -> Option<ChimesTransaction> {
+    ACTIVE_TRANSACTION
+        .try_with(|f| {
+            if std::ptr::eq(f.rb, rb) {
+                Some(f.clone())
+            } else {
+                None
+            }
+        })
+        .ok()
+        .flatten()
+}
+
+/// 在事务中执行，返回Ok时提交，返回Err或者panic时回滚
+/// 在事务中再次调用时使用SAVEPOINT
+/// for example:
+///     with_transaction(get_rbatis(), |tx| async move {
+///         tx.exec("update a set x = ? where id = ?", vec![...]).await?;
+///         tx.exec("insert into b(x) values (?)", vec![...]).await?;
+///         Ok(())
+///     }).await?;
+pub async fn with_transaction<F, Fut, T>(rb: &'static Rbatis, f: F) -> ChimesResult<T>
+where
+    F: FnOnce(ChimesTransaction) -> Fut,
+    Fut: Future<Output = ChimesResult<T>>,
+{
+    if let Some(tx) = current_transaction(rb) {
+        return tx.nested(f).await;
+    }
+
+    let executor = match rb.acquire_begin().await {
+        Ok(executor) => executor,
+        Err(err) => {
+            return Err(ChimesError::custom_err(
+                10092,
+                "Could not begin the transaction",
+                err,
+            ))
+        }
+    };
+    let tx = ChimesTransaction {
+        rb,
+        executor: Arc::new(Mutex::new(Some(executor))),
+        savepoints: Arc::new(AtomicUsize::new(0)),
+        depth: 0,
+    };
+
+    match run_in_transaction(tx.clone(), f).await {
+        Ok(Ok(value)) => {
+            tx.commit().await?;
+            Ok(value)
+        }
+        Ok(Err(err)) => {
+            tx.rollback().await;
+            Err(err)
+        }
+        Err(panic) => {
+            tx.rollback().await;
+            std::panic::resume_unwind(panic)
+        }
+    }
+}
+
+/// 在命名数据源的主库上执行事务
+pub async fn with_datasource_transaction<F, Fut, T>(name: &str, f: F) -> ChimesResult<T>
+where
+    F: FnOnce(ChimesTransaction) -> Fut,
+    Fut: Future<Output = ChimesResult<T>>,
+{
+    let rb = write_datasource_async(name).await?;
+    with_transaction(rb, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn sqlite_rbatis(file: &str) -> &'static Rbatis {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", file, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rb = Rbatis::new();
+        rb.link(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        rb.exec("create table tx_items (id integer primary key)", vec![])
+            .await
+            .unwrap();
+        Box::leak(Box::new(rb))
+    }
+
+    fn row_ids(rows: Vec<serde_json::Value>) -> Vec<i64> {
+        rows.iter().filter_map(|f| f["id"].as_i64()).collect()
+    }
+
+    async fn ids(rb: &Rbatis) -> Vec<i64> {
+        row_ids(
+            rb.fetch("select id from tx_items order by id", vec![])
+                .await
+                .unwrap(),
+        )
+    }
+
+    async fn insert(tx: &ChimesTransaction, id: i64) -> ChimesResult<DBExecResult> {
+        tx.exec(
+            "insert into tx_items (id) values (?)",
+            vec![Bson::Int64(id)],
+        )
+        .await
+    }
+
+    #[test]
+    fn savepoint_statements() {
+        assert_eq!(
+            savepoint_sql(SqlDialect::MsSql, "chimes_sp_1"),
+            (
+                "SAVE TRANSACTION chimes_sp_1".to_string(),
+                None,
+                "ROLLBACK TRANSACTION chimes_sp_1".to_string()
+            )
+        );
+        assert_eq!(
+            savepoint_sql(SqlDialect::Postgres, "chimes_sp_2"),
+            (
+                "SAVEPOINT chimes_sp_2".to_string(),
+                Some("RELEASE SAVEPOINT chimes_sp_2".to_string()),
+                "ROLLBACK TO SAVEPOINT chimes_sp_2".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_and_rollback() {
+        let rb = sqlite_rbatis("chimes_tx_commit").await;
+        with_transaction(rb, |tx| async move {
+            assert_eq!(tx.depth(), 0);
+            insert(&tx, 1).await?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let err = with_transaction(rb, |tx| async move {
+            insert(&tx, 2).await?;
+            Err::<(), ChimesError>(ChimesError::custom(1, "failed"))
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err.kind, crate::ErrorKind::Custom { code: 1, .. }));
+
+        let panicked = AssertUnwindSafe(with_transaction(rb, |tx| async move {
+            insert(&tx, 3).await?;
+            if tx.depth() == 0 {
+                panic!("failed in transaction");
+            }
+            Ok(())
+        }))
+        .catch_unwind()
+        .await;
+        assert!(panicked.is_err());
+        assert!(current_transaction(rb).is_none());
+        assert_eq!(ids(rb).await, vec![1]);
+
+        // 失败的语句可以从错误中取出
+        let sql = "insert into tx_missing (id) values (?)";
+        let err = with_transaction(rb, |tx| async move {
+            tx.exec(sql, vec![Bson::Int64(1)]).await?;
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.statement(), Some(sql));
+    }
+
+    #[tokio::test]
+    async fn nested_savepoints() {
+        let rb = sqlite_rbatis("chimes_tx_nested").await;
+        with_transaction(rb, |tx| async move {
+            insert(&tx, 10).await?;
+            let err = with_transaction(rb, |inner| async move {
+                assert_eq!(inner.depth(), 1);
+                insert(&inner, 11).await?;
+                Err::<(), ChimesError>(ChimesError::custom(1, "inner failed"))
+            })
+            .await;
+            assert!(err.is_err());
+            tx.nested(|inner| async move {
+                insert(&inner, 12).await?;
+                inner
+                    .nested(|deepest| async move {
+                        assert_eq!(deepest.depth(), 2);
+                        insert(&deepest, 13).await
+                    })
+                    .await
+            })
+            .await?;
+            let rows = tx
+                .fetch("select id from tx_items order by id", vec![])
+                .await?;
+            assert_eq!(row_ids(rows), vec![10, 12, 13]);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(ids(rb).await, vec![10, 12, 13]);
+
+        // 内层panic时外层整体回滚
+        let panicked = AssertUnwindSafe(with_transaction(rb, |tx| async move {
+            insert(&tx, 20).await?;
+            tx.nested(|inner| async move {
+                insert(&inner, 21).await?;
+                if inner.depth() == 1 {
+                    panic!("failed in savepoint");
+                }
+                Ok(())
+            })
+            .await
+        }))
+        .catch_unwind()
+        .await;
+        assert!(panicked.is_err());
+        assert_eq!(ids(rb).await, vec![10, 12, 13]);
+    }
+}